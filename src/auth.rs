@@ -0,0 +1,289 @@
+use std::future::{ready, Ready};
+use actix_web::{dev::Payload, error, http::header, FromRequest, HttpRequest};
+use argon2::password_hash::{rand_core::{OsRng, RngCore}, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::Engine;
+use yaml_rust2::Yaml;
+use crate::cast_utility;
+use crate::configuration_manager::ConfigurationManager;
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+pub const CSRF_HEADER:&str = "X-CSRF-Token";
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+	ReadOnly,
+	Operator,
+}
+impl Role {
+	pub fn from_str(role:&str) -> ResultOpenHems<Role> {
+		match role.to_lowercase().as_str() {
+			"readonly" | "read-only" | "" => Ok(Role::ReadOnly),
+			"operator" => Ok(Role::Operator),
+			_ => Err(OpenHemsError::new(format!("Unknown role '{role}', use 'readonly' or 'operator'.")))
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+struct User {
+	name: String,
+	password_hash: String, // PHC string (argon2)
+	role: Role,
+}
+#[derive(Debug, Clone)]
+struct Token {
+	name: String,
+	token: String,
+	role: Role,
+}
+
+/// Web panel access control, from 'server.auth.*' configuration keys.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+	enabled: bool,
+	users: Vec<User>,
+	tokens: Vec<Token>,
+	csrf_token: String,
+}
+impl AuthConfig {
+	pub fn from_conf(configurator:&ConfigurationManager) -> ResultOpenHems<AuthConfig> {
+		let mut users = Vec::new();
+		for user in configurator.get_as_list("server.auth.users") {
+			let conf = cast_utility::to_type_dict(user);
+			let get = |key:&str| conf.get(key).map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+			let name = get("name");
+			let password_hash = get("password");
+			PasswordHash::new(&password_hash)
+				.map_err(|err| OpenHemsError::new(format!(
+					"Invalid password hash for user '{name}' (use --hash-password) : {err}"
+				)))?;
+			users.push(User {
+				role: Role::from_str(&get("role"))?,
+				name,
+				password_hash,
+			});
+		}
+		let mut tokens = Vec::new();
+		for (i, token) in configurator.get_as_list("server.auth.tokens").iter().enumerate() {
+			let conf = cast_utility::to_type_dict(token);
+			let get = |key:&str| conf.get(key).map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+			let value = get("token");
+			if value.len()<16 {
+				return Err(OpenHemsError::new(format!("Token {i} is too short (16 characters minimum).")));
+			}
+			let mut name = get("name");
+			if name.is_empty() {
+				name = format!("token_{i}");
+			}
+			tokens.push(Token {
+				role: Role::from_str(&get("role"))?,
+				name,
+				token: value,
+			});
+		}
+		let enabled = configurator.get_as_bool("server.auth.enabled");
+		if enabled && users.is_empty() && tokens.is_empty() {
+			return Err(OpenHemsError::new("Authentication is enabled but there is no user nor token.".to_string()));
+		}
+		Ok(AuthConfig {
+			enabled,
+			users,
+			tokens,
+			csrf_token: Self::new_secret(),
+		})
+	}
+	fn new_secret() -> String {
+		let mut bytes = [0u8; 24];
+		OsRng.fill_bytes(&mut bytes);
+		base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+	}
+	pub fn get_csrf_token(&self) -> &str {
+		&self.csrf_token
+	}
+	/// Identify the request sender with 'Authorization: Bearer' or 'Authorization: Basic' header.
+	pub fn authenticate(&self, req:&HttpRequest) -> Result<Authenticated, actix_web::Error> {
+		let authorization = req.headers().get(header::AUTHORIZATION)
+			.and_then(|v| v.to_str().ok());
+		if !self.enabled {
+			return Ok(Authenticated {
+				name: String::from("anonymous"),
+				role: Role::Operator,
+				by_token: false,
+			});
+		}
+		if let Some(token) = authorization.and_then(|v| v.strip_prefix("Bearer ")) {
+			for known in &self.tokens {
+				if constant_time_eq(known.token.as_bytes(), token.trim().as_bytes()) {
+					return Ok(Authenticated {
+						name: known.name.clone(),
+						role: known.role,
+						by_token: true,
+					});
+				}
+			}
+		} else if let Some(basic) = authorization.and_then(|v| v.strip_prefix("Basic ")) {
+			let decoded = base64::engine::general_purpose::STANDARD.decode(basic.trim()).ok()
+				.and_then(|v| String::from_utf8(v).ok())
+				.unwrap_or_default();
+			if let Some((name, password)) = decoded.split_once(':') {
+				if let Some(user) = self.users.iter().find(|u| u.name==name) {
+					if verify_password(password, &user.password_hash) {
+						return Ok(Authenticated {
+							name: user.name.clone(),
+							role: user.role,
+							by_token: false,
+						});
+					}
+				}
+				log::warn!("Authentication failure for user '{name}'.");
+			}
+		} else {
+			return Err(unauthorized("Authentication required."));
+		}
+		Err(unauthorized("Invalid credentials."))
+	}
+}
+
+/// Identity of the request sender, to use as handler parameter.
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+	pub name: String,
+	pub role: Role,
+	by_token: bool,
+}
+impl Authenticated {
+	pub fn require(&self, role:Role) -> Result<(), actix_web::Error> {
+		if self.role>=role {
+			Ok(())
+		} else {
+			log::warn!("User '{}' is not allowed to do this ({:?} is required).", self.name, role);
+			Err(error::ErrorForbidden("Not allowed."))
+		}
+	}
+	/// Check the user is an operator and the request come from our panel, not from another site.
+	/// Requests with a bearer token can't be forged by a browser so they don't need it.
+	pub fn require_mutation(&self, req:&HttpRequest, auth:&AuthConfig) -> Result<(), actix_web::Error> {
+		self.require(Role::Operator)?;
+		if self.by_token {
+			return Ok(());
+		}
+		let csrf = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+		if let Some(csrf) = csrf {
+			if constant_time_eq(csrf.as_bytes(), auth.csrf_token.as_bytes()) {
+				return Ok(());
+			}
+		} else if !auth.enabled && is_same_origin(req) {
+			// Without authentication, keep scripts working but refuse other web sites.
+			return Ok(());
+		}
+		log::warn!("Refuse request from '{}' : invalid CSRF token.", self.name);
+		Err(error::ErrorForbidden("Invalid CSRF token."))
+	}
+}
+impl FromRequest for Authenticated {
+	type Error = actix_web::Error;
+	type Future = Ready<Result<Self, Self::Error>>;
+
+	fn from_request(req:&HttpRequest, _:&mut Payload) -> Self::Future {
+		ready(match req.app_data::<actix_web::web::Data<AuthConfig>>() {
+			Some(auth) => auth.authenticate(req),
+			None => Err(error::ErrorInternalServerError("No authentication configuration."))
+		})
+	}
+}
+
+fn unauthorized(message:&'static str) -> actix_web::Error {
+	let response = actix_web::HttpResponse::Unauthorized()
+		.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"OpenHEMS\""))
+		.body(message);
+	error::InternalError::from_response(message, response).into()
+}
+
+fn is_same_origin(req:&HttpRequest) -> bool {
+	let Some(origin) = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok()) else {
+		return true; // Not a browser cross-site request
+	};
+	let host = req.connection_info().host().to_string();
+	origin.split_once("://").map(|(_, o)| o==host).unwrap_or(false)
+}
+
+fn constant_time_eq(a:&[u8], b:&[u8]) -> bool {
+	a.len()==b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x^y))==0
+}
+
+pub fn hash_password(password:&str) -> ResultOpenHems<String> {
+	let salt = SaltString::generate(&mut OsRng);
+	Argon2::default().hash_password(password.as_bytes(), &salt)
+		.map(|hash| hash.to_string())
+		.map_err(|err| OpenHemsError::new(format!("Fail hash password : {err}")))
+}
+pub fn verify_password(password:&str, password_hash:&str) -> bool {
+	match PasswordHash::new(password_hash) {
+		Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+		Err(_) => false
+	}
+}
+
+/// Used by configuration check.
+pub fn check_entry(entry:&Yaml) -> Option<String> {
+	let conf = cast_utility::to_type_dict(entry);
+	if let Some(role) = conf.get("role") {
+		if let Err(err) = Role::from_str(&cast_utility::to_type_str(role)) {
+			return Some(err.message);
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_password() -> ResultOpenHems<()> {
+		let hash = hash_password("secret")?;
+		assert!(verify_password("secret", &hash));
+		assert!(!verify_password("Secret", &hash));
+		assert!(!verify_password("secret", "not a hash"));
+		assert!(Role::Operator>Role::ReadOnly);
+		Ok(())
+	}
+
+	async fn mutate(req:HttpRequest, user:Authenticated, auth:actix_web::web::Data<AuthConfig>)
+			-> Result<actix_web::HttpResponse, actix_web::Error> {
+		user.require_mutation(&req, &auth)?;
+		Ok(actix_web::HttpResponse::Ok().finish())
+	}
+
+	#[actix_web::test]
+	async fn test_roles_and_csrf() {
+		use actix_web::{test, App};
+		let auth = AuthConfig {
+			enabled: true,
+			users: vec![User{name: "admin".to_string(), password_hash: hash_password("pwd").unwrap(), role: Role::Operator}],
+			tokens: vec![Token{name: "ha".to_string(), token: "0123456789abcdef".to_string(), role: Role::ReadOnly}],
+			csrf_token: AuthConfig::new_secret(),
+		};
+		let csrf = auth.csrf_token.clone();
+		let app = test::init_service(App::new()
+			.app_data(actix_web::web::Data::new(auth))
+			.route("/", actix_web::web::post().to(mutate))
+		).await;
+		let basic = format!("Basic {}", base64::engine::general_purpose::STANDARD.encode("admin:pwd"));
+		let status = |req:test::TestRequest| {
+			let app = &app;
+			async move { test::call_service(app, req.to_request()).await.status().as_u16() }
+		};
+		assert_eq!(status(test::TestRequest::post().uri("/")).await, 401);
+		assert_eq!(status(test::TestRequest::post().uri("/")
+			.insert_header((header::AUTHORIZATION, "Bearer 0123456789abcdef"))).await, 403);
+		assert_eq!(status(test::TestRequest::post().uri("/")
+			.insert_header((header::AUTHORIZATION, "Basic YWRtaW46YmFk"))).await, 401);
+		assert_eq!(status(test::TestRequest::post().uri("/")
+			.insert_header((header::AUTHORIZATION, basic.clone()))).await, 403);
+		assert_eq!(status(test::TestRequest::post().uri("/")
+			.insert_header((header::AUTHORIZATION, basic))
+			.insert_header((CSRF_HEADER, csrf))).await, 200);
+	}
+}
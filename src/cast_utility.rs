@@ -1,5 +1,6 @@
 use yaml_rust2::Yaml;
 use std::collections::HashMap;
+use crate::error::{OpenHemsError, ResultOpenHems};
 
 pub fn to_type_str(value: &Yaml) -> String {
 	match value {
@@ -36,73 +37,78 @@ pub fn to_type_str(value: &Yaml) -> String {
 		}
 	}
 }
-pub fn to_type_int(value: &Yaml) -> i32 {
+pub fn try_to_type_int(value: &Yaml) -> ResultOpenHems<i32> {
 	match value {
 		Yaml::Real(v) => {
-			let r = v.parse::<f64>().unwrap();
-			r as i32
+			let r = v.parse::<f64>()
+				.map_err(|err| OpenHemsError::new(format!("'{v}' is not a number : {err}")))?;
+			Ok(r as i32)
 		}
 		Yaml::Integer(v) => {
-			*v as i32
+			i32::try_from(*v)
+				.map_err(|err| OpenHemsError::new(format!("'{v}' is too big : {err}")))
 		}
 		Yaml::String(v) => {
-			v.parse::<i32>().unwrap()
+			v.trim().parse::<i32>()
+				.map_err(|err| OpenHemsError::new(format!("'{v}' is not an integer : {err}")))
 		}
 		Yaml::Boolean(v) => {
 			if *v {
-				1
+				Ok(1)
 			} else {
-				0
+				Ok(0)
 			}
 		}
-		Yaml::Array(_) => {
-			0
-		}
-		Yaml::Hash(_) => {
-			0
-		}
-		Yaml::Alias(_) => {
-			0
-		}
 		Yaml::Null => {
-			0
+			Ok(0)
 		}
-		Yaml::BadValue => {
+		_ => {
+			Err(OpenHemsError::new(format!("{:?} is not an integer", value)))
+		}
+	}
+}
+pub fn to_type_int(value: &Yaml) -> i32 {
+	match try_to_type_int(value) {
+		Ok(v) => v,
+		Err(err) => {
+			log::error!("Fail cast to integer : {}", err.message);
 			0
 		}
 	}
 }
-pub fn to_type_float(value: &Yaml) -> f32 {
+pub fn try_to_type_float(value: &Yaml) -> ResultOpenHems<f32> {
 	match value {
 		Yaml::Real(v) => {
-			v.parse::<f32>().unwrap()
+			v.parse::<f32>()
+				.map_err(|err| OpenHemsError::new(format!("'{v}' is not a number : {err}")))
 		}
 		Yaml::Integer(v) => {
-			*v as f32
+			Ok(*v as f32)
 		}
 		Yaml::String(v) => {
-			v.parse::<f32>().unwrap()
+			v.trim().parse::<f32>()
+				.map_err(|err| OpenHemsError::new(format!("'{v}' is not a number : {err}")))
 		}
 		Yaml::Boolean(v) => {
 			if *v {
-				1.0
+				Ok(1.0)
 			} else {
-				0.0
+				Ok(0.0)
 			}
 		}
-		Yaml::Array(_) => {
-			0.0
-		}
-		Yaml::Hash(_) => {
-			0.0
-		}
-		Yaml::Alias(_) => {
-			0.0
-		}
 		Yaml::Null => {
-			0.0
+			Ok(0.0)
 		}
-		Yaml::BadValue => {
+		_ => {
+			Err(OpenHemsError::new(format!("{:?} is not a number", value)))
+		}
+	}
+}
+pub fn to_type_float(value: &Yaml) -> f32 {
+	match try_to_type_float(value) {
+		Ok(v) => v,
+		Err(err) => {
+			log::error!("Fail cast to float : {}", err.message);
 			0.0
 		}
 	}
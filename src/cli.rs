@@ -0,0 +1,75 @@
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+pub const USAGE:&str = "Usage: openhems-rust [OPTIONS]
+
+Options:
+  -c, --config <FILE>    Configuration file (default ./config/openhems.yaml, env OPENHEMS_CONFIG)
+  -s, --secret <FILE>    Secret file used by '!secret' values (default ./config/openhems.secret.yaml, env OPENHEMS_SECRET)
+  -d, --default <FILE>   Default configuration file (default ./data/openhems_default.yaml)
+      --check-config     Check the configuration, report all problems and exit
+      --hash-password <PASSWORD>  Print the hash to use as 'password' in server.auth.users
+  -h, --help             Print this help
+
+Any configuration key can be overridden with an environment variable:
+ 'server.loopDelay' with OPENHEMS_SERVER_LOOPDELAY, 'api.long_lived_token' with OPENHEMS_API_LONG_LIVED_TOKEN.
+A key suffixed with '_file' (like OPENHEMS_API_LONG_LIVED_TOKEN_FILE) is read from that file.";
+
+#[derive(Debug, Clone)]
+pub struct Arguments {
+	pub config_path: String,
+	pub secret_path: String,
+	pub default_path: String,
+	pub check_config: bool,
+	pub hash_password: Option<String>,
+	pub help: bool,
+}
+impl Arguments {
+	/// Parse command line arguments (without the program name),
+	/// paths not given default to OPENHEMS_CONFIG / OPENHEMS_SECRET environment variables.
+	pub fn parse<I:Iterator<Item=String>>(mut args:I) -> ResultOpenHems<Arguments> {
+		let mut arguments = Arguments {
+			config_path: std::env::var("OPENHEMS_CONFIG")
+				.unwrap_or(String::from("./config/openhems.yaml")),
+			secret_path: std::env::var("OPENHEMS_SECRET")
+				.unwrap_or(String::from("./config/openhems.secret.yaml")),
+			default_path: String::from("./data/openhems_default.yaml"),
+			check_config: false,
+			hash_password: None,
+			help: false,
+		};
+		let mut hash_password = String::new();
+		while let Some(arg) = args.next() {
+			let (name, inline_value) = match arg.split_once('=') {
+				Some((name, value)) => (name.to_string(), Some(value.to_string())),
+				None => (arg.clone(), None)
+			};
+			let target = match name.as_str() {
+				"-c" | "--config" => &mut arguments.config_path,
+				"-s" | "--secret" => &mut arguments.secret_path,
+				"-d" | "--default" => &mut arguments.default_path,
+				"--hash-password" => &mut hash_password,
+				"--check-config" => {
+					arguments.check_config = true;
+					continue;
+				}
+				"-h" | "--help" => {
+					arguments.help = true;
+					continue;
+				}
+				_ => {
+					return Err(OpenHemsError::new(format!("Unknown argument '{arg}'.")));
+				}
+			};
+			*target = match inline_value.or_else(|| args.next()) {
+				Some(value) => value,
+				None => {
+					return Err(OpenHemsError::new(format!("Missing value for argument '{name}'.")));
+				}
+			};
+		}
+		if !hash_password.is_empty() {
+			arguments.hash_password = Some(hash_password);
+		}
+		Ok(arguments)
+	}
+}
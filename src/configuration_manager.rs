@@ -1,31 +1,122 @@
 use std::{collections::HashMap, fs};
 use std::error::Error;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
 use yaml_rust2::{Yaml, YamlLoader};
 use crate::cast_utility;
+use crate::configuration_schema::{ConfigurationIssue, ConfigurationSchema, Severity};
+
+enum LocatorFrame {
+	Map{prefix:String, key:Option<String>},
+	Seq{prefix:String, index:usize},
+}
+
+// Record the line of each key (and list item) of a YAML document,
+// using the same dotted keys as ConfigurationManager ("network.nodes[1].id").
+struct KeyLocator {
+	stack: Vec<LocatorFrame>,
+	lines: HashMap<String, usize>,
+	tags: HashMap<String, String>, // Local tags like '!secret'
+}
+impl KeyLocator {
+	fn new() -> KeyLocator {
+		KeyLocator {
+			stack: Vec::new(),
+			lines: HashMap::new(),
+			tags: HashMap::new(),
+		}
+	}
+	// Return the path of the node starting now, None if it is a mapping key.
+	fn node_path(&mut self, ev:&Event, line:usize) -> Option<String> {
+		match self.stack.last_mut() {
+			Some(LocatorFrame::Map{prefix, key}) => {
+				if let Some(k) = key.take() {
+					Some(k)
+				} else {
+					if let Event::Scalar(name, _, _, _) = ev {
+						let path = if prefix.is_empty() {name.clone()} else {format!("{prefix}.{name}")};
+						self.lines.insert(path.clone(), line);
+						*key = Some(path);
+					} else {
+						*key = Some(String::from("?"));
+					}
+					None
+				}
+			}
+			Some(LocatorFrame::Seq{prefix, index}) => {
+				let path = format!("{prefix}[{index}]");
+				*index += 1;
+				self.lines.insert(path.clone(), line);
+				Some(path)
+			}
+			None => Some(String::new())
+		}
+	}
+}
+impl MarkedEventReceiver for KeyLocator {
+	fn on_event(&mut self, ev:Event, mark:Marker) {
+		match ev {
+			Event::MappingStart(..) | Event::SequenceStart(..) | Event::Scalar(..) | Event::Alias(_) => {
+				if let Some(path) = self.node_path(&ev, mark.line()) {
+					match ev {
+						Event::MappingStart(..) => self.stack.push(LocatorFrame::Map{prefix:path, key:None}),
+						Event::SequenceStart(..) => self.stack.push(LocatorFrame::Seq{prefix:path, index:0}),
+						Event::Scalar(_, _, _, Some(tag)) if tag.handle=="!" => {
+							self.tags.insert(path, tag.suffix);
+						}
+						_ => {}
+					}
+				}
+			}
+			Event::MappingEnd | Event::SequenceEnd => {
+				self.stack.pop();
+			}
+			_ => {}
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LoadMode {
+	Default,
+	User,
+	Secret, // Like User but unknown keys are names for '!secret' values
+}
 
 pub struct ConfigurationManager {
 	conf:HashMap<String, Box<Yaml>>,
 	_cache:HashMap<String, String>,
-	default_path:String
+	default_path:String,
+	schema:ConfigurationSchema,
+	locations:HashMap<String, (String, usize)>,
+	issues:Vec<ConfigurationIssue>,
+	secrets:HashMap<String, Yaml>,
+	secret_refs:HashMap<String, String>, // key -> secret name not yet resolved
 }
 
 pub fn get(default_path:Option<String>) -> ConfigurationManager {
 	let mut conf = ConfigurationManager {
 		conf: HashMap::new(),
 		_cache: HashMap::new(),
-		default_path: String::from("")
+		default_path: String::from(""),
+		schema: ConfigurationSchema::new(),
+		locations: HashMap::new(),
+		issues: Vec::new(),
+		secrets: HashMap::new(),
+		secret_refs: HashMap::new(),
 	};
-	if let Some(path) = default_path {
-		let _ = conf.add_yaml_config(&path, true);
-	} else {
-		let path = "./data/openhems_default.yaml";
-		let _ = conf.add_yaml_config(path, true);
+	let path = default_path.unwrap_or(String::from("./data/openhems_default.yaml"));
+	if let Err(err) = conf.add_yaml_config(&path, true) {
+		log::error!("Fail load default configuration {path}: {err}");
+		let mut issue = conf.issue(Severity::Fatal, "", format!("can not be loaded : {err}"));
+		issue.file = path;
+		conf.add_issue(issue);
 	}
 	conf
 }
 
 impl ConfigurationManager {
-	fn add(&mut self, key:&str, value:&Yaml, init:bool) {
+	fn add(&mut self, key:&str, value:&Yaml, mode:LoadMode) {
 		if let Yaml::Hash(config) = value {
 			for (k, value) in config.into_iter() {
 				let k_str = cast_utility::to_type_str(k);
@@ -37,28 +128,184 @@ impl ConfigurationManager {
 				} else {
 					newkey = k_str;
 				}
-				self.add(&newkey, value, init);
+				self.add(&newkey, value, mode);
 			}
 		} else {
 			let val = Box::new(value.clone());
-			if !init && !self.conf.contains_key(key){
-				log::error!("key='{key}' is not valid in configuration.");
-			} else {
+			if mode==LoadMode::Default {
+				self.schema.add_default(key, value);
+				self.conf.insert(key.to_string(),val);
+			} else if self.conf.contains_key(key) {
 				//  log::debug!(" - ConfigurationManager.add({key})");
+				self.secret_refs.remove(key);
 				self.conf.insert(key.to_string(),val);
+			} else if let Some(base) = key.strip_suffix("_file").filter(|k| self.conf.contains_key(*k)) {
+				let file_path = cast_utility::to_type_str(value);
+				match fs::read_to_string(&file_path) {
+					Ok(content) => {
+						log::info!("Load '{base}' from file : {file_path}");
+						self.secret_refs.remove(base);
+						self.conf.insert(base.to_string(), Box::new(Yaml::String(content.trim().to_string())));
+					}
+					Err(err) => {
+						let issue = self.issue(Severity::Fatal, key, format!("file '{file_path}' can not be read : {err}"));
+						self.issues.push(issue);
+					}
+				}
+			} else if mode!=LoadMode::Secret {
+				let issue = self.issue(Severity::Warning, key, "is not a valid key, it will be ignored.".to_string());
+				log::error!("{issue}");
+				self.issues.push(issue);
 			}
 		}
 	}
 
 	pub fn add_yaml_config(&mut self, file_path:&str, init:bool) -> Result<(), Box<dyn Error>> {
-		self.default_path = String::from(file_path);
 		let yaml_config: String = fs::read_to_string(file_path)?;
 		log::info!("Load YAML configuration from : {file_path}");
-		let docs = YamlLoader::load_from_str(&yaml_config)?;
-		let doc = &docs[0];
-		self.add("", doc, init);
+		let mode = if init {LoadMode::Default} else {LoadMode::User};
+		self.add_yaml(file_path, &yaml_config, mode)
+	}
+	/// Load a secret file : top level keys are names usable with '!secret <name>'
+	///  and valid configuration keys are applied as usual.
+	pub fn add_secret_config(&mut self, file_path:&str) -> Result<(), Box<dyn Error>> {
+		let yaml_config: String = fs::read_to_string(file_path)?;
+		log::info!("Load YAML secrets from : {file_path}");
+		self.add_yaml(file_path, &yaml_config, LoadMode::Secret)
+	}
+	#[cfg(test)]
+	pub fn add_yaml_str(&mut self, file_path:&str, yaml_config:&str) -> Result<(), Box<dyn Error>> {
+		self.add_yaml(file_path, yaml_config, LoadMode::User)
+	}
+	fn add_yaml(&mut self, file_path:&str, yaml_config:&str, mode:LoadMode) -> Result<(), Box<dyn Error>> {
+		self.default_path = String::from(file_path);
+		let docs = match YamlLoader::load_from_str(yaml_config) {
+			Ok(docs) => docs,
+			Err(err) => {
+				self.issues.push(ConfigurationIssue {
+					severity: Severity::Fatal,
+					key: String::new(),
+					file: file_path.to_string(),
+					line: err.marker().line(),
+					message: format!("is not a valid YAML file : {}", err.info()),
+				});
+				return Err(Box::new(err));
+			}
+		};
+		let mut locator = KeyLocator::new();
+		Parser::new_from_str(yaml_config).load(&mut locator, false)?;
+		for (key, line) in locator.lines {
+			self.locations.insert(key, (file_path.to_string(), line));
+		}
+		if let Some(doc) = docs.first() {
+			if mode==LoadMode::Secret {
+				if let Yaml::Hash(secrets) = doc {
+					for (name, value) in secrets {
+						self.secrets.insert(cast_utility::to_type_str(name), value.clone());
+					}
+				}
+			}
+			self.add("", doc, mode);
+		}
+		for (key, tag) in locator.tags {
+			if tag=="secret" {
+				if self.conf.contains_key(&key) {
+					self.secret_refs.insert(key.clone(), self.get_as_str(&key));
+				}
+			} else {
+				let issue = self.issue(Severity::Warning, &key, format!("has an unknown tag '!{tag}'."));
+				self.issues.push(issue);
+			}
+		}
+		self.resolve_secrets();
 		Ok(())
 	}
+	fn resolve_secrets(&mut self) {
+		let mut resolved = Vec::new();
+		for (key, name) in &self.secret_refs {
+			if let Some(value) = self.secrets.get(name) {
+				self.conf.insert(key.clone(), Box::new(value.clone()));
+				resolved.push(key.clone());
+			}
+		}
+		for key in resolved {
+			self.secret_refs.remove(&key);
+		}
+	}
+	/// Override configuration with 'OPENHEMS_*' variables, the name is the key in upper case
+	///  with '.' replaced by '_' (OPENHEMS_SERVER_LOOPDELAY for server.loopDelay).
+	/// A '_FILE' suffix reads the value from a file (For docker secrets).
+	pub fn add_env_config<I:Iterator<Item=(String, String)>>(&mut self, vars:I) {
+		let known:HashMap<String, String> = self.conf.keys()
+			.map(|key| (key.replace('.', "_").to_uppercase(), key.clone()))
+			.collect();
+		for (name, value) in vars {
+			let Some(suffix) = name.strip_prefix("OPENHEMS_") else {
+				continue;
+			};
+			if suffix=="CONFIG" || suffix=="SECRET" {
+				continue; // Used for files path
+			}
+			let location = (format!("${name}"), 0);
+			if let Some(key) = known.get(suffix) {
+				let yaml = match YamlLoader::load_from_str(&value) {
+					Ok(docs) => match docs.into_iter().next() {
+						Some(Yaml::Hash(_)) | None => Yaml::String(value.clone()),
+						Some(yaml) => yaml,
+					}
+					Err(_) => Yaml::String(value.clone())
+				};
+				log::info!("Override '{key}' with environment variable {name}");
+				self.locations.insert(key.clone(), location);
+				self.add(key, &yaml, LoadMode::User);
+			} else if let Some(key) = suffix.strip_suffix("_FILE").and_then(|base| known.get(base)) {
+				let key = format!("{key}_file");
+				self.locations.insert(key.clone(), location);
+				self.add(&key, &Yaml::String(value), LoadMode::User);
+			} else {
+				self.issues.push(ConfigurationIssue {
+					severity: Severity::Warning,
+					key: name.clone(),
+					file: location.0,
+					line: 0,
+					message: "does not match any configuration key.".to_string(),
+				});
+			}
+		}
+	}
+	/// Build an issue located where the key (or its nearest parent) has been defined.
+	pub fn issue(&self, severity:Severity, key:&str, message:String) -> ConfigurationIssue {
+		let mut path = key;
+		let (file, line) = loop {
+			if let Some((file, line)) = self.locations.get(path) {
+				break (file.clone(), *line);
+			}
+			if let Some(pos) = path.rfind(['.', '[']) {
+				path = &path[..pos];
+			} else {
+				break (self.default_path.clone(), 0);
+			}
+		};
+		ConfigurationIssue {
+			severity,
+			key: key.to_string(),
+			file,
+			line,
+			message,
+		}
+	}
+	pub fn add_issue(&mut self, issue:ConfigurationIssue) {
+		self.issues.push(issue);
+	}
+	/// Return all problems found while loading and checking the configuration.
+	pub fn check(&self) -> Vec<ConfigurationIssue> {
+		let mut issues = self.issues.clone();
+		for (key, name) in &self.secret_refs {
+			issues.push(self.issue(Severity::Fatal, key, format!("use secret '{name}' which is not defined.")));
+		}
+		issues.extend(self.schema.check(self));
+		issues
+	}
 	pub fn get(&self, key:&str) -> Option<&Box<Yaml>>{
 		self.conf.get(key)
 	}
@@ -84,6 +331,16 @@ impl ConfigurationManager {
 			0.0
 		}
 	}
+	pub fn get_as_bool(&self, key:&str) -> bool {
+		if let Some(value) = self.conf.get(key) {
+			match value.as_ref() {
+				Yaml::Boolean(v) => *v,
+				_ => cast_utility::to_type_bool(value)
+			}
+		} else {
+			false
+		}
+	}
 	pub fn get_as_list<'a>(&'a self, key:&str) -> Vec<&'a Yaml> {
 		if let Some(value) = self.conf.get(key) {
 			cast_utility::to_type_list(value)
@@ -91,4 +348,40 @@ impl ConfigurationManager {
 			Vec::new()
 		}
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_secret_and_env() {
+		let mut configurator = get(None);
+		configurator.add_yaml_str("test.yaml", "
+api:
+  url: http://localhost:8123/api
+  long_lived_token: !secret ha_token
+server:
+  port: 8000
+").unwrap();
+		assert_eq!(configurator.check().iter().filter(|i| i.key=="api.long_lived_token").count(), 1);
+		configurator.add_yaml("secret.yaml", "ha_token: abcd", LoadMode::Secret).unwrap();
+		assert_eq!(configurator.get_as_str("api.long_lived_token"), "abcd");
+		let token_path = std::env::temp_dir().join("openhems_test_token");
+		fs::write(&token_path, "efgh\n").unwrap();
+		configurator.add_env_config(vec![
+			(String::from("OPENHEMS_SERVER_LOOPDELAY"), String::from("12")),
+			(String::from("OPENHEMS_SERVER_INDOCKER"), String::from("true")),
+			(String::from("OPENHEMS_API_LONG_LIVED_TOKEN_FILE"), token_path.to_string_lossy().to_string()),
+			(String::from("OPENHEMS_SERVER_UNKNOWN"), String::from("1")),
+			(String::from("PATH"), String::from("/bin")),
+		].into_iter());
+		assert_eq!(configurator.get_as_int("server.loopDelay"), 12);
+		assert!(configurator.get_as_bool("server.inDocker"));
+		assert_eq!(configurator.get_as_str("api.long_lived_token"), "efgh");
+		let issues = configurator.check();
+		let issue = issues.iter().find(|i| i.key=="OPENHEMS_SERVER_UNKNOWN").unwrap();
+		assert!(!issue.is_fatal());
+		assert!(issues.iter().all(|i| !i.is_fatal()));
+	}
+}
@@ -0,0 +1,495 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use yaml_rust2::Yaml;
+use crate::{auth, cast_utility, logger, modbus_updater, mqtt_updater, notification};
+use crate::configuration_manager::ConfigurationManager;
+use crate::home_assistant_api::Actuator;
+use crate::cycle::CycleDetector;
+use crate::error::OpenHemsError;
+use crate::expression::Expression;
+use crate::filter::FilterChain;
+use crate::schedule::Program;
+
+// Node classes Network::set_nodes() knows how to build.
+pub const NODE_CLASSES: [&str; 2] = ["publicpowergrid", "switch"];
+// Strategy classes Server::init() knows how to build, with their defaults section.
+pub const STRATEGY_CLASSES: [(&str, &str); 2] = [("offpeak", "offpeak"), ("solarnosell", "nosell")];
+pub const NETWORK_SOURCES: [&str; 3] = ["homeassistant", "mqtt", "modbus"];
+pub const LOG_LEVELS: [&str; 8] = ["debug", "info", "warn", "warning", "error", "critical", "no", "trace"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+	Warning,
+	Fatal,
+}
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Severity::Warning => write!(f, "warning"),
+			Severity::Fatal => write!(f, "error"),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigurationIssue {
+	pub severity: Severity,
+	pub key: String,
+	pub file: String,
+	pub line: usize,
+	pub message: String,
+}
+impl fmt::Display for ConfigurationIssue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}: {}: '{}' {}", self.file, self.line, self.severity, self.key, self.message)
+	}
+}
+impl ConfigurationIssue {
+	pub fn is_fatal(&self) -> bool {
+		self.severity==Severity::Fatal
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+	Int,
+	Float,
+	Str,
+	Bool,
+	List,
+	Any,
+}
+impl ValueType {
+	fn from_default(value:&Yaml) -> ValueType {
+		match value {
+			Yaml::Integer(_) => ValueType::Int,
+			Yaml::Real(_) => ValueType::Float,
+			Yaml::String(v) if v.is_empty() => ValueType::Any, // No hint on the type
+			Yaml::String(v) => {
+				// YAML 1.2 reads 'False' as a string, but it's meant as a boolean.
+				if ["true", "false"].iter().any(|&b| b==v.to_lowercase()) {
+					ValueType::Bool
+				} else {
+					ValueType::Str
+				}
+			}
+			Yaml::Boolean(_) => ValueType::Bool,
+			Yaml::Array(_) => ValueType::List,
+			_ => ValueType::Any,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct KeySchema {
+	pub value_type: ValueType,
+	pub min: Option<f32>,
+	pub max: Option<f32>,
+	pub required: bool,
+	pub choices: Vec<String>,
+}
+impl KeySchema {
+	fn new(value_type:ValueType) -> KeySchema {
+		KeySchema {
+			value_type,
+			min: None,
+			max: None,
+			required: false,
+			choices: Vec::new(),
+		}
+	}
+	/// Check one value against this schema, return an error message if invalid.
+	pub fn check(&self, value:&Yaml) -> Option<String> {
+		if let Yaml::BadValue = value {
+			return Some("has an invalid value.".to_string());
+		}
+		if let Yaml::Null = value {
+			if self.required {
+				return Some("is required.".to_string());
+			}
+			return None;
+		}
+		match self.value_type {
+			ValueType::Int => {
+				match cast_utility::try_to_type_int(value) {
+					Ok(v) => self.check_range(v as f32),
+					Err(err) => Some(format!("expect an integer : {}", err.message))
+				}
+			}
+			ValueType::Float => {
+				match cast_utility::try_to_type_float(value) {
+					Ok(v) => self.check_range(v),
+					Err(err) => Some(format!("expect a number : {}", err.message))
+				}
+			}
+			ValueType::Bool => {
+				match value {
+					Yaml::Boolean(_) => None,
+					Yaml::String(v) if ["true", "false", "1", "0"].iter().any(|&b| b==v.to_lowercase()) => None,
+					_ => Some(format!("expect a boolean, got {:?}.", value))
+				}
+			}
+			ValueType::Str => {
+				match value {
+					Yaml::Array(_) | Yaml::Hash(_) => Some("expect a single value, not a list.".to_string()),
+					_ => {
+						let v = cast_utility::to_type_str(value);
+						if self.required && v.is_empty() {
+							Some("is required.".to_string())
+						} else if !self.choices.is_empty() && !self.choices.iter().any(|c| *c==v.to_lowercase()) {
+							Some(format!("is '{v}' but must be one of {:?}.", self.choices))
+						} else {
+							None
+						}
+					}
+				}
+			}
+			ValueType::List => {
+				match value {
+					Yaml::Array(_) => None,
+					_ => Some("expect a list.".to_string())
+				}
+			}
+			ValueType::Any => None
+		}
+	}
+	fn check_range(&self, value:f32) -> Option<String> {
+		if let Some(min) = self.min {
+			if value<min {
+				return Some(format!("is {value} but must be >= {min}."));
+			}
+		}
+		if let Some(max) = self.max {
+			if value>max {
+				return Some(format!("is {value} but must be <= {max}."));
+			}
+		}
+		None
+	}
+}
+
+/// Describe every valid configuration key.
+/// Types come from openhems_default.yaml, ranges, choices and required keys are added on top.
+#[derive(Debug, Clone)]
+pub struct ConfigurationSchema {
+	keys: HashMap<String, KeySchema>,
+}
+impl ConfigurationSchema {
+	pub fn new() -> ConfigurationSchema {
+		ConfigurationSchema {
+			keys: HashMap::new(),
+		}
+	}
+	/// Register a key found in the default configuration file.
+	pub fn add_default(&mut self, key:&str, value:&Yaml) {
+		let mut value_type = ValueType::from_default(value);
+		if key=="default.node.switch.isOnDelay" {
+			value_type = ValueType::Any; // Seconds or an entity
+		} else if value_type==ValueType::Int && key.starts_with("default.node.") {
+			value_type = ValueType::Float; // Powers are read as float
+		}
+		let schema = KeySchema::new(value_type);
+		self.keys.insert(key.to_string(), schema);
+		self.refine(key);
+	}
+	fn refine(&mut self, key:&str) {
+		let Some(schema) = self.keys.get_mut(key) else {
+			return;
+		};
+		let (min, max, required, choices):(Option<f32>, Option<f32>, bool, &[&str]) = match key {
+			"api.url" => (None, None, true, &[]),
+			"api.timeout" => (Some(1.0), Some(300.0), false, &[]),
+			"api.retries" => (Some(0.0), Some(10.0), false, &[]),
+			"mqtt.port" | "modbus.port" => (Some(1.0), Some(65535.0), false, &[]),
+			"notifications.dedupDelay" | "notifications.maxPerHour" => (Some(0.0), None, false, &[]),
+			"teleinfo.mode" => (None, None, false, &["auto", "historic", "standard"]),
+			"modbus.unit" => (Some(0.0), Some(255.0), false, &[]),
+			"modbus.timeout" => (Some(1.0), Some(60.0), false, &[]),
+			"localization.latitude" => (Some(-90.0), Some(90.0), false, &[]),
+			"localization.longitude" => (Some(-180.0), Some(180.0), false, &[]),
+			"server.port" => (Some(1.0), Some(65535.0), false, &[]),
+			"server.loopDelay" => (Some(1.0), Some(3600.0), false, &[]),
+			"server.workers" => (Some(1.0), Some(64.0), false, &[]),
+			"server.staleDelay" => (Some(0.0), Some(86400.0), false, &[]),
+			"default.node.switch.isOnPower" | "default.node.switch.runningPower" => (Some(0.0), None, false, &[]),
+			"default.node.switch.failsafe" => (None, None, false, &["keep", "off", "on"]),
+			"server.logmaxsize" | "server.logbackups" => (Some(0.0), None, false, &[]),
+			"server.loglevel" => (None, None, false, &LOG_LEVELS),
+			"server.network" => (None, None, true, &NETWORK_SOURCES),
+			_ => (None, None, false, &[])
+		};
+		schema.min = min;
+		schema.max = max;
+		schema.required = required;
+		schema.choices = choices.iter().map(|c| c.to_string()).collect();
+	}
+	/// Keys allowed directly under a 'default.<section>.<classname>' section.
+	fn get_class_keys(&self, section:&str, classname:&str) -> HashMap<String, KeySchema> {
+		let prefix = format!("default.{section}.{classname}.");
+		let mut keys = HashMap::new();
+		for (key, schema) in &self.keys {
+			if let Some(subkey) = key.strip_prefix(&prefix) {
+				if let Some((parent, _)) = subkey.split_once('.') {
+					// Sub-dictionary (like 'contract'), checked elsewhere
+					keys.insert(parent.to_string(), KeySchema::new(ValueType::Any));
+				} else {
+					keys.insert(subkey.to_string(), schema.clone());
+				}
+			}
+		}
+		keys
+	}
+
+	/// Check the whole configuration and return every problem found.
+	pub fn check(&self, configurator:&ConfigurationManager) -> Vec<ConfigurationIssue> {
+		let mut issues = Vec::new();
+		let mut keys:Vec<&String> = self.keys.keys().collect();
+		keys.sort();
+		for key in keys {
+			let schema = &self.keys[key];
+			if let Some(value) = configurator.get(key) {
+				if let Some(message) = schema.check(value) {
+					issues.push(configurator.issue(Severity::Fatal, key, message));
+				}
+			}
+		}
+		if configurator.get_as_str("server.network")=="homeassistant"
+				&& configurator.get_as_str("api.long_lived_token").is_empty() {
+			issues.push(configurator.issue(Severity::Fatal, "api.long_lived_token",
+				"is required for Home-Assistant network.".to_string()));
+		}
+		if configurator.get_as_str("server.network")=="mqtt" {
+			for (i, entry) in configurator.get_as_list("mqtt.entities").iter().enumerate() {
+				if let Some(message) = mqtt_updater::check_entity(entry) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("mqtt.entities[{i}]"), message));
+				}
+			}
+		}
+		if configurator.get_as_str("server.network")=="modbus" {
+			for (i, entry) in configurator.get_as_list("modbus.registers").iter().enumerate() {
+				if let Some(message) = modbus_updater::check_register(entry) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("modbus.registers[{i}]"), message));
+				}
+			}
+		}
+		for (i, entry) in configurator.get_as_list("notifications.channels").iter().enumerate() {
+			if let Some(message) = notification::check_channel(entry) {
+				issues.push(configurator.issue(Severity::Fatal, &format!("notifications.channels[{i}]"), message));
+			}
+		}
+		for key in ["server.auth.users", "server.auth.tokens"] {
+			for (i, entry) in configurator.get_as_list(key).iter().enumerate() {
+				if let Some(message) = auth::check_entry(entry) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{key}[{i}]"), message));
+				}
+			}
+		}
+		let entries:Vec<String> = configurator.get_as_list("server.logmodules").iter()
+			.map(|v| cast_utility::to_type_str(v))
+			.collect();
+		if let Err(err) = logger::parse_modules(&entries) {
+			issues.push(configurator.issue(Severity::Fatal, "server.logmodules", err.message));
+		}
+		let strategies = self.check_strategies(configurator, &mut issues);
+		self.check_nodes(configurator, &strategies, &mut issues);
+		issues
+	}
+
+	fn check_class_keys(&self, configurator:&ConfigurationManager, path:&str,
+			conf:&HashMap<String, &Yaml>, allowed:&HashMap<String, KeySchema>,
+			issues:&mut Vec<ConfigurationIssue>) {
+		for (key, value) in conf {
+			let subpath = format!("{path}.{key}");
+			if key=="id" || key=="class" {
+				continue;
+			}
+			if let Some(schema) = allowed.get(key) {
+				if let Some(message) = schema.check(value) {
+					issues.push(configurator.issue(Severity::Fatal, &subpath, message));
+				}
+			} else {
+				issues.push(configurator.issue(Severity::Warning, &subpath,
+					"is not a known key, it will be ignored.".to_string()));
+			}
+		}
+	}
+
+	fn check_strategies(&self, configurator:&ConfigurationManager, issues:&mut Vec<ConfigurationIssue>) -> HashSet<String> {
+		let mut ids = HashSet::new();
+		for (i, strategy) in configurator.get_as_list("server.strategies").iter().enumerate() {
+			let path = format!("server.strategies[{i}]");
+			let Yaml::Hash(_) = strategy else {
+				issues.push(configurator.issue(Severity::Fatal, &path, "must be a dictionary.".to_string()));
+				continue;
+			};
+			let conf = cast_utility::to_type_dict(strategy);
+			if let Some(id) = conf.get("id") {
+				let id = cast_utility::to_type_str(id);
+				if !ids.insert(id.clone()) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{path}.id"),
+						format!("'{id}' is used by many strategies.")));
+				}
+			} else {
+				issues.push(configurator.issue(Severity::Fatal, &path, "has no 'id'.".to_string()));
+			}
+			let Some(classname) = conf.get("class") else {
+				issues.push(configurator.issue(Severity::Fatal, &path, "has no 'class'.".to_string()));
+				continue;
+			};
+			let classname = cast_utility::to_type_str(classname).to_lowercase();
+			if let Some((_, section)) = STRATEGY_CLASSES.iter().find(|(c, _)| *c==classname) {
+				let allowed = self.get_class_keys("strategy", section);
+				self.check_class_keys(configurator, &path, &conf, &allowed, issues);
+			} else {
+				let classes:Vec<&str> = STRATEGY_CLASSES.iter().map(|(c, _)| *c).collect();
+				issues.push(configurator.issue(Severity::Fatal, &format!("{path}.class"),
+					format!("'{classname}' is not a supported strategy, use one of {classes:?}.")));
+			}
+		}
+		ids
+	}
+
+	fn check_nodes(&self, configurator:&ConfigurationManager, strategies:&HashSet<String>, issues:&mut Vec<ConfigurationIssue>) {
+		let mut ids = HashSet::new();
+		for (i, node) in configurator.get_as_list("network.nodes").iter().enumerate() {
+			let path = format!("network.nodes[{i}]");
+			let Yaml::Hash(_) = node else {
+				issues.push(configurator.issue(Severity::Fatal, &path, "must be a dictionary.".to_string()));
+				continue;
+			};
+			let conf = cast_utility::to_type_dict(node);
+			if let Some(id) = conf.get("id") {
+				let id = cast_utility::to_type_str(id);
+				if !ids.insert(id.clone()) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{path}.id"),
+						format!("'{id}' is used by many nodes.")));
+				}
+			} else {
+				issues.push(configurator.issue(Severity::Warning, &path, "has no 'id', a generic one will be used.".to_string()));
+			}
+			let Some(classname) = conf.get("class") else {
+				issues.push(configurator.issue(Severity::Fatal, &path, "has no 'class'.".to_string()));
+				continue;
+			};
+			let classname = cast_utility::to_type_str(classname).to_lowercase();
+			if !NODE_CLASSES.iter().any(|&c| c==classname) {
+				issues.push(configurator.issue(Severity::Fatal, &format!("{path}.class"),
+					format!("'{classname}' is not a supported node, use one of {NODE_CLASSES:?}.")));
+				continue;
+			}
+			let allowed = self.get_class_keys("node", &classname);
+			self.check_class_keys(configurator, &path, &conf, &allowed, issues);
+			match conf.get("currentPower") {
+				Some(Yaml::String(expression)) => {
+					if let Err(err) = Expression::parse(expression) {
+						issues.push(configurator.issue(Severity::Fatal, &format!("{path}.currentPower"), err.message));
+					}
+				}
+				Some(feeder @ Yaml::Hash(_)) => {
+					let feeder = cast_utility::to_type_dict(feeder);
+					let entity = match (feeder.get("entity"), feeder.get("default")) {
+						(Some(Yaml::String(expression)), _) => Expression::parse(expression).map(|_| ()),
+						(None, Some(Yaml::Integer(_) | Yaml::Real(_))) => Ok(()),
+						_ => Err(OpenHemsError::new("needs an 'entity' or a 'default' value.".to_string()))
+					};
+					let filters = match feeder.get("filters") {
+						Some(filters) => FilterChain::from_conf(filters).map(|_| ()),
+						None => Ok(())
+					};
+					if let Err(err) = entity.and(filters) {
+						issues.push(configurator.issue(Severity::Fatal, &format!("{path}.currentPower"), err.message));
+					}
+				}
+				Some(Yaml::Integer(_) | Yaml::Real(_)) => {}
+				_ => {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{path}.currentPower"),
+						"is required and must be an entity id, an expression or a constant.".to_string()));
+				}
+			}
+			if classname=="publicpowergrid" {
+				match conf.get("contract") {
+					Some(Yaml::Hash(_)) => {}
+					_ => {
+						issues.push(configurator.issue(Severity::Fatal, &format!("{path}.contract"),
+							"is required for a public power grid.".to_string()));
+					}
+				}
+			}
+			if let Some(actuator) = conf.get("actuator") {
+				let is_on = conf.get("isOn").map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+				if let Some(Yaml::Boolean(_) | Yaml::Integer(_)) = conf.get("isOn") {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{path}.actuator"),
+						"can not switch a constant 'isOn', give its entity.".to_string()));
+				} else if let Err(err) = Actuator::new(&is_on, Some(actuator)) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{path}.actuator"), err.message));
+				}
+			}
+			if let Some(cycle) = conf.get("cycle") {
+				if let Err(err) = CycleDetector::from_conf(cycle) {
+					issues.push(configurator.issue(Severity::Fatal, &format!("{path}.cycle"), err.message));
+				}
+			}
+			if let Some(Yaml::Array(programs)) = conf.get("programs") {
+				for (j, program) in programs.iter().enumerate() {
+					if let Err(err) = Program::from_conf(program) {
+						issues.push(configurator.issue(Severity::Fatal, &format!("{path}.programs[{j}]"), err.message));
+					}
+				}
+			}
+			if let Some(strategy) = conf.get("strategy") {
+				let strategy = cast_utility::to_type_str(strategy);
+				if !strategy.is_empty() && !strategies.contains(&strategy) {
+					issues.push(configurator.issue(Severity::Warning, &format!("{path}.strategy"),
+						format!("'{strategy}' is not a declared strategy id.")));
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::configuration_manager;
+	use super::*;
+
+	#[test]
+	fn test_check_config() {
+		let mut configurator = configuration_manager::get(None);
+		configurator.add_yaml_str("test.yaml", "
+api:
+  publish: sometimes
+server:
+  network: fake
+  loopDelay: 0
+  unknown: 2
+  strategies:
+    - {class: offpeak, id: offpeak}
+    - {class: magic, id: offpeak}
+network:
+  nodes:
+    - {id: linky, class: PublicPowerGrid, currentPower: sensor.power, maxPower: many}
+    - {id: car, class: switch, currentPower: sensor.car, strategy: offpeak, failsafe: sometimes}
+    - {id: grid, class: PublicPowerGrid, currentPower: sensor.grid, contract: {class: RTETempo}}
+    - {id: ev, class: switch, currentPower: sensor.ev, isOn: switch.ev, actuator: {entity: number.ev_current, on: 16}}
+    - {id: pump, class: switch, currentPower: sensor.pump, isOn: true, actuator: {entity: switch.pump}}
+").unwrap();
+		let issues = configurator.check();
+		let find = |key:&str| issues.iter().find(|i| i.key==key);
+		assert!(find("api.publish").unwrap().is_fatal());
+		assert!(find("server.network").unwrap().is_fatal());
+		let issue = find("server.loopDelay").unwrap();
+		assert!(issue.is_fatal());
+		assert_eq!(issue.line, 6);
+		assert_eq!(find("server.unknown").unwrap().severity, Severity::Warning);
+		assert_eq!(find("server.strategies[1].id").unwrap().line, 10);
+		assert!(find("server.strategies[1].class").unwrap().is_fatal());
+		assert!(find("network.nodes[0].maxPower").unwrap().is_fatal());
+		assert!(find("network.nodes[0].contract").unwrap().is_fatal());
+		assert!(find("network.nodes[1].currentPower").is_none());
+		assert!(find("network.nodes[1].strategy").is_none());
+		assert!(find("network.nodes[1].failsafe").unwrap().is_fatal());
+		assert!(find("network.nodes[2].contract").is_none());
+		assert!(find("network.nodes[3].actuator").unwrap().is_fatal());
+		assert!(find("network.nodes[4].actuator").unwrap().message.contains("constant"));
+	}
+}
@@ -1,19 +1,62 @@
+use std::cell::RefCell;
+use std::rc::Rc;
 use yaml_rust2::Yaml;
 use yaml_rust2::YamlLoader;
 use crate::cast_utility;
+use crate::feeder::{EnumFeeder, Feeder};
+use crate::network_updater::NetworkUpdater;
 use crate::{
-	error::ResultOpenHems, time::HoursRanges
+	error::{OpenHemsError, ResultOpenHems}, time::HoursRanges
 };
 
+const TEMPO_COLORS:[&str; 3] = ["bleu", "blanc", "rouge"];
+
 #[derive(Debug, Clone)]
 pub struct Contract {
 	timeslots: HoursRanges,
+	period: Option<Feeder<String>>, // Current tariff period from the meter (HC/HP), overrides timeslots
+	color: Option<EnumFeeder>, // Current Tempo colour
 }
 impl Contract {
 	pub fn get_hoursranges(&self) -> &HoursRanges {
 		&self.timeslots
 	}
-	pub fn get_from_conf(contract_conf: &Yaml) -> ResultOpenHems<Contract>{
+	/// Offpeak status given by the 'period' entity (like 'teleinfo.period' or a PTEC sensor),
+	/// None when not set or unavailable.
+	pub fn is_offpeak(&self) -> Option<bool> {
+		let mut period = self.period.clone()?;
+		match period.get_value() {
+			Ok(value) => {
+				let value = value.to_uppercase();
+				Some(value.starts_with("HC") || value.contains("CREUSE"))
+			}
+			Err(err) => {
+				log::warn!("Contract : fail get tariff period : {}", err.message);
+				None
+			}
+		}
+	}
+	/// Current Tempo colour (bleu, blanc or rouge) given by the 'color' entity.
+	pub fn get_color(&self) -> Option<String> {
+		match self.color.clone()?.get_value() {
+			Ok(color) => Some(color),
+			Err(err) => {
+				log::warn!("Contract : fail get Tempo colour : {}", err.message);
+				None
+			}
+		}
+	}
+	// Feeder for an optional entity key (or {entity: <id>, default: <value>}).
+	fn get_feeder(updater:&Rc<RefCell<NetworkUpdater>>, contract_conf:&Yaml, key:&str) -> ResultOpenHems<Option<Feeder<String>>> {
+		match &contract_conf[key] {
+			Yaml::Null | Yaml::BadValue => Ok(None),
+			Yaml::String(entity_id) if entity_id.is_empty() => Ok(None),
+			conf => Feeder::from_conf(updater, Some(conf), None)
+				.map(Some)
+				.map_err(|err| OpenHemsError::new(format!("Invalid contract '{key}' : {}", err.message)))
+		}
+	}
+	pub fn get_from_conf(contract_conf: &Yaml, updater:Rc<RefCell<NetworkUpdater>>) -> ResultOpenHems<Contract>{
 		let default_config = "[\"22h-6h\"]";
 		let configs = YamlLoader::load_from_str(default_config).unwrap();
 		let mut config = &configs[0];
@@ -48,6 +91,11 @@ impl Contract {
 			default_cost, outrange_cost)?;
 		Ok(Contract {
 			timeslots: ranges,
+			period: Self::get_feeder(&updater, contract_conf, "period")?,
+			color: Self::get_feeder(&updater, contract_conf, "color")?
+				.map(|feeder| EnumFeeder::new(feeder, &TEMPO_COLORS))
+				.transpose()
+				.map_err(|err| OpenHemsError::new(format!("Invalid contract 'color' : {}", err.message)))?,
 		})
 	}
 }
\ No newline at end of file
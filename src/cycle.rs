@@ -0,0 +1,109 @@
+use chrono::{DateTime, Local};
+use yaml_rust2::Yaml;
+use crate::cast_utility;
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+/// Run state of an appliance (dishwasher, washing machine...) guessed from its power.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CycleState {
+	Idle,
+	Running, // Must not be switched off
+	Finished, // A cycle ended, until the next one starts
+}
+impl CycleState {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			CycleState::Idle => "idle",
+			CycleState::Running => "running",
+			CycleState::Finished => "finished",
+		}
+	}
+}
+
+/// Detect appliance cycles : running when the power stays above 'start_power' for 'start_delay' seconds,
+/// finished when it stays below 'end_power' for 'end_delay' seconds (pauses of the cycle are shorter).
+#[derive(Clone, Debug)]
+pub struct CycleDetector {
+	start_power: f32,
+	start_delay: u32,
+	end_power: f32,
+	end_delay: u32,
+	state: CycleState,
+	since: Option<DateTime<Local>>, // When the power crossed the threshold to change state
+}
+impl CycleDetector {
+	pub fn new(start_power:f32, start_delay:u32, end_power:f32, end_delay:u32) -> ResultOpenHems<CycleDetector> {
+		if end_power>start_power {
+			return Err(OpenHemsError::new(format!(
+				"Cycle 'endPower' ({end_power}) must not be above 'startPower' ({start_power})."
+			)));
+		}
+		Ok(CycleDetector {
+			start_power,
+			start_delay,
+			end_power,
+			end_delay,
+			state: CycleState::Idle,
+			since: None,
+		})
+	}
+	/// Detector from a node 'cycle' configuration, None when 'startPower' is not set.
+	pub fn from_conf(conf:&Yaml) -> ResultOpenHems<Option<CycleDetector>> {
+		let conf = cast_utility::to_type_dict(conf);
+		let start_power = match conf.get("startPower") {
+			Some(Yaml::Null) | None => {
+				return Ok(None);
+			}
+			Some(v) => cast_utility::to_type_float(v),
+		};
+		let get_int = |key:&str, default_value:i32| conf.get(key).map(|v| cast_utility::to_type_int(v)).unwrap_or(default_value).max(0) as u32;
+		let end_power = conf.get("endPower").map(|v| cast_utility::to_type_float(v)).unwrap_or(5.0);
+		Ok(Some(CycleDetector::new(start_power, get_int("startDelay", 60), end_power, get_int("endDelay", 300))?))
+	}
+	pub fn get_state(&self) -> CycleState {
+		self.state
+	}
+	/// Update the state with the current 'power'. Return the new state if it changed.
+	pub fn update(&mut self, now:DateTime<Local>, power:f32) -> Option<CycleState> {
+		let (crossed, delay, next) = match self.state {
+			CycleState::Idle | CycleState::Finished => (power>=self.start_power, self.start_delay, CycleState::Running),
+			CycleState::Running => (power<self.end_power, self.end_delay, CycleState::Finished),
+		};
+		if !crossed {
+			self.since = None;
+			return None;
+		}
+		let since = *self.since.get_or_insert(now);
+		if (now-since).num_seconds()<delay as i64 {
+			return None;
+		}
+		self.state = next;
+		self.since = None;
+		Some(next)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Duration;
+	use super::*;
+
+	#[test]
+	fn test_cycle() {
+		let conf = &yaml_rust2::YamlLoader::load_from_str("{startPower: 50, startDelay: 60, endDelay: 300}").unwrap()[0];
+		let mut cycle = CycleDetector::from_conf(conf).unwrap().unwrap();
+		let now = Local::now();
+		let at = |s:i64| now+Duration::seconds(s);
+		assert_eq!(cycle.update(at(0), 2000.0), None);
+		assert_eq!(cycle.update(at(30), 1.0), None); // Spike
+		assert_eq!(cycle.update(at(40), 2000.0), None);
+		assert_eq!(cycle.update(at(100), 2000.0), Some(CycleState::Running));
+		assert_eq!(cycle.update(at(200), 2.0), None); // Pause
+		assert_eq!(cycle.update(at(400), 100.0), None);
+		assert_eq!(cycle.update(at(500), 2.0), None);
+		assert_eq!(cycle.update(at(800), 2.0), Some(CycleState::Finished));
+		assert_eq!(cycle.get_state(), CycleState::Finished);
+		assert!(CycleDetector::from_conf(&Yaml::Hash(Default::default())).unwrap().is_none());
+		assert!(CycleDetector::new(5.0, 0, 10.0, 0).is_err());
+	}
+}
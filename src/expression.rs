@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use chrono::{DateTime, Local};
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+/// Arithmetic expression over entities values, like "max(0, sensor.grid + sensor.solar - sensor.battery) * 1000".
+/// Functions are min(), max(), abs() and avg(expression, seconds) for a moving average.
+#[derive(Debug)]
+pub enum Expression {
+	Number(f32),
+	Entity(String),
+	Neg(Box<Expression>),
+	Binary(char, Box<Expression>, Box<Expression>),
+	Function(String, Vec<Expression>),
+	Average(Box<Expression>, u32, RefCell<VecDeque<(DateTime<Local>, f32)>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Number(f32),
+	Name(String),
+	Symbol(char),
+}
+
+struct Parser {
+	tokens: Vec<Token>,
+	index: usize,
+}
+impl Parser {
+	fn tokenize(text:&str) -> ResultOpenHems<Vec<Token>> {
+		let mut tokens = Vec::new();
+		let chars:Vec<char> = text.chars().collect();
+		let mut i = 0;
+		while i<chars.len() {
+			let c = chars[i];
+			if c.is_whitespace() {
+				i += 1;
+			} else if c.is_ascii_digit() || (c=='.' && chars.get(i+1).is_some_and(|c| c.is_ascii_digit())) {
+				let start = i;
+				while i<chars.len() && (chars[i].is_ascii_digit() || chars[i]=='.') {
+					i += 1;
+				}
+				let number:String = chars[start..i].iter().collect();
+				tokens.push(Token::Number(number.parse()
+					.map_err(|_| OpenHemsError::new(format!("Invalid number '{number}' in expression '{text}'.")))?));
+			} else if c.is_alphabetic() || c=='_' {
+				let start = i;
+				while i<chars.len() && (chars[i].is_alphanumeric() || "_.#".contains(chars[i])) {
+					i += 1;
+				}
+				tokens.push(Token::Name(chars[start..i].iter().collect()));
+			} else if "+-*/(),".contains(c) {
+				tokens.push(Token::Symbol(c));
+				i += 1;
+			} else {
+				return Err(OpenHemsError::new(format!("Unexpected '{c}' in expression '{text}'.")));
+			}
+		}
+		Ok(tokens)
+	}
+	fn next(&mut self) -> Option<Token> {
+		let token = self.tokens.get(self.index).cloned();
+		self.index += 1;
+		token
+	}
+	fn peek_symbol(&self, symbols:&str) -> Option<char> {
+		match self.tokens.get(self.index) {
+			Some(Token::Symbol(c)) if symbols.contains(*c) => Some(*c),
+			_ => None
+		}
+	}
+	fn expect(&mut self, symbol:char) -> ResultOpenHems<()> {
+		match self.next() {
+			Some(Token::Symbol(c)) if c==symbol => Ok(()),
+			token => Err(OpenHemsError::new(format!("Expected '{symbol}' but got {token:?}.")))
+		}
+	}
+	// sum := product (('+'|'-') product)*
+	fn parse_sum(&mut self) -> ResultOpenHems<Expression> {
+		let mut left = self.parse_product()?;
+		while let Some(op) = self.peek_symbol("+-") {
+			self.index += 1;
+			left = Expression::Binary(op, Box::new(left), Box::new(self.parse_product()?));
+		}
+		Ok(left)
+	}
+	// product := unary (('*'|'/') unary)*
+	fn parse_product(&mut self) -> ResultOpenHems<Expression> {
+		let mut left = self.parse_unary()?;
+		while let Some(op) = self.peek_symbol("*/") {
+			self.index += 1;
+			left = Expression::Binary(op, Box::new(left), Box::new(self.parse_unary()?));
+		}
+		Ok(left)
+	}
+	// unary := '-' unary | number | entity | function '(' sum (',' sum)* ')' | '(' sum ')'
+	fn parse_unary(&mut self) -> ResultOpenHems<Expression> {
+		match self.next() {
+			Some(Token::Symbol('-')) => Ok(Expression::Neg(Box::new(self.parse_unary()?))),
+			Some(Token::Symbol('(')) => {
+				let expression = self.parse_sum()?;
+				self.expect(')')?;
+				Ok(expression)
+			}
+			Some(Token::Number(value)) => Ok(Expression::Number(value)),
+			Some(Token::Name(name)) if name.contains('.') => Ok(Expression::Entity(name)),
+			Some(Token::Name(name)) => {
+				self.expect('(')?;
+				let mut args = vec![self.parse_sum()?];
+				while self.peek_symbol(",").is_some() {
+					self.index += 1;
+					args.push(self.parse_sum()?);
+				}
+				self.expect(')')?;
+				Expression::function(name.to_lowercase(), args)
+			}
+			token => Err(OpenHemsError::new(format!("Unexpected {token:?}.")))
+		}
+	}
+}
+
+impl Expression {
+	pub fn parse(text:&str) -> ResultOpenHems<Expression> {
+		let mut parser = Parser {tokens: Parser::tokenize(text)?, index: 0};
+		let expression = parser.parse_sum()
+			.map_err(|err| OpenHemsError::new(format!("Invalid expression '{text}' : {}", err.message)))?;
+		if parser.index<parser.tokens.len() {
+			return Err(OpenHemsError::new(format!("Invalid expression '{text}' : unexpected {:?}.", parser.tokens[parser.index])));
+		}
+		Ok(expression)
+	}
+	fn function(name:String, mut args:Vec<Expression>) -> ResultOpenHems<Expression> {
+		match (name.as_str(), args.len()) {
+			("min" | "max", n) if n>=1 => Ok(Expression::Function(name, args)),
+			("abs", 1) => Ok(Expression::Function(name, args)),
+			("avg", 2) => {
+				let Some(Expression::Number(seconds)) = args.pop() else {
+					return Err(OpenHemsError::new("avg() second argument must be a number of seconds.".to_string()));
+				};
+				Ok(Expression::Average(Box::new(args.remove(0)), seconds.max(0.0) as u32, RefCell::new(VecDeque::new())))
+			}
+			_ => Err(OpenHemsError::new(format!("Unknown function {name}() with {} argument(s).", args.len())))
+		}
+	}
+	/// Entities ids used by the expression.
+	pub fn get_entities(&self) -> Vec<String> {
+		let mut ids = Vec::new();
+		self.add_entities(&mut ids);
+		ids
+	}
+	fn add_entities(&self, ids:&mut Vec<String>) {
+		match self {
+			Expression::Number(_) => {}
+			Expression::Entity(id) => {
+				if !ids.contains(id) {
+					ids.push(id.clone());
+				}
+			}
+			Expression::Neg(e) | Expression::Average(e, _, _) => e.add_entities(ids),
+			Expression::Binary(_, left, right) => {
+				left.add_entities(ids);
+				right.add_entities(ids);
+			}
+			Expression::Function(_, args) => {
+				for arg in args {
+					arg.add_entities(ids);
+				}
+			}
+		}
+	}
+	/// Compute the value at 'now', with entities values given by 'get'.
+	pub fn evaluate(&self, now:DateTime<Local>, get:&dyn Fn(&str) -> ResultOpenHems<f32>) -> ResultOpenHems<f32> {
+		Ok(match self {
+			Expression::Number(value) => *value,
+			Expression::Entity(id) => get(id)?,
+			Expression::Neg(e) => -e.evaluate(now, get)?,
+			Expression::Binary(op, left, right) => {
+				let (left, right) = (left.evaluate(now, get)?, right.evaluate(now, get)?);
+				match op {
+					'+' => left+right,
+					'-' => left-right,
+					'*' => left*right,
+					_ => {
+						if right==0.0 {
+							return Err(OpenHemsError::new("Division by zero in expression.".to_string()));
+						}
+						left/right
+					}
+				}
+			}
+			Expression::Function(name, args) => {
+				let values = args.iter().map(|arg| arg.evaluate(now, get)).collect::<ResultOpenHems<Vec<f32>>>()?;
+				match name.as_str() {
+					"min" => values.into_iter().fold(f32::INFINITY, f32::min),
+					"max" => values.into_iter().fold(f32::NEG_INFINITY, f32::max),
+					_ => values[0].abs(),
+				}
+			}
+			Expression::Average(e, seconds, history) => {
+				let value = e.evaluate(now, get)?;
+				let mut history = history.borrow_mut();
+				history.push_back((now, value));
+				while history.front().is_some_and(|(date, _)| (now-*date).num_seconds()>*seconds as i64) {
+					history.pop_front();
+				}
+				history.iter().map(|(_, v)| v).sum::<f32>()/(history.len() as f32)
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Duration;
+	use super::*;
+
+	#[test]
+	fn test_expression() {
+		let get = |id:&str| match id {
+			"sensor.grid" => Ok(500.0),
+			"sensor.solar" => Ok(1.5),
+			"sensor.battery#power" => Ok(-200.0),
+			_ => Err(OpenHemsError::new(format!("No entity '{id}'.")))
+		};
+		let now = Local::now();
+		let expression = Expression::parse("max(0, sensor.grid + sensor.solar*1000 - sensor.battery#power)").unwrap();
+		assert_eq!(expression.get_entities(), vec!["sensor.grid", "sensor.solar", "sensor.battery#power"]);
+		assert_eq!(expression.evaluate(now, &get).unwrap(), 2200.0);
+		assert_eq!(Expression::parse("-(2+4)/3 - abs(-1)").unwrap().evaluate(now, &get).unwrap(), -3.0);
+		assert!(Expression::parse("sensor.unknown").unwrap().evaluate(now, &get).is_err());
+		assert!(Expression::parse("max(1, 2").is_err());
+		assert!(Expression::parse("sqrt(4)").is_err());
+		assert!(Expression::parse("1 2").is_err());
+		let average = Expression::parse("avg(sensor.grid*t.x, 60)").unwrap();
+		let get2 = |x:f32| move |id:&str| if id=="t.x" {Ok(x)} else {get(id)};
+		assert_eq!(average.evaluate(now, &get2(1.0)).unwrap(), 500.0);
+		assert_eq!(average.evaluate(now+Duration::seconds(30), &get2(2.0)).unwrap(), 750.0);
+		assert_eq!(average.evaluate(now+Duration::seconds(80), &get2(3.0)).unwrap(), 1250.0);
+	}
+}
@@ -1,39 +1,117 @@
 use std::cell::{RefCell, RefMut};
 use std::rc::Rc;
 
-use arrayvec::ArrayString;
+use chrono::{DateTime, Local};
+use yaml_rust2::Yaml;
+use crate::cast_utility;
 use crate::error::OpenHemsError;
+use crate::expression::Expression;
+use crate::filter::FilterChain;
+use crate::time;
 
-use crate::home_assistant_api::HomeAssistantAPI;
+use crate::home_assistant_api::Actuator;
+use crate::network_updater::NetworkUpdater;
 use crate::{error::ResultOpenHems, home_assistant_api::HomeStateUpdater};
 
 pub trait FeederOutType<T:Clone> {
 	fn default() -> T;
+	/// Constant from the configuration, None if it is not of this type.
+	fn from_yaml(value:&Yaml) -> Option<T>;
 }
 impl FeederOutType<i32> for i32 {
 	fn default() -> i32 {
 		0
 	}
+	fn from_yaml(value:&Yaml) -> Option<i32> {
+		value.as_i64().map(|v| v as i32)
+	}
 }
 impl FeederOutType<f32> for f32 {
 	fn default() -> f32 {
 		0.0
 	}
+	fn from_yaml(value:&Yaml) -> Option<f32> {
+		value.as_f64().or(value.as_i64().map(|v| v as f64)).map(|v| v as f32)
+	}
 }
 impl FeederOutType<String> for String {
 	fn default() -> String {
 		String::from("")
 	}
+	fn from_yaml(value:&Yaml) -> Option<String> {
+		match value {
+			Yaml::Null | Yaml::Hash(_) | Yaml::Array(_) => None,
+			value => Some(cast_utility::to_type_str(value))
+		}
+	}
 }
 impl FeederOutType<bool> for bool {
 	fn default() -> bool {
 		true
 	}
+	fn from_yaml(value:&Yaml) -> Option<bool> {
+		value.as_bool()
+	}
 }
 #[derive(Clone, Debug)]
 pub enum Feeder<T:FeederOutType<T>+Clone> {
 	Source(SourceFeeder<T>),
-	Const(ConstFeeder<T>)
+	Const(ConstFeeder<T>),
+	Guess(GuessIsOnFeeder), // Only for bool
+	Computed(ComputedFeeder), // Only for f32
+}
+impl<T:FeederOutType<T>+Clone> Feeder<T> {
+	/// Feeder for a node configuration value : an entity id, a constant, or {entity: <id>, default: <constant>}
+	/// (A string constant is only given as default). When the entity is missing, 'default' is used if any.
+	pub fn from_conf(updater:&Rc<RefCell<NetworkUpdater>>, conf:Option<&Yaml>, default_value:Option<T>) -> ResultOpenHems<Feeder<T>> {
+		let (entity_id, default_value) = match conf {
+			Some(Yaml::String(entity_id)) if !entity_id.is_empty() => (Some(entity_id.clone()), default_value),
+			Some(conf @ Yaml::Hash(_)) => {
+				let conf = cast_utility::to_type_dict(conf);
+				let entity_id = conf.get("entity").map(|v| cast_utility::to_type_str(v)).filter(|e| !e.is_empty());
+				(entity_id, conf.get("default").and_then(|v| T::from_yaml(v)).or(default_value))
+			}
+			Some(Yaml::String(_)) | Some(Yaml::Null) | None => (None, default_value),
+			Some(value) => {
+				return match T::from_yaml(value) {
+					Some(value) => Ok(Feeder::Const(ConstFeeder::new(value))),
+					None => Err(OpenHemsError::new(format!("Invalid value {value:?}, expect an entity id or a constant.")))
+				};
+			}
+		};
+		match (entity_id, default_value) {
+			(Some(entity_id), default_value) => {
+				if updater.borrow().has_entity(&entity_id) {
+					Ok(Feeder::Source(SourceFeeder::new(Rc::clone(updater), &entity_id)?))
+				} else if let Some(value) = default_value {
+					log::warn!("No entity '{entity_id}' found, use its default value.");
+					Ok(Feeder::Const(ConstFeeder::new(value)))
+				} else {
+					Err(OpenHemsError::new(format!("No entity '{entity_id}' found.")))
+				}
+			}
+			(None, Some(value)) => Ok(Feeder::Const(ConstFeeder::new(value))),
+			(None, None) => Err(OpenHemsError::new("No entity nor default value.".to_string()))
+		}
+	}
+	/// Last time the source reported the value, None for constants (never stale).
+	pub fn get_last_update(&self) -> Option<DateTime<Local>> {
+		match self {
+			Feeder::Source(feeder) => Some(feeder.get_last_update()),
+			Feeder::Const(_) => None,
+			Feeder::Guess(feeder) => Some(feeder.get_last_update()),
+			Feeder::Computed(feeder) => Some(feeder.get_last_update()),
+		}
+	}
+	/// Switch with the actuator (on the power entity source for a guessed state).
+	pub fn switch(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		match self {
+			Feeder::Source(feeder) => feeder.switch(actuator, on),
+			Feeder::Guess(feeder) => feeder.switch(actuator, on),
+			Feeder::Computed(feeder) => feeder.switch(actuator, on),
+			Feeder::Const(_) => Err(OpenHemsError::new("Can't switch a constant state.".to_string()))
+		}
+	}
 }
 impl<'a> Feeder<bool> {
 	pub fn get_value(&self) -> ResultOpenHems<bool> {
@@ -44,6 +122,56 @@ impl<'a> Feeder<bool> {
 			Feeder::Const(mut feeder) => {
 				feeder.get_value()
 			}
+			Feeder::Guess(feeder) => {
+				feeder.get_value()
+			}
+			Feeder::Computed(_) => {
+				Err(OpenHemsError::new("A computed value is not a boolean.".to_string()))
+			}
+		}
+	}
+}
+impl Feeder<String> {
+	pub fn get_value(&mut self) -> ResultOpenHems<String> {
+		match self {
+			Feeder::Source(feeder) => feeder.get_value(),
+			Feeder::Const(feeder) => feeder.get_value(),
+			_ => Err(OpenHemsError::new("Not a string feeder.".to_string()))
+		}
+	}
+}
+impl Feeder<i32> {
+	pub fn get_value(&mut self) -> ResultOpenHems<i32> {
+		match self {
+			Feeder::Source(feeder) => feeder.get_value(),
+			Feeder::Const(feeder) => feeder.get_value(),
+			_ => Err(OpenHemsError::new("Not an integer feeder.".to_string()))
+		}
+	}
+}
+impl Feeder<f32> {
+	pub fn get_value(&mut self) -> ResultOpenHems<f32> {
+		match self {
+			Feeder::Source(feeder) => feeder.get_value(),
+			Feeder::Const(feeder) => feeder.get_value(),
+			Feeder::Computed(feeder) => feeder.get_value(),
+			Feeder::Guess(_) => Err(OpenHemsError::new("A guessed state is not a number.".to_string()))
+		}
+	}
+	/// Value before filters.
+	pub fn get_raw_value(&mut self) -> ResultOpenHems<f32> {
+		match self {
+			Feeder::Source(feeder) => feeder.get_raw_value(),
+			Feeder::Computed(feeder) => feeder.get_raw_value(),
+			_ => self.get_value()
+		}
+	}
+	/// Smooth values with 'filters' (Constants are not filtered).
+	pub fn set_filters(&mut self, filters:FilterChain) {
+		match self {
+			Feeder::Source(feeder) => feeder.set_filters(filters),
+			Feeder::Computed(feeder) => feeder.set_filters(filters),
+			_ => {}
 		}
 	}
 }
@@ -51,10 +179,11 @@ impl<'a> Feeder<bool> {
 // #[derive(Debug, Clone)] // , Clone implemented manually
 #[derive(Debug, Clone)]
 pub struct SourceFeeder<T:FeederOutType<T>+Clone> {
-	nameid: ArrayString<64>, // Home Assistant  entity id are long (sensor.lixee_zlinky_tic_puissance_apparente)
-	source: Rc<RefCell<HomeAssistantAPI>>,
+	nameid: String, // Home Assistant entity id (or 'sensor.x#attribute')
+	source: Rc<RefCell<NetworkUpdater>>,
 	cycle_id:u32,
-	value: T
+	value: T,
+	filters: Option<Rc<RefCell<FilterChain>>>, // Only for f32, shared by clones
 }
 /* impl<'a, 'b:'a, T:FeederOutType<T>+Clone> Clone for SourceFeeder<T> {
     fn clone(&self) -> SourceFeeder<'a, T> {
@@ -67,28 +196,33 @@ pub struct SourceFeeder<T:FeederOutType<T>+Clone> {
     }
 } */
 impl<'a, 'b:'a, T:FeederOutType<T>+Clone> SourceFeeder<T> {
-	pub fn new(updater:Rc<RefCell<HomeAssistantAPI>>, entity_id:&str) -> ResultOpenHems<SourceFeeder<T>> {
-		let nameid = ArrayString::from(entity_id)
-			.map_err(|message| OpenHemsError::new(
-				format!("Entity id '{entity_id}' is too long : {}", message.to_string())
-			))?;
+	pub fn new(updater:Rc<RefCell<NetworkUpdater>>, entity_id:&str) -> ResultOpenHems<SourceFeeder<T>> {
+		if entity_id.is_empty() {
+			return Err(OpenHemsError::new("Empty entity id.".to_string()));
+		}
 		{
 			let mut updater2 = updater.borrow_mut();
 			updater2.register_entity(entity_id);
 		}
 		Ok(SourceFeeder {
-			nameid: nameid,
+			nameid: entity_id.to_string(),
 			source: updater,
 			cycle_id: 0,
-			value: T::default()
+			value: T::default(),
+			filters: None,
 		})
 	}
-	pub fn get_nameid(&self) -> &ArrayString<64> {
+	pub fn get_nameid(&self) -> &str {
 		&self.nameid
 	}
-	pub fn switch(&self, nameid:&str, on:bool) -> ResultOpenHems<bool>{
+	/// Last time the source reported the value (MIN_DATETIME if never).
+	pub fn get_last_update(&self) -> DateTime<Local> {
+		self.source.borrow().get_entity_last_update(&self.nameid)
+			.unwrap_or(*time::MIN_DATETIME)
+	}
+	pub fn switch(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool>{
 		let updater = self.source.borrow();
-		updater.switch(nameid, on)
+		updater.call_actuator(actuator, on)
 	}
 }
 /* impl<'a, T:FeederOutType<T>+Clone> SourceFeeder<T> {
@@ -98,8 +232,10 @@ impl<'a, 'b:'a, T:FeederOutType<T>+Clone> SourceFeeder<T> {
 } */
 macro_rules! get_value (
     ($t:ident, $f:ident) => (
-#[must_use]
-pub fn get_value(&mut self) -> ResultOpenHems<$t> {
+get_value!(get_value, $t, $f);
+    );
+    ($name:ident, $t:ident, $f:ident) => (
+pub fn $name(&mut self) -> ResultOpenHems<$t> {
 	let source = self.source.borrow_mut();
 	let cur_id = source.get_cycle_id();
 	if self.cycle_id <= cur_id {
@@ -115,7 +251,18 @@ impl<'a> SourceFeeder<i32> {
 	get_value!(i32, get_entity_value_int);
 }
 impl<'a> SourceFeeder<f32> {
-	get_value!(f32, get_entity_value_float);
+	get_value!(get_raw_value, f32, get_entity_value_float);
+	/// Value smoothed by the filters, if any.
+	pub fn get_value(&mut self) -> ResultOpenHems<f32> {
+		let raw = self.get_raw_value()?;
+		Ok(match &self.filters {
+			Some(filters) => filters.borrow_mut().apply(self.cycle_id, raw),
+			None => raw
+		})
+	}
+	pub fn set_filters(&mut self, filters:FilterChain) {
+		self.filters = Some(Rc::new(RefCell::new(filters)));
+	}
 }
 impl<'a> SourceFeeder<String> {
 	pub fn get_value(&mut self) -> ResultOpenHems<String> {
@@ -147,20 +294,190 @@ impl<T:FeederOutType<T>+Clone> ConstFeeder<T> {
 	}
 }
 
-/* #[derive(Clone, Debug)]
-pub struct GuessIsOnFeeder<T:FeederOutType<T>+Clone> {
-	source: Feeder<Feeder<T>>
+/// String value among allowed ones (like Tempo colours), compared case-insensitively.
+#[derive(Clone, Debug)]
+pub struct EnumFeeder {
+	feeder: Feeder<String>,
+	values: &'static [&'static str], // Lowercase allowed values
 }
-impl<'a, 'b:'a, T:FeederOutType<T>+Clone> Clone for GuessIsOnFeeder<T> {
-    fn clone(&self) -> GuessIsOnFeeder<T> {
-        GuessIsOnFeeder {
-			source: self.source.clone()
+impl EnumFeeder {
+	/// Error if the feeder is a constant which is not an allowed value.
+	pub fn new(feeder:Feeder<String>, values:&'static [&'static str]) -> ResultOpenHems<EnumFeeder> {
+		let mut feeder = EnumFeeder {feeder, values};
+		if let Feeder::Const(_) = feeder.feeder {
+			feeder.get_value()?;
 		}
-    }
+		Ok(feeder)
+	}
+	/// Lowercase value, error if it is not an allowed one.
+	pub fn get_value(&mut self) -> ResultOpenHems<String> {
+		let value = self.feeder.get_value()?;
+		let lowercase = value.trim().to_lowercase();
+		if self.values.contains(&lowercase.as_str()) {
+			Ok(lowercase)
+		} else {
+			Err(OpenHemsError::new(format!("Invalid value '{value}', expect one of {:?}.", self.values)))
+		}
+	}
+}
+
+/// Value computed from an expression over entities (like "sensor.grid + sensor.solar - sensor.battery").
+#[derive(Clone, Debug)]
+pub struct ComputedFeeder {
+	expression: Rc<Expression>, // Shared by clones, to keep moving averages
+	entities: Vec<String>,
+	source: Rc<RefCell<NetworkUpdater>>,
+	value: Rc<RefCell<(Option<u32>, f32)>>, // Value of the cycle id
+	filters: Option<Rc<RefCell<FilterChain>>>,
 }
-impl Feeder for GuessIsOnFeeder<bool> {
-	type Item = bool;
-	fn get_value(&mut self) -> ResultOpenHems<bool> {
-		self.source.get_value()
+impl ComputedFeeder {
+	pub fn new(updater:Rc<RefCell<NetworkUpdater>>, expression:Expression) -> ResultOpenHems<ComputedFeeder> {
+		let entities = expression.get_entities();
+		{
+			let mut updater2 = updater.borrow_mut();
+			for entity_id in &entities {
+				if !updater2.has_entity(entity_id) {
+					return Err(OpenHemsError::new(format!("No entity '{entity_id}' found for expression.")));
+				}
+				updater2.register_entity(entity_id);
+			}
+		}
+		Ok(ComputedFeeder {
+			expression: Rc::new(expression),
+			entities,
+			source: updater,
+			value: Rc::new(RefCell::new((None, 0.0))),
+			filters: None,
+		})
 	}
-} */
+	/// Value smoothed by the filters, if any.
+	pub fn get_value(&self) -> ResultOpenHems<f32> {
+		let raw = self.get_raw_value()?;
+		Ok(match &self.filters {
+			Some(filters) => filters.borrow_mut().apply(self.source.borrow().get_cycle_id(), raw),
+			None => raw
+		})
+	}
+	pub fn set_filters(&mut self, filters:FilterChain) {
+		self.filters = Some(Rc::new(RefCell::new(filters)));
+	}
+	/// Computed once per network update.
+	pub fn get_raw_value(&self) -> ResultOpenHems<f32> {
+		let source = self.source.borrow();
+		let cur_id = source.get_cycle_id();
+		let mut value = self.value.borrow_mut();
+		if value.0!=Some(cur_id) {
+			value.1 = self.expression.evaluate(Local::now(), &|entity_id| source.get_entity_value_float(entity_id))?;
+			value.0 = Some(cur_id);
+		}
+		Ok(value.1)
+	}
+	/// Oldest report date of the entities.
+	pub fn get_last_update(&self) -> DateTime<Local> {
+		let source = self.source.borrow();
+		self.entities.iter()
+			.map(|entity_id| source.get_entity_last_update(entity_id).unwrap_or(*time::MIN_DATETIME))
+			.min()
+			.unwrap_or(*time::MIN_DATETIME)
+	}
+	pub fn switch(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		self.source.borrow().call_actuator(actuator, on)
+	}
+}
+
+/// On/off state guessed from a power : on above 'threshold' W.
+/// The state changes only when the power stays on the other side of the threshold for 'debounce' seconds
+/// (a constant or an entity, like an 'input_number').
+/// A command is sent once : a device on under the threshold (or during the debounce) is not switched again
+/// until the guessed state changes.
+#[derive(Clone, Debug)]
+pub struct GuessIsOnFeeder {
+	threshold: f32,
+	state: Rc<RefCell<GuessState>>,
+}
+#[derive(Debug)]
+struct GuessState {
+	power: Feeder<f32>,
+	debounce: Feeder<i32>,
+	on: Option<bool>,
+	since: Option<DateTime<Local>>, // When the power crossed the threshold
+	commanded: Option<bool>, // Last state sent to the actuator, until the guessed state changes
+}
+impl GuessIsOnFeeder {
+	pub fn new(power:Feeder<f32>, threshold:f32, debounce:Feeder<i32>) -> GuessIsOnFeeder {
+		GuessIsOnFeeder {
+			threshold,
+			state: Rc::new(RefCell::new(GuessState {power, debounce, on: None, since: None, commanded: None})),
+		}
+	}
+	pub fn get_value(&self) -> ResultOpenHems<bool> {
+		self.get_value_at(Local::now())
+	}
+	fn get_value_at(&self, now:DateTime<Local>) -> ResultOpenHems<bool> {
+		let mut state = self.state.borrow_mut();
+		let above = state.power.get_value()?>self.threshold;
+		match state.on {
+			Some(on) if on!=above => {
+				let since = *state.since.get_or_insert(now);
+				if (now-since).num_seconds()>=state.debounce.get_value()?.max(0) as i64 {
+					log::debug!("GuessIsOnFeeder() : on={above}");
+					state.on = Some(above);
+					state.since = None;
+					state.commanded = None;
+				}
+			}
+			Some(_) => {
+				state.since = None;
+			}
+			None => {
+				state.on = Some(above);
+			}
+		}
+		Ok(state.on.unwrap_or(above))
+	}
+	pub fn get_last_update(&self) -> DateTime<Local> {
+		self.state.borrow().power.get_last_update().unwrap_or(*time::MIN_DATETIME)
+	}
+	pub fn switch(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		let mut state = self.state.borrow_mut();
+		if state.commanded==Some(on) {
+			log::debug!("GuessIsOnFeeder() : already switched on={on}");
+			return Ok(true);
+		}
+		let result = state.power.switch(actuator, on)?;
+		if result {
+			state.commanded = Some(on);
+		}
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use yaml_rust2::YamlLoader;
+	use super::*;
+
+	#[test]
+	fn test_from_conf() {
+		let updater = Rc::new(RefCell::new(NetworkUpdater::default()));
+		let load = |conf:&str| YamlLoader::load_from_str(conf).unwrap().remove(0);
+		let mut feeder = Feeder::<f32>::from_conf(&updater, Some(&load("1500")), None).unwrap();
+		assert_eq!(feeder.get_value().unwrap(), 1500.0);
+		let mut feeder = Feeder::<f32>::from_conf(&updater, Some(&load("{entity: sensor.unknown, default: 20}")), None).unwrap();
+		assert_eq!(feeder.get_value().unwrap(), 20.0);
+		let feeder = Feeder::<bool>::from_conf(&updater, None, Some(true)).unwrap();
+		assert!(feeder.get_value().unwrap());
+		assert!(Feeder::<f32>::from_conf(&updater, Some(&load("sensor.unknown")), None).is_err());
+		let mut feeder = Feeder::<i32>::from_conf(&updater, Some(&load("16")), None).unwrap();
+		assert_eq!(feeder.get_value().unwrap(), 16);
+		let mut feeder = Feeder::<i32>::from_conf(&updater, Some(&load("{entity: sensor.unknown, default: 6}")), None).unwrap();
+		assert_eq!(feeder.get_value().unwrap(), 6);
+		// Enum
+		let colors = &["bleu", "blanc", "rouge"];
+		let feeder = Feeder::<String>::from_conf(&updater, Some(&load("{entity: sensor.unknown, default: Rouge}")), None).unwrap();
+		assert_eq!(EnumFeeder::new(feeder, colors).unwrap().get_value().unwrap(), "rouge");
+		let feeder = Feeder::<String>::from_conf(&updater, Some(&load("{entity: sensor.unknown, default: violet}")), None).unwrap();
+		assert!(EnumFeeder::new(feeder, colors).is_err());
+		assert!(Feeder::<String>::from_conf(&updater, Some(&load("[HC, HP]")), None).is_err());
+	}
+}
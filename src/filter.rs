@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use chrono::{DateTime, Local};
+use yaml_rust2::Yaml;
+use crate::cast_utility;
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+/// Smoothing of a feeder value, so strategies don't react to spikes (like a kettle).
+#[derive(Clone, Debug)]
+pub enum Filter {
+	Ema {alpha: f32, last: Option<f32>}, // Exponential moving average
+	Median {size: usize, window: VecDeque<f32>}, // Median of the last 'size' values
+	RateLimit {rate: f32, last: Option<(DateTime<Local>, f32)>}, // Max change per second
+}
+impl Filter {
+	/// Filter like {class: ema, alpha: 0.3}, {class: median, size: 5} or {class: ratelimit, rate: 100}.
+	pub fn from_conf(conf:&Yaml) -> ResultOpenHems<Filter> {
+		let conf = cast_utility::to_type_dict(conf);
+		let get = |key:&str| conf.get(key).map(|v| cast_utility::to_type_float(v));
+		let classname = conf.get("class").map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+		match classname.to_lowercase().as_str() {
+			"ema" => match get("alpha") {
+				Some(alpha) if alpha>0.0 && alpha<=1.0 => Ok(Filter::Ema {alpha, last: None}),
+				_ => Err(OpenHemsError::new("Filter ema needs an 'alpha' in ]0, 1].".to_string()))
+			}
+			"median" => match get("size") {
+				Some(size) if size>=1.0 => Ok(Filter::Median {size: size as usize, window: VecDeque::new()}),
+				_ => Err(OpenHemsError::new("Filter median needs a 'size' of at least 1.".to_string()))
+			}
+			"ratelimit" => match get("rate") {
+				Some(rate) if rate>0.0 => Ok(Filter::RateLimit {rate, last: None}),
+				_ => Err(OpenHemsError::new("Filter ratelimit needs a positive 'rate' (per second).".to_string()))
+			}
+			_ => Err(OpenHemsError::new(format!("Unknown filter class '{classname}', use ema, median or ratelimit.")))
+		}
+	}
+	pub fn apply(&mut self, now:DateTime<Local>, value:f32) -> f32 {
+		match self {
+			Filter::Ema {alpha, last} => {
+				let filtered = match last {
+					Some(last) => *alpha*value + (1.0-*alpha)*(*last),
+					None => value
+				};
+				*last = Some(filtered);
+				filtered
+			}
+			Filter::Median {size, window} => {
+				window.push_back(value);
+				while window.len()>*size {
+					window.pop_front();
+				}
+				let mut sorted:Vec<f32> = window.iter().copied().collect();
+				sorted.sort_by(|a, b| a.total_cmp(b));
+				let middle = sorted.len()/2;
+				if sorted.len().is_multiple_of(2) {(sorted[middle-1]+sorted[middle])/2.0} else {sorted[middle]}
+			}
+			Filter::RateLimit {rate, last} => {
+				let filtered = match last {
+					Some((date, last)) => {
+						let max_change = *rate*((now-*date).num_milliseconds() as f32/1000.0);
+						value.clamp(*last-max_change, *last+max_change)
+					}
+					None => value
+				};
+				*last = Some((now, filtered));
+				filtered
+			}
+		}
+	}
+}
+
+/// Filters applied in turn on each new value (once per network update).
+#[derive(Clone, Debug)]
+pub struct FilterChain {
+	filters: Vec<Filter>,
+	cycle_id: Option<u32>,
+	raw: f32,
+	value: f32,
+}
+impl FilterChain {
+	pub fn from_conf(conf:&Yaml) -> ResultOpenHems<FilterChain> {
+		let filters = match conf {
+			Yaml::Array(list) => list.iter().map(Filter::from_conf).collect::<ResultOpenHems<_>>()?,
+			conf => vec![Filter::from_conf(conf)?]
+		};
+		Ok(FilterChain {filters, cycle_id: None, raw: 0.0, value: 0.0})
+	}
+	/// Filtered value of 'raw', read at network update 'cycle_id'.
+	pub fn apply(&mut self, cycle_id:u32, raw:f32) -> f32 {
+		if self.cycle_id!=Some(cycle_id) {
+			let now = Local::now();
+			self.raw = raw;
+			self.value = self.filters.iter_mut().fold(raw, |value, filter| filter.apply(now, value));
+			self.cycle_id = Some(cycle_id);
+		}
+		self.value
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Duration;
+	use super::*;
+
+	#[test]
+	fn test_filters() {
+		let load = |conf:&str| FilterChain::from_conf(&yaml_rust2::YamlLoader::load_from_str(conf).unwrap()[0]);
+		let mut median = load("{class: median, size: 3}").unwrap();
+		let values:Vec<f32> = [100.0, 2000.0, 110.0, 120.0].iter().enumerate()
+			.map(|(i, v)| median.apply(i as u32, *v)).collect();
+		assert_eq!(values, vec![100.0, 1050.0, 110.0, 120.0]);
+		assert_eq!(median.apply(3, 5000.0), 120.0); // Same update
+		let mut ema = load("[{class: ema, alpha: 0.5}]").unwrap();
+		assert_eq!(ema.apply(0, 100.0), 100.0);
+		assert_eq!(ema.apply(1, 200.0), 150.0);
+		let mut ratelimit = Filter::from_conf(&yaml_rust2::YamlLoader::load_from_str("{class: ratelimit, rate: 10}").unwrap()[0]).unwrap();
+		let now = Local::now();
+		assert_eq!(ratelimit.apply(now, 100.0), 100.0);
+		assert_eq!(ratelimit.apply(now+Duration::seconds(5), 2000.0), 150.0);
+		assert_eq!(ratelimit.apply(now+Duration::seconds(6), 0.0), 140.0);
+		assert!(load("{class: ema, alpha: 2}").is_err());
+		assert!(load("{class: kalman}").is_err());
+	}
+}
@@ -1,14 +1,18 @@
-use std::{cell::{RefCell, RefMut}, collections::HashMap, rc::Rc};
-use reqwest;
+use std::{cell::RefCell, collections::HashMap, rc::Rc, thread::sleep, time::Duration};
+use chrono::{DateTime, Local};
+use reqwest::{self, blocking::Client, StatusCode};
 use json::{self, JsonValue, object::Object};
 use yaml_rust2::Yaml;
 use core::fmt;
 use std::io::Read;
+use std::sync::mpsc::Receiver;
 use serde_json::json;
 use crate::{
 	cast_utility, configuration_manager::ConfigurationManager,
 	error::{OpenHemsError, ResultOpenHems},
-	feeder::{ConstFeeder, Feeder, SourceFeeder},
+	expression::Expression, filter::FilterChain, feeder::{ComputedFeeder, ConstFeeder, Feeder, FeederOutType, GuessIsOnFeeder},
+	home_assistant_ws::HomeAssistantWebSocket,
+	network_updater::{NetworkUpdater, PublishedState},
 	network::Network, node::{self, Node, NodeBase, Switch}
 };
 
@@ -35,6 +39,8 @@ pub trait HomeStateUpdater:Clone
 	// fn get_battery(&self,nameid:&str, nodeConf:HashMap<String, Yaml>);
     // fn get_network(&self) -> Network;
     fn get_cycle_id(&self) -> u32;
+	/// Last time the entity was reported by the source, None if never.
+	fn get_entity_last_update(&self, entity_id:&str) -> Option<DateTime<Local>>;
 }
 
 #[derive(Clone)]
@@ -43,7 +49,26 @@ pub struct HomeAssistantAPI {
     url: String,
     cached_ids: HashMap<String, JsonValue>,
 	ha_elements: HashMap<String, JsonValue>,
-	cycle_id:u32
+	friendly_names: HashMap<String, String>,
+	cycle_id:u32,
+	websocket: Option<HomeAssistantWebSocket>,
+	client: Client,
+	retries: u32,
+	last_update: Option<DateTime<Local>>,
+	stale_since: Option<DateTime<Local>>,
+	publish: bool,
+	published: HashMap<String, (String, DateTime<Local>)>, // Last state posted by entity id
+}
+
+const CONNECT_TIMEOUT:Duration = Duration::from_secs(5);
+const RETRY_DELAY:Duration = Duration::from_millis(500); // Doubled on each retry
+// Unchanged states are posted again after this delay (Home-Assistant forget them on restart).
+const REPUBLISH_DELAY:chrono::TimeDelta = chrono::TimeDelta::minutes(10);
+
+// Failure of a call to Home-Assistant, Transient ones are worth a retry.
+enum ApiError {
+	Transient(String),
+	Permanent(String),
 }
 impl<'a, 'b:'a, 'c:'b> fmt::Display for HomeAssistantAPI {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -62,51 +87,122 @@ impl<'a> HomeAssistantAPI {
     pub fn new(configurator:&ConfigurationManager) -> ResultOpenHems<HomeAssistantAPI> {
 		let url: String = configurator.get_as_str("api.url");
 		let token = configurator.get_as_str("api.long_lived_token");
+		let websocket = if configurator.get_as_bool("api.websocket") {
+			let mut websocket = HomeAssistantWebSocket::new(&HomeAssistantWebSocket::get_url(&url), &token);
+			let ca_path = configurator.get_as_str("api.ssl_certificate");
+			if !ca_path.is_empty() {
+				websocket.set_certificate(&ca_path)?;
+			}
+			Some(websocket)
+		} else {
+			None
+		};
 		let mut updater = HomeAssistantAPI {
 			token: token,
 			url: url,
 			cached_ids: HashMap::new(),
 			ha_elements: HashMap::new(),
-			cycle_id: 0
+			friendly_names: HashMap::new(),
+			cycle_id: 0,
+			websocket,
+			client: Self::get_client(configurator)?,
+			retries: configurator.get_as_int("api.retries").max(0) as u32,
+			last_update: None,
+			stale_since: None,
+			publish: configurator.get_as_bool("api.publish"),
+			published: HashMap::new(),
 		};
 		updater.init_network()?;
 		Ok(updater)
 	}
-	// TODO : post with data + Status...
+	/// Shared HTTP client with timeouts and optional custom CA ('api.ssl_certificate').
+	fn get_client(configurator:&ConfigurationManager) -> ResultOpenHems<Client> {
+		let timeout = configurator.get_as_int("api.timeout").max(1) as u64;
+		let mut builder = Client::builder()
+			.connect_timeout(CONNECT_TIMEOUT)
+			.timeout(Duration::from_secs(timeout));
+		let ca_path = configurator.get_as_str("api.ssl_certificate");
+		if !ca_path.is_empty() {
+			let pem = std::fs::read(&ca_path)
+				.map_err(|err| OpenHemsError::new(format!("Fail read api.ssl_certificate '{ca_path}' : {err}")))?;
+			let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+				.map_err(|err| OpenHemsError::new(format!("Invalid certificate in '{ca_path}' : {err}")))?;
+			builder = builder.tls_certs_merge(certificates);
+		}
+		builder.build()
+			.map_err(|err| OpenHemsError::new(format!("Fail build HTTP client : {err}")))
+	}
+	/// True when last update failed, so values are from a previous update.
+	pub fn is_stale(&self) -> bool {
+		self.stale_since.is_some()
+	}
+	/// Call Home-Assistant REST API, retrying with backoff on network or server (5xx) errors.
+	/// Service calls (with data) are retried only if the connection failed (so never sent).
 	pub fn call_api(&self, url: &str, data: Option<serde_json::Value>) -> Result<JsonValue, OpenHemsError> {
-		// let client = reqwest::Client::new();
-		let client = reqwest::blocking::Client::new();
-		let mut bearer = String::from("Bearer ");
-		bearer.push_str(&self.token);
-		let mut complete_url: String = String::from(&self.url);
-		complete_url.push_str(url);
+		let mut delay = RETRY_DELAY;
+		let mut attempt = 0;
+		loop {
+			match self.try_call_api(url, &data) {
+				Ok(value) => {
+					return Ok(value);
+				}
+				Err(ApiError::Transient(message)) if attempt<self.retries => {
+					attempt += 1;
+					log::warn!("{message} (retry {attempt}/{} in {:?})", self.retries, delay);
+					sleep(delay);
+					delay *= 2;
+				}
+				Err(ApiError::Transient(message)) | Err(ApiError::Permanent(message)) => {
+					return Err(OpenHemsError::new(message));
+				}
+			}
+		}
+	}
+	fn try_call_api(&self, url: &str, data: &Option<serde_json::Value>) -> Result<JsonValue, ApiError> {
+		let complete_url = format!("{}{url}", self.url);
 		log::info!("Call Home-Assistant API : {complete_url}");
-		let request_builder;
-		if let Some(mydata) = data {
-			// let mut body = reqwest::blocking::Body::new(mydata);
-			let request_body_string = serde_json::to_string(&mydata).unwrap();
+		let request_builder = if let Some(mydata) = data {
+			let request_body_string = mydata.to_string();
 			log::info!("   with data = {}", request_body_string);
-			request_builder = client.post(&complete_url)
-				.body(request_body_string);
+			self.client.post(&complete_url)
+				.body(request_body_string)
 		} else {
-			request_builder = client.get(&complete_url);
-		}
+			self.client.get(&complete_url)
+		};
 		let mut res = request_builder
-			.header("Authorization", bearer)
+			.header("Authorization", format!("Bearer {}", self.token))
 			.header("content-type", "application/json")
 			.send()
-			.map_err(|message| OpenHemsError::new(
-				format!("Fail call Home-Assistant API for url '{url}' : {}",message.to_string())
-			))?;
+			.map_err(|err| {
+				let message = format!("Fail call Home-Assistant API for url '{url}' : {err}");
+				if err.is_connect() || (data.is_none() && err.is_timeout()) {
+					ApiError::Transient(message)
+				} else {
+					ApiError::Permanent(message)
+				}
+			})?;
+		let status = res.status();
 		let mut body = String::new();
 		let _ = res.read_to_string(&mut body);
-		// println!("Status: {}", a.status());
-		// println!("Headers:\n{:#?}", a.headers());
-		// println!("Body:\n{}", body);
+		match status {
+			StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+				return Err(ApiError::Permanent(format!(
+					"Home-Assistant refused the token (HTTP {status}) for '{url}' : check api.long_lived_token."
+				)));
+			}
+			s if s.is_server_error() => {
+				let message = format!("Home-Assistant error (HTTP {status}) for '{url}' : {body}");
+				return Err(if data.is_none() {ApiError::Transient(message)} else {ApiError::Permanent(message)});
+			}
+			s if !s.is_success() => {
+				return Err(ApiError::Permanent(format!("Home-Assistant error (HTTP {status}) for '{url}' : {body}")));
+			}
+			_ => {}
+		}
 		log::debug!("Call Home-Assistant API : {complete_url} : Ok");
 		json::parse(&body)
-			.map_err(|message| OpenHemsError::new(
-				format!("Call Home-Assistant API for {url} : Fail parse '{body}' : {}", message.to_string())
+			.map_err(|message| ApiError::Permanent(
+				format!("Call Home-Assistant API for {url} : Fail parse '{body}' : {message}")
 			))
 	}
 	pub fn get_feeder_const_int(node_conf:&HashMap<String, &Yaml>, key:&str, default_value:i32) -> i32 {
@@ -130,105 +226,379 @@ impl<'a> HomeAssistantAPI {
 			default_value
 		}
 	}
-	pub fn get_feeder_float(updater:Rc<RefCell<HomeAssistantAPI>>, node_conf:&HashMap<String, &Yaml>, key:&str, default_value:f32) -> ResultOpenHems<SourceFeeder<f32>> {
-		if let Some(val) = node_conf.get(key) {
-			if let Yaml::String(entity_id) = val {
-				let updater2 = updater.borrow_mut();
-				if updater2.ha_elements.contains_key(entity_id) {
-					drop(updater2);
-					SourceFeeder::new(Rc::clone(&updater), entity_id)
-				} else {
-					Err(OpenHemsError::new(format!("No  key '{key}'")))
-				}
-			} else {
-				Err(OpenHemsError::new(format!("No  key '{key}'")))
+	/// Feeder of an entity, a constant, or computed from an expression over entities (like "sensor.grid - sensor.solar").
+	/// With {entity: sensor.x, default: 0, filters: [...]}, the default is used if entities are missing and values are smoothed.
+	pub fn get_feeder_float(updater:Rc<RefCell<NetworkUpdater>>, node_conf:&HashMap<String, &Yaml>, key:&str, default_value:Option<f32>) -> ResultOpenHems<Feeder<f32>> {
+		let conf = node_conf.get(key).copied();
+		let (expression, default_value, filters) = match conf {
+			Some(Yaml::String(expression)) => (Some(expression.clone()), default_value, None),
+			Some(feeder_conf @ Yaml::Hash(_)) => {
+				let feeder_conf = cast_utility::to_type_dict(feeder_conf);
+				let default_value = feeder_conf.get("default").and_then(|v| f32::from_yaml(v)).or(default_value);
+				(feeder_conf.get("entity").map(|v| cast_utility::to_type_str(v)), default_value, feeder_conf.get("filters").copied())
 			}
-		} else {
-			Err(OpenHemsError::new(format!("No  key '{key}'")))
-		}
-	}
-	pub fn get_feeder_bool(updater:Rc<RefCell<HomeAssistantAPI>>, node_conf:&HashMap<String, &Yaml>, key:&str, default_value:bool) -> ResultOpenHems<SourceFeeder<bool>> {
-		if let Some(val) = node_conf.get(key) {
-			if let Yaml::String(entity_id) = val {
-				let updater2 = updater.borrow_mut();
-				if updater2.ha_elements.contains_key(entity_id) {
-					drop(updater2);
-					// <HomeAssistantAPI, f32>
-					SourceFeeder::new(Rc::clone(&updater), entity_id)
-				} else {
-					Err(OpenHemsError::new(format!("No  key '{key}'")))
+			_ => (None, default_value, None)
+		};
+		let expression = match &expression {
+			Some(expression) if !expression.is_empty() => Some(Expression::parse(expression)?),
+			_ => None
+		};
+		let mut feeder = match expression {
+			Some(expression) if expression.get_entities().is_empty() => {
+				Feeder::Const(ConstFeeder::new(expression.evaluate(Local::now(), &|_| Ok(0.0))?))
+			}
+			Some(Expression::Entity(_)) | None => Feeder::from_conf(&updater, conf, default_value)
+				.map_err(|err| OpenHemsError::new(format!("Invalid '{key}' : {}", err.message)))?,
+			Some(expression) => match (ComputedFeeder::new(Rc::clone(&updater), expression), default_value) {
+				(Ok(feeder), _) => Feeder::Computed(feeder),
+				(Err(err), Some(value)) => {
+					log::warn!("{}, use '{key}' default value.", err.message);
+					Feeder::Const(ConstFeeder::new(value))
+				}
+				(Err(err), None) => {
+					return Err(err);
 				}
-			} else {
-				Err(OpenHemsError::new(format!("No  key '{key}'")))
 			}
-		} else {
-			Err(OpenHemsError::new(format!("No  key '{key}'")))
+		};
+		if let Some(filters) = filters {
+			feeder.set_filters(FilterChain::from_conf(filters)?);
 		}
+		Ok(feeder)
+	}
+	pub fn get_feeder_int(updater:Rc<RefCell<NetworkUpdater>>, node_conf:&HashMap<String, &Yaml>, key:&str, default_value:Option<i32>) -> ResultOpenHems<Feeder<i32>> {
+		Feeder::from_conf(&updater, node_conf.get(key).copied(), default_value)
+			.map_err(|err| OpenHemsError::new(format!("Invalid '{key}' : {}", err.message)))
+	}
+	pub fn get_feeder_bool(updater:Rc<RefCell<NetworkUpdater>>, node_conf:&HashMap<String, &Yaml>, key:&str, default_value:Option<bool>) -> ResultOpenHems<Feeder<bool>> {
+		Feeder::from_conf(&updater, node_conf.get(key).copied(), default_value)
+			.map_err(|err| OpenHemsError::new(format!("Invalid '{key}' : {}", err.message)))
 	}
-	pub fn get_nodebase(updater:Rc<RefCell<HomeAssistantAPI>>, nameid:&str, node_conf:&HashMap<String, &Yaml>) -> ResultOpenHems<NodeBase> {
+	pub fn get_nodebase(updater:Rc<RefCell<NetworkUpdater>>, nameid:&str, node_conf:&HashMap<String, &Yaml>) -> ResultOpenHems<NodeBase> {
 		let max_power = HomeAssistantAPI::get_feeder_const_float(node_conf, "maxPower", 0.0);
 		let min_power = HomeAssistantAPI::get_feeder_const_float(node_conf, "minPower", 0.0);
-		let current_power = HomeAssistantAPI::get_feeder_float(Rc::clone(&updater), node_conf, "currentPower", 0.0)?;
-		let is_on:Feeder<bool>;
-		if let Ok(source_feeder) = HomeAssistantAPI::get_feeder_bool(Rc::clone(&updater), node_conf, "isOn", false) {
-			is_on = Feeder::Source(source_feeder);
-		} else {
-			is_on = Feeder::Const(ConstFeeder::new(true));
-		}
-		let node = node::get_nodebase(nameid, max_power, min_power, current_power, is_on)?;
+		let current_power = HomeAssistantAPI::get_feeder_float(Rc::clone(&updater), node_conf, "currentPower", None)?;
+		// Without on/off entity, guess it from the power drawn. A configured one must exist (typo...).
+		let is_on = match node_conf.get("isOn") {
+			None | Some(Yaml::Null) => None,
+			Some(Yaml::String(entity_id)) if entity_id.is_empty() => None,
+			Some(_) => Some(HomeAssistantAPI::get_feeder_bool(Rc::clone(&updater), node_conf, "isOn", None)?),
+		};
+		let is_on = match is_on {
+			Some(feeder) => feeder,
+			None => {
+				let threshold = HomeAssistantAPI::get_feeder_const_float(node_conf, "isOnPower", 10.0);
+				let debounce = HomeAssistantAPI::get_feeder_int(Rc::clone(&updater), node_conf, "isOnDelay", Some(60))?;
+				Feeder::Guess(GuessIsOnFeeder::new(current_power.clone(), threshold, debounce))
+			}
+		};
+		// Display name : configured, else the friendly name of its on/off entity in Home-Assistant.
+		let name = match (HomeAssistantAPI::get_feeder_const_str(node_conf, "name", ""), &is_on) {
+			(name, _) if !name.is_empty() => Some(name),
+			(_, Feeder::Source(feeder)) => updater.borrow().get_friendly_name(feeder.get_nameid()),
+			_ => None
+		};
+		let mut node = node::get_nodebase(nameid, max_power, min_power, current_power, is_on)?;
+		node.set_name(&name.unwrap_or_default());
 		Ok(node)
 	}
-	pub fn get_entity_value(&self, entity_id:&str) -> ResultOpenHems<&JsonValue> {
-		if self.cached_ids.contains_key(entity_id) {
-			Ok(self.cached_ids.get(entity_id).unwrap())
-		} else {
-			/* let mut url = String::from("/states/");
-			url.push_str(entity_id);
-			let value = self.call_api(&url, None)?;
-			self.cached_ids.insert(entity_id.to_string(), value);
-			if let Some(value) = self.cached_ids.get(entity_id) {
-				Ok(value)
-			} else { // Should be impossible
-				Ok(&JsonValue::Null)
-			} */
-			 Err(OpenHemsError::new(format!("No entity '{entity_id}' found.")))
+	pub fn has_entity(&self, nameid:&str) -> bool {
+		self.ha_elements.contains_key(Self::split_entity_id(nameid).0)
+	}
+	/// Home-Assistant 'friendly_name' of an entity.
+	pub fn get_friendly_name(&self, nameid:&str) -> Option<&str> {
+		self.friendly_names.get(Self::split_entity_id(nameid).0).map(|name| name.as_str())
+	}
+	/// Split 'sensor.x#attribute' in entity id and optional attribute name.
+	pub fn split_entity_id(nameid:&str) -> (&str, Option<&str>) {
+		match nameid.split_once('#') {
+			Some((entity_id, attribute)) => (entity_id, Some(attribute)),
+			None => (nameid, None)
 		}
 	}
+	/// State (or attribute with 'sensor.x#attribute') of an entity, 'unavailable' and 'unknown' are errors.
+	pub fn get_entity_value(&self, nameid:&str) -> ResultOpenHems<&JsonValue> {
+		let (entity_id, attribute) = Self::split_entity_id(nameid);
+		let Some(entity) = self.cached_ids.get(entity_id) else {
+			return Err(OpenHemsError::new(format!("No entity '{entity_id}' found.")));
+		};
+		if entity.is_null() {
+			return Err(OpenHemsError::new(format!("No value received yet for '{entity_id}'.")));
+		}
+		if let Some(attribute) = attribute {
+			let value = &entity["attributes"][attribute];
+			if value.is_null() {
+				return Err(OpenHemsError::new(format!("No attribute '{attribute}' for '{entity_id}'.")));
+			}
+			return Ok(value);
+		}
+		let state = &entity["state"];
+		match state.as_str() {
+			Some("unavailable") | Some("unknown") | None => {
+				Err(OpenHemsError::new(format!("Entity '{entity_id}' is {state}.")))
+			}
+			Some(_) => Ok(state)
+		}
+	}
+	/// Numeric value, converted to W/Wh according to 'unit_of_measurement' (kW, kVA, kWh, MWh...).
+	fn get_entity_number(&self, nameid:&str) -> ResultOpenHems<f64> {
+		let v = self.get_entity_value(nameid)?;
+		let value = match v.as_str() {
+			Some(s) => s.trim().parse::<f64>().ok(),
+			None => v.as_f64()
+		};
+		let Some(value) = value else {
+			return Err(OpenHemsError::new(format!("Value of '{nameid}' can not be parsed as number : {v}")));
+		};
+		let (entity_id, attribute) = Self::split_entity_id(nameid);
+		if attribute.is_some() {
+			return Ok(value);
+		}
+		let unit = self.cached_ids[entity_id]["attributes"]["unit_of_measurement"].as_str().unwrap_or("");
+		Ok(value*get_unit_factor(unit))
+	}
     pub fn init(&mut self, url: String, token: String) -> ResultOpenHems<bool> {
 		self.url = url;
 		self.token = token;
 		self.init_network()
 	}
-	pub fn switch(&self, entity_id:&str, on:bool) -> ResultOpenHems<bool> {
+	/// Call a notify service, like 'notify.mobile_app_phone'.
+	/// Fail at once when Home-Assistant is unreachable (stale), other notification channels are still used.
+	pub fn notify_service(&self, service:&str, title:&str, message:&str) -> ResultOpenHems<bool> {
+		if self.is_stale() {
+			return Err(OpenHemsError::new(format!("Home-Assistant is unreachable, '{service}' not called.")));
+		}
 		let data = json!({
-			"entity_id": entity_id
+			"message": message,
+			"title": title
 		});
-		let expect = if on {"on"} else {"off"};
-		log::info!("Switching '{entity_id}' to {expect}.");
-		let url = format!("/services/switch/turn_{}", expect);
-		if let JsonValue::Array(response) = self.call_api(&url, Some(data))? {
-			if let Some(states) = response.get(0) {
-				println!("States : {:?}", states);
+		self.call_api(
+			&format!("/services/{}", service.replacen('.', "/", 1)), Some(data)
+		)?;
+		Ok(true)
+	}
+	/// Create/update OpenHEMS entities (like 'sensor.openhems_car_schedule') with POST /api/states.
+	/// Only changed states are posted, and none while Home-Assistant is unreachable (stale).
+	pub fn publish_states(&mut self, states:&[PublishedState]) -> ResultOpenHems<()> {
+		if !self.publish || self.is_stale() {
+			return Ok(());
+		}
+		let now = Local::now();
+		for state in states {
+			// A select can't be controlled with the REST API, it's published as a sensor.
+			let domain = if state.domain=="select" {"sensor"} else {state.domain};
+			let entity_id = format!("{domain}.{}", state.object_id);
+			if let Some((value, date)) = self.published.get(&entity_id) {
+				if *value==state.value && now-*date<REPUBLISH_DELAY {
+					continue;
+				}
+			}
+			let mut attributes = json!({"friendly_name": state.name, "icon": "mdi:home-lightning-bolt"});
+			if !state.unit.is_empty() {
+				attributes["unit_of_measurement"] = json!(state.unit);
+			}
+			if !state.options.is_empty() {
+				attributes["device_class"] = json!("enum");
+				attributes["options"] = json!(state.options);
+			}
+			// One rejected entity must not stop the others.
+			match self.call_api(&format!("/states/{entity_id}"), Some(json!({"state": state.value, "attributes": attributes}))) {
+				Ok(_) => {
+					self.published.insert(entity_id, (state.value.clone(), now));
+				}
+				Err(err) => log::error!("Fail publish '{entity_id}' : {}", err.message),
 			}
 		}
-		Ok(true)
+		Ok(())
+	}
+	/// Start listening Home-Assistant events (if 'api.websocket' is set).
+	/// The receiver get the id of each changed registered entity.
+	pub fn start_websocket(&self) -> Option<Receiver<String>> {
+		self.websocket.as_ref().map(|ws| ws.start())
+	}
+	fn set_updated(&mut self) {
+		if let Some(stale_since) = self.stale_since.take() {
+			log::info!("Home-Assistant is back (data were stale since {stale_since}).");
+		}
+		self.last_update = Some(Local::now());
+	}
+	// Update cached states from websocket, return false if not connected (REST fallback).
+	// Entities Home-Assistant did not send are unknown (the websocket log them once).
+	fn update_from_websocket(&mut self) -> bool {
+		let Some(ws) = self.websocket.as_ref() else {
+			return false;
+		};
+		if !ws.is_connected() {
+			return false;
+		}
+		let mut states = ws.get_states();
+		for (entity_id, entity) in self.cached_ids.iter_mut() {
+			match states.remove(entity_id) {
+				Some(state) => {
+					log::debug!("HomeAssistantAPI::update_from_websocket() : '{entity_id}' := {}", state["state"]);
+					*entity = state;
+				}
+				None => *entity = JsonValue::Null,
+			}
+		}
+		true
+	}
+	// Keep state with attributes.
+	fn set_entity(&mut self, entity_id:&str, entity:&JsonValue) {
+		self.cached_ids.insert(String::from(entity_id), entity.clone());
+	}
+	/// Call the actuator service and check the new state returned by Home-Assistant.
+	/// Return false if Home-Assistant did not return the new state (not yet changed).
+	pub fn call_actuator(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		let (url, data) = actuator.get_call(on);
+		log::info!("Switching '{}' to {} : {url}", actuator.get_entity_id(), if on {"on"} else {"off"});
+		let response = self.call_api(&url, Some(data))?;
+		let Some(state) = response.members().find(|state| state["entity_id"]==actuator.get_entity_id()) else {
+			log::warn!("Switching '{}' : no new state returned by Home-Assistant.", actuator.get_entity_id());
+			return Ok(false);
+		};
+		if actuator.check_state(state, on) {
+			Ok(true)
+		} else {
+			Err(OpenHemsError::new(format!(
+				"Fail switch '{}' : unexpected new state {} (attributes {}).",
+				actuator.get_entity_id(), state["state"], state["attributes"]
+			)))
+		}
 	}
 }
 
-macro_rules! get_entity_value_ (
-    ($fname:ident, $t:ident, $f:ident) => (
-#[must_use]
-fn $fname(&self, entity_id:&str) -> ResultOpenHems<$t> {
-	let v= self.get_entity_value(entity_id)?;
-	if let Some(value)  = v.$f() {
-		Ok(value)
-	} else {
-		let message = format!("Value can not be parsed as $name.");
-		Err(OpenHemsError::new(message))
+/// Home-Assistant service called to switch on/off a node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ActuatorService {
+	TurnOnOff, // <domain>.turn_on / <domain>.turn_off
+	SetValue(f32, f32), // <domain>.set_value with 'on' or 'off' value
+	SetTemperature(f32, f32), // climate.set_temperature
+	SetHvacMode(String, String), // climate.set_hvac_mode
+}
+#[derive(Clone, Debug)]
+pub struct Actuator {
+	entity_id: String,
+	domain: String,
+	service: ActuatorService,
+}
+impl Actuator {
+	/// From the optional 'actuator' node configuration: {service, entity, on, off}.
+	/// Without service, it's derived from the entity domain (default entity is 'isOn').
+	pub fn new(default_entity:&str, conf:Option<&Yaml>) -> ResultOpenHems<Actuator> {
+		let conf = match conf {
+			Some(conf @ Yaml::Hash(_)) => cast_utility::to_type_dict(conf),
+			Some(Yaml::Null) | None => HashMap::new(),
+			Some(conf) => {
+				return Err(OpenHemsError::new(format!("Actuator must be a dictionary, not {conf:?}.")));
+			}
+		};
+		let get = |key:&str| conf.get(key).map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+		let mut entity_id = get("entity");
+		if entity_id.is_empty() {
+			entity_id = default_entity.to_string();
+		}
+		let Some((entity_domain, _)) = entity_id.split_once('.') else {
+			return Err(OpenHemsError::new(format!("Invalid actuator entity '{entity_id}'.")));
+		};
+		let mut service = get("service");
+		if service.is_empty() {
+			service = match entity_domain {
+				"number" | "input_number" => format!("{entity_domain}.set_value"),
+				_ => format!("{entity_domain}.turn_on"),
+			};
+		}
+		let Some((domain, name)) = service.split_once('.') else {
+			return Err(OpenHemsError::new(format!("Invalid actuator service '{service}', expect '<domain>.<service>'.")));
+		};
+		let get_float = |key:&str| -> ResultOpenHems<f32> {
+			match conf.get(key) {
+				Some(v) => cast_utility::try_to_type_float(v),
+				None => Err(OpenHemsError::new(format!("Actuator service '{service}' need '{key}' value.")))
+			}
+		};
+		let get_str = |key:&str| -> ResultOpenHems<String> {
+			match conf.get(key) {
+				Some(v) => Ok(cast_utility::to_type_str(v)),
+				None => Err(OpenHemsError::new(format!("Actuator service '{service}' need '{key}' value.")))
+			}
+		};
+		let service = match name {
+			"turn_on" | "turn_off" => ActuatorService::TurnOnOff,
+			"set_value" => ActuatorService::SetValue(get_float("on")?, get_float("off")?),
+			"set_temperature" => ActuatorService::SetTemperature(get_float("on")?, get_float("off")?),
+			"set_hvac_mode" => ActuatorService::SetHvacMode(get_str("on")?, get_str("off")?),
+			_ => {
+				return Err(OpenHemsError::new(format!(
+					"Unsupported actuator service '{service}', use turn_on, set_value, set_temperature or set_hvac_mode."
+				)));
+			}
+		};
+		Ok(Actuator {
+			domain: domain.to_string(),
+			entity_id,
+			service,
+		})
+	}
+	pub fn get_entity_id(&self) -> &str {
+		&self.entity_id
+	}
+	pub fn get_service(&self) -> &ActuatorService {
+		&self.service
+	}
+	/// Service URL and data to call to switch on/off.
+	pub fn get_call(&self, on:bool) -> (String, serde_json::Value) {
+		let entity_id = self.entity_id.as_str();
+		let (service, data) = match &self.service {
+			ActuatorService::TurnOnOff => (
+				if on {"turn_on"} else {"turn_off"},
+				json!({"entity_id": entity_id})
+			),
+			ActuatorService::SetValue(on_value, off_value) => (
+				"set_value",
+				json!({"entity_id": entity_id, "value": if on {on_value} else {off_value}})
+			),
+			ActuatorService::SetTemperature(on_value, off_value) => (
+				"set_temperature",
+				json!({"entity_id": entity_id, "temperature": if on {on_value} else {off_value}})
+			),
+			ActuatorService::SetHvacMode(on_value, off_value) => (
+				"set_hvac_mode",
+				json!({"entity_id": entity_id, "hvac_mode": if on {on_value} else {off_value}})
+			),
+		};
+		(format!("/services/{}/{service}", self.domain), data)
+	}
+	/// Check a state returned by Home-Assistant match what we asked.
+	pub fn check_state(&self, state:&JsonValue, on:bool) -> bool {
+		let value = state["state"].as_str().unwrap_or("");
+		let same_float = |v:&JsonValue, expected:f32| {
+			let v = match v.as_str() {
+				Some(s) => s.parse::<f32>().ok(),
+				None => v.as_f32()
+			};
+			v.map(|v| (v-expected).abs()<0.001).unwrap_or(false)
+		};
+		match &self.service {
+			ActuatorService::TurnOnOff => (value!="off")==on, // climate can be heat, cool...
+			ActuatorService::SetValue(on_value, off_value) =>
+				same_float(&state["state"], if on {*on_value} else {*off_value}),
+			ActuatorService::SetTemperature(on_value, off_value) =>
+				same_float(&state["attributes"]["temperature"], if on {*on_value} else {*off_value}),
+			ActuatorService::SetHvacMode(on_value, off_value) =>
+				value==(if on {on_value} else {off_value}),
+		}
+	}
+}
+
+/// Factor to convert a Home-Assistant unit to W (power) or Wh (energy).
+pub fn get_unit_factor(unit:&str) -> f64 {
+	match unit {
+		"mW" => 0.001,
+		"kW" | "kVA" | "kvar" | "kWh" | "kVAh" => 1000.0,
+		"MW" | "MVA" | "MWh" => 1_000_000.0,
+		_ => 1.0 // W, VA, Wh, or not a power
 	}
 }
-    );
-);
 
 impl<'a, 'b:'a, 'c:'b> HomeStateUpdater for HomeAssistantAPI {
     fn default() -> Self {
@@ -237,77 +607,105 @@ impl<'a, 'b:'a, 'c:'b> HomeStateUpdater for HomeAssistantAPI {
 			url: "".to_string(),
 			cached_ids: HashMap::new(),
 			ha_elements: HashMap::new(),
-			cycle_id: 0
+			friendly_names: HashMap::new(),
+			cycle_id: 0,
+			websocket: None,
+			client: Client::new(),
+			retries: 0,
+			last_update: None,
+			stale_since: None,
+			publish: true,
+			published: HashMap::new(),
 		}
 	}
     fn init_network(&mut self)-> ResultOpenHems<bool> {
 		let states = self.call_api("/states", None)?;
-		let mut count = 0;
-		if let JsonValue::Array(parsed_list) = states {
-			for elem in parsed_list {
-				if let JsonValue::Object(entity) = elem {
-					let entity_id = entity.get("entity_id").unwrap().as_str().unwrap();
-					// println!(" - {entity_id}");
-					if let Some(state) = entity.get("state") {
-						// println!("HomeAssistantAPI::init_network() : '{entity_id}' := {state:?}")
-						self.ha_elements.insert(String::from(entity_id), state.clone());
-					}
-					count = count + 1;
+		for entity in states.members() {
+			if let Some(entity_id) = entity["entity_id"].as_str() {
+				self.ha_elements.insert(String::from(entity_id), entity["state"].clone());
+				if let Some(name) = entity["attributes"]["friendly_name"].as_str() {
+					self.friendly_names.insert(String::from(entity_id), name.to_string());
 				}
+			} else {
+				log::warn!("HomeAssistantAPI::init_network() : no entity_id in {entity}");
 			}
 		}
-		println!("Count {count}");
+		log::info!("HomeAssistantAPI::init_network() : {} entities.", self.ha_elements.len());
 		Ok(true)
 	}
+	/// On failure, keep running with previous values (return false) as long as we had some.
 	fn update_network(&mut self) -> ResultOpenHems<bool> {
 		self.cycle_id += 1;
 		if self.cached_ids.len()==0 {
 			log::warn!("HomeAssistantAPI.update_network() : No entities to update.")
 		}
-		let states = self.call_api("/states", None)?;
+		if self.update_from_websocket() {
+			self.set_updated();
+			return Ok(true);
+		}
+		let states = match self.call_api("/states", None) {
+			Ok(states) => states,
+			Err(err) => {
+				let Some(last_update) = self.last_update else {
+					return Err(err);
+				};
+				if self.stale_since.is_none() {
+					self.stale_since = Some(Local::now());
+				}
+				log::error!("{} : keep running with values from {last_update}.", err.message);
+				return Ok(false);
+			}
+		};
 		let mut count = 0;
-		if let JsonValue::Array(parsed_list) = states {
-			for elem in parsed_list {
-				if let JsonValue::Object(entity) = elem {
-					let entity_id = entity.get("entity_id").unwrap().as_str().unwrap();
-					// println!(" - {entity_id}");
-					if self.cached_ids.contains_key(entity_id) {
-						if let Some(state) = entity.get("state") {
-							log::debug!("HomeAssistantAPI::update_network() : '{entity_id}' := {state:?}");
-							self.cached_ids.insert(String::from(entity_id), state.clone());
-						} else {
-							log::warn!("No state for  : '{entity_id}' := {entity:?}");
-						}
-					}
-					count = count + 1;
+		for entity in states.members() {
+			let Some(entity_id) = entity["entity_id"].as_str() else {
+				continue;
+			};
+			if self.cached_ids.contains_key(entity_id) {
+				if entity.has_key("state") {
+					log::debug!("HomeAssistantAPI::update_network() : '{entity_id}' := {}", entity["state"]);
+					self.set_entity(entity_id, entity);
+				} else {
+					log::warn!("No state for  : '{entity_id}' := {entity}");
 				}
 			}
+			count += 1;
 		}
 		log::debug!("update_network() : {} keys.", count);
+		self.set_updated();
 		Ok(true)
 	}
 	fn notify(&self, message: &str) -> ResultOpenHems<bool> {
-		let data = json!({
-			"message": message,
-			"title": "Notification from OpenHEMS."
-		});
-		self.call_api(
-			"/services/notify/persistent_notification", Some(data)
-		)?;
-		Ok(true)
+		self.notify_service("notify.persistent_notification", "Notification from OpenHEMS.", message)
 	}
 	fn get_cycle_id(&self) -> u32 {
 		self.cycle_id
 	}
+	/// Last successful update of Home-Assistant states (REST poll or connected websocket) if the entity has a state :
+	/// a steady entity (like an 'isOn' switch) is not stale, Home-Assistant reports dead devices as 'unavailable'.
+	fn get_entity_last_update(&self, entity_id:&str) -> Option<DateTime<Local>> {
+		match self.cached_ids.get(Self::split_entity_id(entity_id).0) {
+			Some(entity) if !entity.is_null() => self.last_update,
+			_ => None
+		}
+	}
 	fn register_entity(&mut self, nameid:&str) -> bool {
-		println!("register_entity({})", nameid);
-		if !self.cached_ids.contains_key(nameid) {
-			self.cached_ids.insert(nameid.to_string(), JsonValue::Null);
+		log::debug!("register_entity({})", nameid);
+		let (entity_id, _) = Self::split_entity_id(nameid);
+		if !self.cached_ids.contains_key(entity_id) {
+			self.cached_ids.insert(entity_id.to_string(), JsonValue::Null);
+		}
+		if let Some(ws) = self.websocket.as_ref() {
+			ws.register_entity(entity_id);
 		}
 		true
 	}
-    get_entity_value_!(get_entity_value_int, i32, as_i32);
-    get_entity_value_!(get_entity_value_float, f32, as_f32);
+	fn get_entity_value_int(&self, entity_id:&str) -> ResultOpenHems<i32> {
+		Ok(self.get_entity_number(entity_id)?.round() as i32)
+	}
+	fn get_entity_value_float(&self, entity_id:&str) -> ResultOpenHems<f32> {
+		Ok(self.get_entity_number(entity_id)? as f32)
+	}
 	fn get_entity_value_str(&self, entity_id:&str) -> ResultOpenHems<String> {
 		let v= self.get_entity_value(entity_id)?;
 		if let Some(value)  = v.as_str() {
@@ -323,8 +721,8 @@ impl<'a, 'b:'a, 'c:'b> HomeStateUpdater for HomeAssistantAPI {
 			JsonValue::Boolean(value) => {
 				Ok(*value)
 			}
-			JsonValue::Short(value) => {
-				let v2 = value.as_str().to_lowercase();
+			JsonValue::Short(_) | JsonValue::String(_) => {
+				let v2 = v.as_str().unwrap_or("").to_lowercase();
 				let false_value = ["0", "false", "off"].iter().any(|&s| s==v2);
 				// log::debug!("HomeAssistantAPI::get_entity_value_bool({v2}) => not {false_value}");
 				Ok(!false_value)
@@ -371,13 +769,160 @@ impl HomeStateUpdater for FakeNetworkUpdater {
     fn get_cycle_id(&self) -> u32 {
 		0
 	}
+	fn get_entity_last_update(&self, _nameid:&str) -> Option<DateTime<Local>> {
+		Some(Local::now())
+	}
 }
 
 
 #[cfg(test)]
 mod tests {
+    use crate::{feeder::SourceFeeder, network_updater::NetworkSource};
     use super::*;
 
+    #[test]
+    fn test_actuator() -> Result<(), OpenHemsError> {
+		let actuator = Actuator::new("light.kitchen", None)?;
+		let (url, data) = actuator.get_call(false);
+		assert_eq!(url, "/services/light/turn_off");
+		assert_eq!(data, json!({"entity_id": "light.kitchen"}));
+		assert!(actuator.check_state(&json::object!{"state": "off"}, false));
+		assert!(!actuator.check_state(&json::object!{"state": "off"}, true));
+		assert!(Actuator::new("number.ev_current", None).is_err()); // Need on/off values
+		let yaml = &yaml_rust2::YamlLoader::load_from_str("{entity: number.ev_current, on: 16, off: 6}").unwrap()[0];
+		let actuator = Actuator::new("switch.ev", Some(yaml))?;
+		assert_eq!(actuator.get_call(true), (String::from("/services/number/set_value"),
+			json!({"entity_id": "number.ev_current", "value": 16.0})));
+		assert!(actuator.check_state(&json::object!{"state": "16.0"}, true));
+		let yaml = &yaml_rust2::YamlLoader::load_from_str("{service: climate.set_temperature, on: 21, off: 16.5}").unwrap()[0];
+		let actuator = Actuator::new("climate.living", Some(yaml))?;
+		assert_eq!(actuator.get_call(false).1["temperature"], json!(16.5));
+		assert!(actuator.check_state(&json::object!{"state": "heat", "attributes": {"temperature": 21}}, true));
+		let yaml = &yaml_rust2::YamlLoader::load_from_str("{service: climate.set_hvac_mode, on: heat, off: 'off'}").unwrap()[0];
+		let actuator = Actuator::new("climate.living", Some(yaml))?;
+		assert_eq!(actuator.get_call(true).1["hvac_mode"], json!("heat"));
+		assert!(actuator.check_state(&json::object!{"state": "off"}, false));
+		Ok(())
+    }
+
+    // Answer each connection with the next (status, body), return the number of requests.
+    fn mock_http_server(responses:Vec<(u16, &'static str)>) -> (String, std::thread::JoinHandle<usize>) {
+		use std::io::{BufRead, BufReader, Write};
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/api", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || {
+			let mut count = 0;
+			for (status, body) in responses {
+				let Ok((stream, _)) = listener.accept() else {
+					break;
+				};
+				let mut reader = BufReader::new(stream);
+				let mut line = String::new();
+				while reader.read_line(&mut line).map(|n| n>2).unwrap_or(false) {
+					line.clear();
+				}
+				count += 1;
+				let _ = write!(reader.get_mut(), "HTTP/1.1 {status} X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+			}
+			count
+		});
+		(url, server)
+	}
+
+    #[test]
+    fn test_call_api() -> Result<(), OpenHemsError> {
+		let states = r#"[{"entity_id": "sensor.power", "state": "100", "last_updated": "2024-09-17T10:00:00.123+00:00"}]"#;
+		let (url, server) = mock_http_server(vec![(500, "Oops"), (200, states), (401, "")]);
+		let mut api = HomeAssistantAPI::default();
+		api.url = url;
+		api.retries = 1;
+		assert_eq!(api.call_api("/states", None)?[0]["state"], "100"); // Retried
+		let err = api.call_api("/states", None).unwrap_err(); // Not retried
+		assert!(err.message.contains("long_lived_token"), "{}", err.message);
+		assert_eq!(server.join().unwrap(), 3);
+
+		// Attributes and units
+		api.cached_ids.insert("sensor.linky".to_string(), json::parse(r#"{"state": "1.5",
+			"attributes": {"unit_of_measurement": "kVA", "tariff": "HC"}}"#).unwrap());
+		api.cached_ids.insert("switch.ev".to_string(), json::object!{"state": "unavailable"});
+		assert_eq!(api.get_entity_value_float("sensor.linky")?, 1500.0);
+		assert_eq!(api.get_entity_value_str("sensor.linky#tariff")?, "HC");
+		assert!(api.get_entity_value_str("sensor.linky#color").is_err());
+		assert!(api.get_entity_value_bool("switch.ev").is_err());
+
+		// Degraded mode : keep last values
+		let (url, server) = mock_http_server(vec![(200, states), (503, ""), (200, states)]);
+		api.url = url;
+		api.retries = 0;
+		api.register_entity("sensor.power");
+		assert!(api.update_network()?);
+		assert!(!api.update_network()?);
+		assert!(api.is_stale());
+		assert_eq!(api.get_entity_value_str("sensor.power")?, "100");
+		assert!(api.update_network()?);
+		assert!(!api.is_stale());
+		server.join().unwrap();
+
+		// Publish OpenHEMS states, only when changed
+		let (url, server) = mock_http_server(vec![(201, "{}"), (201, "{}"), (200, "{}")]);
+		api.url = url;
+		let mut states = vec![
+			PublishedState::sensor("openhems_car_schedule", "car schedule", "3600".to_string(), "s"),
+			PublishedState::select("openhems_car_mode", "car mode", "auto", &["auto", "on", "off"]),
+		];
+		assert_eq!(PublishedState::sensor("openhems_switch.Water-Heater _schedule", "", String::new(), "").object_id,
+			"openhems_switch_water_heater_schedule");
+		api.publish_states(&states)?;
+		api.publish_states(&states)?;
+		states[0].value = "3570".to_string();
+		api.publish_states(&states)?;
+		assert_eq!(server.join().unwrap(), 3);
+		assert!(api.published.contains_key("sensor.openhems_car_mode")); // A select is read-only there
+		Ok(())
+    }
+
+    #[test]
+    fn test_stale() -> Result<(), OpenHemsError> {
+		// Switch on for days : it's the source update that counts, not the last change.
+		let states = r#"[{"entity_id": "switch.ev", "state": "on", "last_updated": "2024-09-17T10:00:00.123+00:00"}]"#;
+		let (url, server) = mock_http_server(vec![(200, states)]);
+		let mut api = HomeAssistantAPI::default();
+		api.url = url;
+		api.register_entity("switch.ev");
+		assert!(api.update_network()?);
+		server.join().unwrap();
+		let updater = Rc::new(RefCell::new(NetworkUpdater::new(NetworkSource::HomeAssistant(Box::new(api)), None)));
+		let is_on = Feeder::Source(SourceFeeder::new(Rc::clone(&updater), "switch.ev")?);
+		let node = node::get_nodebase("ev", 1000.0, 0.0, Feeder::Const(ConstFeeder::new(0.0)), is_on)?;
+		let now = Local::now();
+		assert!(!node.is_stale(now+chrono::Duration::seconds(299), 300));
+		assert!(node.is_stale(now+chrono::Duration::seconds(301), 300)); // No update since
+		// Unreachable Home-Assistant : nothing is posted (no retries), notifications fail at once
+		let mut api = HomeAssistantAPI::default();
+		api.url = String::from("http://127.0.0.1:9");
+		api.stale_since = Some(now);
+		api.publish_states(&[PublishedState::sensor("openhems_test", "test", "1".to_string(), "")])?;
+		assert!(api.published.is_empty());
+		assert!(api.notify_service("notify.phone", "title", "message").is_err());
+		Ok(())
+    }
+
+    #[test]
+    fn test_nodebase_is_on() -> Result<(), OpenHemsError> {
+		let mut api = HomeAssistantAPI::default();
+		api.ha_elements.insert("switch.ev".to_string(), "on".into());
+		let updater = Rc::new(RefCell::new(NetworkUpdater::new(NetworkSource::HomeAssistant(Box::new(api)), None)));
+		let get_nodebase = |conf:&str| {
+			let yaml = &yaml_rust2::YamlLoader::load_from_str(conf).unwrap()[0];
+			HomeAssistantAPI::get_nodebase(Rc::clone(&updater), "ev", &cast_utility::to_type_dict(yaml))
+		};
+		assert!(matches!(get_nodebase("{isOn: switch.ev, currentPower: 0}")?.is_on, Feeder::Source(_)));
+		assert!(matches!(get_nodebase("{currentPower: 0}")?.is_on, Feeder::Guess(_)));
+		assert!(matches!(get_nodebase("{isOn: '', currentPower: 0}")?.is_on, Feeder::Guess(_)));
+		assert!(get_nodebase("{isOn: switch.evv, currentPower: 0}").is_err()); // Typo
+		Ok(())
+    }
+
     #[test]
     fn local_test() -> Result<(), OpenHemsError> {
 		let mut api = HomeAssistantAPI::default();
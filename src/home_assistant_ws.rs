@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+use json::JsonValue;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Connector, Message, WebSocket};
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+const MAX_RECONNECT_DELAY:u64 = 60;
+const READ_TIMEOUT:Duration = Duration::from_secs(30); // Ping when nothing is received, then give up
+
+/// Keep registered Home-Assistant entities states up to date with the WebSocket API.
+/// A background thread subscribes to 'state_changed' events and reconnects when needed.
+#[derive(Clone)]
+pub struct HomeAssistantWebSocket {
+	url: String,
+	token: String,
+	entities: Arc<Mutex<HashSet<String>>>,
+	states: Arc<Mutex<HashMap<String, JsonValue>>>,
+	connected: Arc<AtomicBool>,
+	tls: Option<Arc<rustls::ClientConfig>>, // With 'api.ssl_certificate', else default roots
+}
+impl HomeAssistantWebSocket {
+	/// Websocket URL from REST API URL : http://host:8123/api => ws://host:8123/api/websocket
+	pub fn get_url(api_url:&str) -> String {
+		let url = if let Some(u) = api_url.strip_prefix("https://") {
+			format!("wss://{u}")
+		} else if let Some(u) = api_url.strip_prefix("http://") {
+			format!("ws://{u}")
+		} else {
+			api_url.to_string()
+		};
+		format!("{}/websocket", url.trim_end_matches('/'))
+	}
+	pub fn new(url:&str, token:&str) -> HomeAssistantWebSocket {
+		HomeAssistantWebSocket {
+			url: url.to_string(),
+			token: token.to_string(),
+			entities: Arc::new(Mutex::new(HashSet::new())),
+			states: Arc::new(Mutex::new(HashMap::new())),
+			connected: Arc::new(AtomicBool::new(false)),
+			tls: None,
+		}
+	}
+	/// Trust also the CA certificates of PEM file 'ca_path' (like the REST client).
+	pub fn set_certificate(&mut self, ca_path:&str) -> ResultOpenHems<()> {
+		let ca_file = std::fs::File::open(ca_path)
+			.map_err(|err| OpenHemsError::new(format!("Fail read api.ssl_certificate '{ca_path}' : {err}")))?;
+		let mut roots = rustls::RootCertStore {roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()};
+		for certificate in rustls_pemfile::certs(&mut std::io::BufReader::new(ca_file)) {
+			certificate.map_err(|err| OpenHemsError::new(format!("Invalid certificate in '{ca_path}' : {err}")))
+				.and_then(|certificate| roots.add(certificate)
+					.map_err(|err| OpenHemsError::new(format!("Invalid certificate in '{ca_path}' : {err}"))))?;
+		}
+		let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+			.with_safe_default_protocol_versions()
+			.map_err(|err| OpenHemsError::new(format!("Fail configure TLS : {err}")))?
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		self.tls = Some(Arc::new(config));
+		Ok(())
+	}
+	pub fn register_entity(&self, entity_id:&str) {
+		if let Ok(mut entities) = self.entities.lock() {
+			entities.insert(entity_id.to_string());
+		}
+	}
+	/// True once the initial states are received, until the session ends.
+	pub fn is_connected(&self) -> bool {
+		self.connected.load(Ordering::SeqCst)
+	}
+	/// Last state objects received (with 'state', 'attributes', 'last_updated'...), by entity id.
+	pub fn get_states(&self) -> HashMap<String, JsonValue> {
+		match self.states.lock() {
+			Ok(states) => states.clone(),
+			Err(_) => HashMap::new()
+		}
+	}
+	/// Start the background thread, the receiver get the id of each changed entity.
+	pub fn start(&self) -> Receiver<String> {
+		let (sender, receiver) = channel();
+		let ws = self.clone();
+		std::thread::spawn(move || {
+			let mut delay = 1;
+			loop {
+				match ws.run_session(&sender) {
+					Ok(()) => {
+						log::info!("Home-Assistant websocket closed.");
+					}
+					Err(err) => {
+						log::warn!("Home-Assistant websocket : {}", err.message);
+					}
+				}
+				// Back to a short delay once a session worked, even if it ended by an error.
+				if ws.connected.swap(false, Ordering::SeqCst) {
+					delay = 1;
+				}
+				if ws.is_stopped(&sender) {
+					break;
+				}
+				log::info!("Home-Assistant websocket : reconnect in {delay} seconds.");
+				sleep(Duration::from_secs(delay));
+				delay = (delay*2).min(MAX_RECONNECT_DELAY);
+			}
+		});
+		receiver
+	}
+	fn is_stopped(&self, sender:&Sender<String>) -> bool {
+		// Nobody listen anymore
+		sender.send(String::new()).is_err()
+	}
+	fn read_json(socket:&mut Socket) -> ResultOpenHems<Option<JsonValue>> {
+		let message = socket.read()
+			.map_err(|err| OpenHemsError::new(format!("Fail read : {err}")))?;
+		Self::parse_message(message)
+	}
+	fn parse_message(message:Message) -> ResultOpenHems<Option<JsonValue>> {
+		match message {
+			Message::Text(text) => {
+				json::parse(text.as_str())
+					.map(Some)
+					.map_err(|err| OpenHemsError::new(format!("Fail parse '{text}' : {err}")))
+			}
+			Message::Close(_) => Err(OpenHemsError::new("Connection closed by Home-Assistant.".to_string())),
+			_ => Ok(None) // Ping/Pong are handled by tungstenite
+		}
+	}
+	fn send_json(socket:&mut Socket, data:JsonValue) -> ResultOpenHems<()> {
+		socket.send(Message::text(data.dump()))
+			.map_err(|err| OpenHemsError::new(format!("Fail send : {err}")))
+	}
+	fn authenticate(&self, socket:&mut Socket) -> ResultOpenHems<()> {
+		loop {
+			let Some(message) = Self::read_json(socket)? else {
+				continue;
+			};
+			match message["type"].as_str() {
+				Some("auth_required") => {
+					Self::send_json(socket, json::object!{
+						"type": "auth",
+						"access_token": self.token.as_str()
+					})?;
+				}
+				Some("auth_ok") => {
+					return Ok(());
+				}
+				Some("auth_invalid") => {
+					return Err(OpenHemsError::new(format!("Authentication refused : {}", message["message"])));
+				}
+				_ => {
+					log::debug!("Home-Assistant websocket : ignore {message}");
+				}
+			}
+		}
+	}
+	fn run_session(&self, sender:&Sender<String>) -> ResultOpenHems<()> {
+		log::info!("Connect to Home-Assistant websocket : {}", self.url);
+		let mut socket = self.connect()?;
+		self.authenticate(&mut socket)?;
+		Self::send_json(&mut socket, json::object!{
+			"id": 1,
+			"type": "subscribe_events",
+			"event_type": "state_changed"
+		})?;
+		Self::send_json(&mut socket, json::object!{
+			"id": 2,
+			"type": "get_states"
+		})?;
+		let mut ping_sent = false;
+		loop {
+			// A half-open connection is only detected by a ping without answer.
+			let message = match socket.read() {
+				Ok(message) => message,
+				Err(tungstenite::Error::Io(err)) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+					if ping_sent {
+						return Err(OpenHemsError::new(format!("No answer for {} seconds.", 2*READ_TIMEOUT.as_secs())));
+					}
+					socket.send(Message::Ping(Default::default()))
+						.map_err(|err| OpenHemsError::new(format!("Fail send ping : {err}")))?;
+					ping_sent = true;
+					continue;
+				}
+				Err(err) => {
+					return Err(OpenHemsError::new(format!("Fail read : {err}")));
+				}
+			};
+			ping_sent = false;
+			let Some(message) = Self::parse_message(message)? else {
+				continue;
+			};
+			match message["type"].as_str() {
+				Some("event") => {
+					let data = &message["event"]["data"];
+					if let Some(entity_id) = data["entity_id"].as_str() {
+						if self.update_state(entity_id, &data["new_state"]) {
+							log::debug!("Home-Assistant websocket : '{entity_id}' := {}", data["new_state"]["state"]);
+							if sender.send(entity_id.to_string()).is_err() {
+								return Ok(()); // Nobody listen anymore
+							}
+						}
+					}
+				}
+				Some("result") => {
+					if !message["success"].as_bool().unwrap_or(false) {
+						return Err(OpenHemsError::new(format!("Request {} failed : {}", message["id"], message["error"])));
+					}
+					if message["id"].as_u32()==Some(2) {
+						for state in message["result"].members() {
+							if let Some(entity_id) = state["entity_id"].as_str() {
+								self.update_state(entity_id, state);
+							}
+						}
+						self.log_missing_states();
+						self.connected.store(true, Ordering::SeqCst);
+					}
+				}
+				_ => {}
+			}
+		}
+	}
+	// Socket with a read timeout, and the configured certificates for wss://.
+	fn connect(&self) -> ResultOpenHems<Socket> {
+		let error = |err:String| OpenHemsError::new(format!("Fail connect to '{}' : {err}", self.url));
+		let request = self.url.as_str().into_client_request().map_err(|err| error(err.to_string()))?;
+		let uri = request.uri();
+		let host = uri.host().unwrap_or_default().trim_matches(['[', ']']).to_string();
+		let port = uri.port_u16().unwrap_or(if uri.scheme_str()==Some("wss") {443} else {80});
+		let stream = TcpStream::connect((host.as_str(), port)).map_err(|err| error(err.to_string()))?;
+		stream.set_read_timeout(Some(READ_TIMEOUT)).map_err(|err| error(err.to_string()))?;
+		let connector = self.tls.as_ref().map(|config| Connector::Rustls(Arc::clone(config)));
+		let (socket, _) = tungstenite::client_tls_with_config(request, stream, None, connector)
+			.map_err(|err| error(err.to_string()))?;
+		Ok(socket)
+	}
+	// Registered entities unknown to Home-Assistant (typo, removed device...) will never get a state.
+	fn log_missing_states(&self) {
+		let (Ok(entities), Ok(states)) = (self.entities.lock(), self.states.lock()) else {
+			return;
+		};
+		for entity_id in entities.iter().filter(|entity_id| !states.contains_key(*entity_id)) {
+			log::warn!("Home-Assistant websocket : no state for '{entity_id}', it stays unknown.");
+		}
+	}
+	// Return true if the entity is registered (so updated).
+	fn update_state(&self, entity_id:&str, state:&JsonValue) -> bool {
+		let registered = match self.entities.lock() {
+			Ok(entities) => entities.contains(entity_id),
+			Err(_) => false
+		};
+		if registered {
+			if let Ok(mut states) = self.states.lock() {
+				states.insert(entity_id.to_string(), state.clone());
+			}
+		}
+		registered
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+	use super::*;
+
+	// Play Home-Assistant side of the protocol.
+	fn mock_server(listener:TcpListener) -> ResultOpenHems<()> {
+		let (stream, _) = listener.accept().unwrap();
+		let mut socket = tungstenite::accept(MaybeTlsStream::Plain(stream)).unwrap();
+		HomeAssistantWebSocket::send_json(&mut socket, json::object!{"type": "auth_required"})?;
+		let auth = HomeAssistantWebSocket::read_json(&mut socket)?.unwrap();
+		assert_eq!(auth["access_token"], "token");
+		HomeAssistantWebSocket::send_json(&mut socket, json::object!{"type": "auth_ok"})?;
+		let subscribe = HomeAssistantWebSocket::read_json(&mut socket)?.unwrap();
+		assert_eq!(subscribe["event_type"], "state_changed");
+		let get_states = HomeAssistantWebSocket::read_json(&mut socket)?.unwrap();
+		HomeAssistantWebSocket::send_json(&mut socket, json::object!{"id": get_states["id"].clone(), "type": "result", "success": true,
+			"result": [{"entity_id": "sensor.power", "state": "100"}, {"entity_id": "sensor.other", "state": "1"}]})?;
+		HomeAssistantWebSocket::send_json(&mut socket, json::object!{"type": "event", "event": {"data": {
+			"entity_id": "sensor.power", "new_state": {"entity_id": "sensor.power", "state": "250"}
+		}}})?;
+		sleep(Duration::from_millis(500));
+		Ok(())
+	}
+
+	#[test]
+	fn test_websocket() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("ws://{}/api/websocket", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || mock_server(listener));
+		assert_eq!(HomeAssistantWebSocket::get_url("https://ha.local/api/"), "wss://ha.local/api/websocket");
+		let mut ws = HomeAssistantWebSocket::new(&url, "token");
+		assert!(ws.set_certificate("/nonexistent/ca.pem").is_err());
+		ws.register_entity("sensor.power");
+		ws.register_entity("sensor.missing");
+		let events = ws.start();
+		let entity_id = loop {
+			let entity_id = events.recv_timeout(Duration::from_secs(5)).unwrap();
+			if !entity_id.is_empty() {
+				break entity_id;
+			}
+		};
+		assert_eq!(entity_id, "sensor.power");
+		let states = ws.get_states();
+		assert_eq!(states.get("sensor.power").map(|s| s["state"].clone()), Some("250".into()));
+		assert!(!states.contains_key("sensor.other"));
+		assert!(!states.contains_key("sensor.missing"));
+		assert!(ws.is_connected());
+		server.join().unwrap().unwrap();
+	}
+}
@@ -0,0 +1,260 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::{OnceLock, RwLock};
+use log::{LevelFilter, Log, Metadata, Record};
+use crate::cast_utility;
+use crate::configuration_manager::ConfigurationManager;
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+const CRATE_PREFIX:&str = "openhems_rust::";
+
+// Log file renamed in <file>.1, <file>.2... when it's too big.
+struct RotatingFile {
+	path: String,
+	file: File,
+	size: u64,
+	max_size: u64,
+	backups: u32,
+}
+impl RotatingFile {
+	fn open(path:&str, max_size:u64, backups:u32) -> ResultOpenHems<RotatingFile> {
+		let file = OpenOptions::new().create(true).append(true).open(path)
+			.map_err(|err| OpenHemsError::new(format!("Fail open log file '{path}' : {err}")))?;
+		let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+		Ok(RotatingFile {
+			path: path.to_string(),
+			file,
+			size,
+			max_size,
+			backups,
+		})
+	}
+	fn write(&mut self, line:&str) {
+		if self.max_size>0 && self.size+(line.len() as u64)>self.max_size {
+			self.rotate();
+		}
+		if self.file.write_all(line.as_bytes()).is_ok() {
+			self.size += line.len() as u64;
+		}
+	}
+	fn rotate(&mut self) {
+		if self.backups==0 {
+			let _ = self.file.set_len(0);
+		} else {
+			for i in (1..self.backups).rev() {
+				let _ = fs::rename(format!("{}.{i}", self.path), format!("{}.{}", self.path, i+1));
+			}
+			let _ = fs::rename(&self.path, format!("{}.1", self.path));
+		}
+		if let Ok(file) = OpenOptions::new().create(true).append(true).open(&self.path) {
+			self.file = file;
+		}
+		self.size = 0;
+	}
+}
+
+struct LoggerState {
+	level: LevelFilter,
+	modules: Vec<(String, LevelFilter)>, // Sorted longest first
+	format: String,
+	file: Option<RotatingFile>,
+}
+impl LoggerState {
+	fn get_level(&self, target:&str) -> LevelFilter {
+		for (module, level) in &self.modules {
+			if target==module || target.starts_with(&format!("{module}::")) {
+				return *level;
+			}
+		}
+		self.level
+	}
+	fn get_max_level(&self) -> LevelFilter {
+		self.modules.iter().map(|(_, l)| *l).fold(self.level, |a, b| a.max(b))
+	}
+	fn format(&self, record:&Record) -> String {
+		let target = record.target();
+		let module = target.rsplit("::").next().unwrap_or(target);
+		let mut line = self.format
+			.replace("%(asctime)s", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+			.replace("%(levelname)s", record.level().as_str())
+			.replace("%(name)s", target)
+			.replace("%(module)s", module)
+			.replace("%(filename)s", record.file().unwrap_or(""))
+			.replace("%(lineno)d", &record.line().unwrap_or(0).to_string());
+		// Last, so '%(...)' in messages are kept.
+		line = line.replace("%(message)s", &record.args().to_string());
+		line.push('\n');
+		line
+	}
+}
+
+/// Logger configured by 'server.log*' keys, levels can be changed while running.
+pub struct Logger {
+	state: RwLock<LoggerState>,
+}
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Log for Logger {
+	fn enabled(&self, metadata:&Metadata) -> bool {
+		match self.state.read() {
+			Ok(state) => metadata.level()<=state.get_level(metadata.target()),
+			Err(_) => true
+		}
+	}
+	fn log(&self, record:&Record) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		if let Ok(mut state) = self.state.write() {
+			let line = state.format(record);
+			let _ = std::io::stderr().write_all(line.as_bytes());
+			if let Some(file) = state.file.as_mut() {
+				file.write(&line);
+			}
+		}
+	}
+	fn flush(&self) {
+		if let Ok(mut state) = self.state.write() {
+			if let Some(file) = state.file.as_mut() {
+				let _ = file.file.flush();
+			}
+		}
+	}
+}
+
+/// Install the logger with a default configuration (used until configure() is called).
+pub fn init() {
+	let logger = LOGGER.get_or_init(|| Logger {
+		state: RwLock::new(LoggerState {
+			level: LevelFilter::Info,
+			modules: Vec::new(),
+			format: String::from("%(asctime)s [%(levelname)s] - %(message)s"),
+			file: None,
+		})
+	});
+	if log::set_logger(logger).is_ok() {
+		log::set_max_level(LevelFilter::Info);
+	}
+}
+
+/// Parse a level as in 'server.loglevel' (Python names are accepted).
+pub fn parse_level(level:&str) -> ResultOpenHems<LevelFilter> {
+	match level.trim().to_lowercase().as_str() {
+		"trace" => Ok(LevelFilter::Trace),
+		"debug" => Ok(LevelFilter::Debug),
+		"info" | "" => Ok(LevelFilter::Info),
+		"warn" | "warning" => Ok(LevelFilter::Warn),
+		"error" | "critical" => Ok(LevelFilter::Error),
+		"no" | "off" => Ok(LevelFilter::Off),
+		_ => Err(OpenHemsError::new(format!("Invalid log level '{level}'.")))
+	}
+}
+
+/// Parse "module=level" entries, module is relative to this crate or a dependency name.
+pub fn parse_modules(entries:&[String]) -> ResultOpenHems<Vec<(String, LevelFilter)>> {
+	let mut modules = Vec::new();
+	for entry in entries.iter().flat_map(|e| e.split(',')) {
+		if entry.trim().is_empty() {
+			continue;
+		}
+		let Some((module, level)) = entry.split_once('=') else {
+			return Err(OpenHemsError::new(format!("Invalid module log level '{entry}', expect 'module=level'.")));
+		};
+		let module = module.trim().replace('-', "_");
+		let level = parse_level(level)?;
+		// Our own modules can be given without crate name.
+		if !module.starts_with(CRATE_PREFIX) && !module.contains("::") {
+			modules.push((format!("{CRATE_PREFIX}{module}"), level));
+		}
+		modules.push((module, level));
+	}
+	modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+	Ok(modules)
+}
+
+fn set_levels(level:LevelFilter, modules:Vec<(String, LevelFilter)>) -> ResultOpenHems<()> {
+	let Some(logger) = LOGGER.get() else {
+		return Err(OpenHemsError::new("Logger is not initialized.".to_string()));
+	};
+	let mut state = logger.state.write()
+		.map_err(|_| OpenHemsError::new("Logger is poisoned.".to_string()))?;
+	state.level = level;
+	state.modules = modules;
+	log::set_max_level(state.get_max_level());
+	Ok(())
+}
+
+/// Apply 'server.loglevel', 'server.logmodules', 'server.logformat' and 'server.logfile*'.
+pub fn configure(configurator:&ConfigurationManager) -> ResultOpenHems<()> {
+	let level = parse_level(&configurator.get_as_str("server.loglevel"))?;
+	let entries:Vec<String> = configurator.get_as_list("server.logmodules").iter()
+		.map(|v| cast_utility::to_type_str(v))
+		.collect();
+	let modules = parse_modules(&entries)?;
+	let path = configurator.get_as_str("server.logfile");
+	let file = if path.is_empty() {
+		None
+	} else {
+		let max_size = configurator.get_as_int("server.logmaxsize").max(0) as u64 * 1024;
+		let backups = configurator.get_as_int("server.logbackups").max(0) as u32;
+		Some(RotatingFile::open(&path, max_size, backups)?)
+	};
+	if let Some(logger) = LOGGER.get() {
+		let mut state = logger.state.write()
+			.map_err(|_| OpenHemsError::new("Logger is poisoned.".to_string()))?;
+		let format = configurator.get_as_str("server.logformat");
+		if !format.is_empty() {
+			state.format = format;
+		}
+		state.file = file;
+	}
+	set_levels(level, modules)
+}
+
+/// Change levels while running (from the web API), return the new levels description.
+pub fn set_levels_str(level:&str, modules:&[String]) -> ResultOpenHems<String> {
+	let level = parse_level(level)?;
+	let modules = parse_modules(modules)?;
+	set_levels(level, modules)?;
+	log::info!("Log levels changed to {}", get_levels_json());
+	Ok(get_levels_json())
+}
+
+pub fn get_levels_json() -> String {
+	let Some(state) = LOGGER.get().and_then(|l| l.state.read().ok()) else {
+		return String::from("{}");
+	};
+	let modules:Vec<String> = state.modules.iter()
+		.filter(|(m, _)| !m.starts_with(CRATE_PREFIX))
+		.map(|(m, l)| format!("\"{m}={}\"", l.as_str().to_lowercase()))
+		.collect();
+	format!("{{\"level\":\"{}\", \"modules\":[{}]}}", state.level.as_str().to_lowercase(), modules.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_levels() -> ResultOpenHems<()> {
+		let modules = parse_modules(&[String::from("home_assistant_api=debug, actix_web=warn")])?;
+		let state = LoggerState {
+			level: parse_level("warning")?,
+			modules,
+			format: String::from("%(levelname)s:%(module)s:%(message)s"),
+			file: None,
+		};
+		assert_eq!(state.get_level("openhems_rust::home_assistant_api"), LevelFilter::Debug);
+		assert_eq!(state.get_level("actix_web::middleware"), LevelFilter::Warn);
+		assert_eq!(state.get_level("openhems_rust::server"), LevelFilter::Warn);
+		assert_eq!(state.get_max_level(), LevelFilter::Debug);
+		assert!(parse_modules(&[String::from("server")]).is_err());
+		let record = Record::builder()
+			.args(format_args!("100%(message)s"))
+			.level(log::Level::Info)
+			.target("openhems_rust::server")
+			.build();
+		assert_eq!(state.format(&record), "INFO:server:100%(message)s\n");
+		Ok(())
+	}
+}
@@ -5,9 +5,11 @@ use log;
 use chrono::{self};
 use schedule::Schedule;
 use tera::Tera;
-use web::AppState;
-use env_logger;
+use web::{AppState, WebServerConfig};
+use auth::AuthConfig;
+use error::{OpenHemsError, ResultOpenHems};
 use server::Server;
+use configuration_schema::Severity;
 use std::{io::Write};
 // use actix_web::{get, web, App, HttpServer, Responder};
 use std::sync::mpsc::channel;
@@ -15,42 +17,88 @@ use actix_web::{App, HttpServer};
 
 mod utils;
 mod home_assistant_api;
+mod home_assistant_ws;
 mod cast_utility;
 mod configuration_manager;
+mod configuration_schema;
+mod cli;
+mod auth;
+mod logger;
 mod node;
 mod network;
+mod network_updater;
+mod notification;
+mod modbus_updater;
+mod mqtt_updater;
+mod teleinfo;
 mod error;
+mod expression;
 mod  feeder;
+mod filter;
 mod time;
 mod offpeak_strategy;
 mod contract;
+mod cycle;
 mod server;
 mod schedule;
 mod web;
 mod solarnosell_strategy;
 
 
-fn start_web_server(shared_state: Arc<AppState>) -> std::thread::JoinHandle<()> {
-    let tera = Tera::new("templates/**/*.jinja2")
-        .expect("Failed to parse templates");
-    std::thread::spawn(|| {
-        // Create an Actix runtime in the new thread
-        let sys = actix_rt::System::new();
-        sys.block_on(async {
-            let server = HttpServer::new(move || {
-                App::new()
-					.service(fs::Files::new("/js", "./js").show_files_listing())
-					.service(fs::Files::new("/css", "./css").show_files_listing())
-					.service(fs::Files::new("/img", "./img").show_files_listing())
-          			.app_data(actix_web::web::Data::new(tera.clone()))
-					.app_data(actix_web::web::Data::new(shared_state.clone()))
-					.route("/", actix_web::web::get().to(web::index))
-					.route("/states", actix_web::web::post().to(web::states))
+fn start_web_server(shared_state: Arc<AppState>, conf: WebServerConfig, auth: AuthConfig) -> ResultOpenHems<std::thread::JoinHandle<()>> {
+	let templates = conf.get_static_path("templates/**/*.jinja2");
+	let tera = Tera::new(&templates)
+		.map_err(|err| OpenHemsError::new(format!("Failed to parse templates '{templates}' : {err}")))?;
+	let tls_config = if conf.is_tls() {
+		Some(conf.get_tls_config()?)
+	} else {
+		None
+	};
+	let (sender, receiver) = channel();
+	let handle = std::thread::spawn(move || {
+		// Create an Actix runtime in the new thread
+		let sys = actix_rt::System::new();
+		sys.block_on(async {
+			let address = (conf.bind_address.clone(), conf.port);
+			let workers = conf.workers;
+			let conf2 = conf.clone();
+			let server = HttpServer::new(move || {
+					let conf = conf2.clone();
+					App::new()
+						.app_data(actix_web::web::Data::new(tera.clone()))
+						.app_data(actix_web::web::Data::new(shared_state.clone()))
+						.app_data(actix_web::web::Data::new(conf.clone()))
+						.app_data(actix_web::web::Data::new(auth.clone()))
+						.service(actix_web::web::scope(conf.get_scope())
+							.service(fs::Files::new("/js", conf.get_static_path("js")).show_files_listing())
+							.service(fs::Files::new("/css", conf.get_static_path("css")).show_files_listing())
+							.service(fs::Files::new("/img", conf.get_static_path("img")).show_files_listing())
+							.route("/", actix_web::web::get().to(web::index))
+							.route("/states", actix_web::web::post().to(web::states))
+							.route("/log", actix_web::web::get().to(web::get_log))
+							.route("/log", actix_web::web::post().to(web::set_log))
+						)
 				})
-    			.workers(1)
-				.bind("127.0.0.1:8000")
-				.unwrap()
-				.run();
+				.workers(workers);
+			let server = if let Some(tls_config) = tls_config {
+				server.bind_rustls_0_23(address, tls_config)
+			} else {
+				server.bind(address)
+			};
+			let server = match server {
+				Ok(server) => {
+					log::info!("Web server listen on {}://{}:{}{}",
+						if conf.is_tls() {"https"} else {"http"}, conf.bind_address, conf.port, conf.html_root);
+					let _ = sender.send(Ok(()));
+					server.run()
+				}
+				Err(err) => {
+					let _ = sender.send(Err(OpenHemsError::new(
+						format!("Fail bind {}:{} : {err}", conf.bind_address, conf.port)
+					)));
+					return;
+				}
+			};
 			// // Listen for Ctrl+C in the Actix runtime thread
 			// let server_handle = server.handle();
 			// actix_web::rt::spawn(async move {
@@ -59,45 +107,105 @@ fn start_web_server(shared_state: Arc<AppState>) -> std::thread::JoinHandle<()>
 			// 	panic!("Server stopped");
 			// });
 			server.await.unwrap();
-        });
-    })
+		});
+	});
+	receiver.recv()
+		.map_err(|err| OpenHemsError::new(format!("Web server thread failed : {err}")))??;
+	Ok(handle)
 }
 
 fn main() {
-    env_logger::Builder::new()
-        .format(|buf, record| {
-            writeln!(buf,
-                "{} [{}] - {}",
-               chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                record.args()
-            )
-        })
-        .filter(None, log::LevelFilter::Debug)
-        .init();
-    log::info!("log level:");
-	let mut configurator = configuration_manager::get(None);
-	let file_path = "./config/openhems.yaml";
+    logger::init();
+	let args = match cli::Arguments::parse(std::env::args().skip(1)) {
+		Ok(args) => args,
+		Err(err) => {
+			eprintln!("{}\n{}", err.message, cli::USAGE);
+			std::process::exit(2);
+		}
+	};
+	if args.help {
+		println!("{}", cli::USAGE);
+		return;
+	}
+	if let Some(password) = &args.hash_password {
+		match auth::hash_password(password) {
+			Ok(hash) => println!("{hash}"),
+			Err(err) => {
+				eprintln!("{}", err.message);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+	let check_only = args.check_config;
+	let mut configurator = configuration_manager::get(Some(args.default_path.clone()));
+	let file_path = &args.config_path;
 	if let Err(err) = configurator.add_yaml_config(file_path, false) {
 		log::error!("Fail load configuration {file_path}: {err}");
+		let mut issue = configurator.issue(Severity::Fatal, "", format!("can not be loaded : {err}"));
+		issue.file = file_path.to_string();
+		configurator.add_issue(issue);
 	}
-	let file_path = "./config/openhems.secret.yaml";
-	if let Err(err) = configurator.add_yaml_config(file_path, false) {
-		log::error!("Fail load configuration {file_path} : {err}");
+	let file_path = &args.secret_path;
+	if let Err(err) = configurator.add_secret_config(file_path) {
+		log::warn!("Fail load secrets {file_path} : {err}");
+	}
+	configurator.add_env_config(std::env::vars());
+	let issues = configurator.check();
+	let nb_fatal = issues.iter().filter(|issue| issue.is_fatal()).count();
+	if check_only {
+		for issue in &issues {
+			println!("{issue}");
+		}
+		println!("{} error(s), {} warning(s).", nb_fatal, issues.len()-nb_fatal);
+		std::process::exit(if nb_fatal>0 {1} else {0});
+	}
+	for issue in &issues {
+		if issue.is_fatal() {
+			log::error!("{issue}");
+		} else {
+			log::warn!("{issue}");
+		}
+	}
+	if nb_fatal>0 {
+		log::error!("Invalid configuration ({nb_fatal} error(s)), refuse to start. Run with --check-config for details.");
+		std::process::exit(1);
 	}
+	if let Err(err) = logger::configure(&configurator) {
+		log::error!("Fail configure logs : {}", err.message);
+		std::process::exit(1);
+	}
+	let auth = match AuthConfig::from_conf(&configurator) {
+		Ok(auth) => auth,
+		Err(err) => {
+			log::error!("Invalid authentication configuration : {}", err.message);
+			std::process::exit(1);
+		}
+	};
 	let mut appstate = AppState::new();
 	match Server::new(&configurator) {
 		Err(err) =>  {
 			log::error!("Fail configure server : {}", err.message);
+			std::process::exit(1);
 		}
 		Ok(mut hems_server) => {
 			if let Err(err) = hems_server.init(&configurator, &mut appstate) {
 				log::error!("Fail init server : {}", err.message);
+				std::process::exit(1);
+			}
+			if let Err(err) = appstate.restore(&configurator.get_as_str("server.stateFile")) {
+				log::error!("Fail restore schedules : {}", err.message);
 			}
 			let appstate2 = Arc::new(appstate);
 			log::info!("Server : {:?}", hems_server);
-			let _ = hems_server.network.borrow().notify("OpenHEMS started");	
-			let httpserver = start_web_server(Arc::clone(&appstate2));
+			let _ = hems_server.network.borrow().notify(notification::Severity::Info, "OpenHEMS started");	
+			let httpserver = match start_web_server(Arc::clone(&appstate2), WebServerConfig::from_conf(&configurator), auth) {
+				Ok(httpserver) => httpserver,
+				Err(err) => {
+					log::error!("Fail start web server : {}", err.message);
+					std::process::exit(1);
+				}
+			};
 			hems_server.run(appstate2);
 			httpserver.join().unwrap();
 		}
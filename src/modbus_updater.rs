@@ -0,0 +1,583 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::Duration;
+use chrono::{DateTime, Local};
+use yaml_rust2::Yaml;
+use crate::cast_utility;
+use crate::configuration_manager::ConfigurationManager;
+use crate::error::{OpenHemsError, ResultOpenHems};
+use crate::home_assistant_api::{Actuator, ActuatorService, HomeStateUpdater};
+
+const READ_COILS:u8 = 0x01;
+const READ_DISCRETE_INPUTS:u8 = 0x02;
+const READ_HOLDING_REGISTERS:u8 = 0x03;
+const READ_INPUT_REGISTERS:u8 = 0x04;
+const WRITE_SINGLE_COIL:u8 = 0x05;
+const WRITE_SINGLE_REGISTER:u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS:u8 = 0x10;
+
+/// Minimal Modbus TCP client (one request at a time).
+struct ModbusClient {
+	stream: TcpStream,
+	transaction_id: u16,
+}
+impl ModbusClient {
+	fn connect(address:&str, timeout:Duration) -> ResultOpenHems<ModbusClient> {
+		let socket_address = std::net::ToSocketAddrs::to_socket_addrs(address)
+			.ok().and_then(|mut a| a.next())
+			.ok_or(OpenHemsError::new(format!("Invalid Modbus address '{address}'.")))?;
+		let stream = TcpStream::connect_timeout(&socket_address, timeout)
+			.map_err(|err| OpenHemsError::new(format!("Fail connect to Modbus '{address}' : {err}")))?;
+		let _ = stream.set_read_timeout(Some(timeout));
+		let _ = stream.set_write_timeout(Some(timeout));
+		Ok(ModbusClient {
+			stream,
+			transaction_id: 0,
+		})
+	}
+	// Send a PDU (function + data) and return the response data (without function code).
+	fn request(&mut self, unit:u8, function:u8, data:&[u8]) -> ResultOpenHems<Vec<u8>> {
+		self.transaction_id = self.transaction_id.wrapping_add(1);
+		let mut frame = Vec::with_capacity(8+data.len());
+		frame.extend_from_slice(&self.transaction_id.to_be_bytes());
+		frame.extend_from_slice(&[0, 0]); // Protocol id
+		frame.extend_from_slice(&((data.len()+2) as u16).to_be_bytes());
+		frame.push(unit);
+		frame.push(function);
+		frame.extend_from_slice(data);
+		let io_error = |err:std::io::Error| OpenHemsError::new(format!("Modbus communication error : {err}"));
+		self.stream.write_all(&frame).map_err(io_error)?;
+		let mut header = [0u8; 7];
+		self.stream.read_exact(&mut header).map_err(io_error)?;
+		let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+		if length<2 || u16::from_be_bytes([header[0], header[1]])!=self.transaction_id {
+			return Err(OpenHemsError::new("Invalid Modbus response header.".to_string()));
+		}
+		let mut pdu = vec![0u8; length-1];
+		self.stream.read_exact(&mut pdu).map_err(io_error)?;
+		if pdu[0]==function|0x80 {
+			return Err(OpenHemsError::new(format!("Modbus exception {} for function {function}.", pdu.get(1).unwrap_or(&0))));
+		}
+		if pdu[0]!=function {
+			return Err(OpenHemsError::new(format!("Unexpected Modbus function {} in response.", pdu[0])));
+		}
+		Ok(pdu[1..].to_vec())
+	}
+	fn read_bits(&mut self, unit:u8, function:u8, address:u16, count:u16) -> ResultOpenHems<Vec<bool>> {
+		let mut data = address.to_be_bytes().to_vec();
+		data.extend_from_slice(&count.to_be_bytes());
+		let response = self.request(unit, function, &data)?;
+		let bytes = response.get(1..).unwrap_or(&[]);
+		if bytes.len()*8<count as usize {
+			return Err(OpenHemsError::new("Modbus response too short.".to_string()));
+		}
+		Ok((0..count as usize).map(|i| bytes[i/8]>>(i%8) & 1==1).collect())
+	}
+	fn read_registers(&mut self, unit:u8, function:u8, address:u16, count:u16) -> ResultOpenHems<Vec<u16>> {
+		let mut data = address.to_be_bytes().to_vec();
+		data.extend_from_slice(&count.to_be_bytes());
+		let response = self.request(unit, function, &data)?;
+		let bytes = response.get(1..).unwrap_or(&[]);
+		if bytes.len()<2*count as usize {
+			return Err(OpenHemsError::new("Modbus response too short.".to_string()));
+		}
+		Ok(bytes.chunks(2).take(count as usize).map(|w| u16::from_be_bytes([w[0], w[1]])).collect())
+	}
+	fn write_coil(&mut self, unit:u8, address:u16, value:bool) -> ResultOpenHems<()> {
+		let mut data = address.to_be_bytes().to_vec();
+		data.extend_from_slice(if value {&[0xFF, 0x00]} else {&[0x00, 0x00]});
+		self.request(unit, WRITE_SINGLE_COIL, &data)?;
+		Ok(())
+	}
+	fn write_registers(&mut self, unit:u8, address:u16, words:&[u16]) -> ResultOpenHems<()> {
+		let mut data = address.to_be_bytes().to_vec();
+		if words.len()==1 {
+			data.extend_from_slice(&words[0].to_be_bytes());
+			self.request(unit, WRITE_SINGLE_REGISTER, &data)?;
+		} else {
+			data.extend_from_slice(&(words.len() as u16).to_be_bytes());
+			data.push((words.len()*2) as u8);
+			for word in words {
+				data.extend_from_slice(&word.to_be_bytes());
+			}
+			self.request(unit, WRITE_MULTIPLE_REGISTERS, &data)?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RegisterKind {
+	Holding,
+	Input,
+	Coil,
+	Discrete,
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RegisterType {
+	U16, I16, U32, I32, U64, I64, F32, F64,
+}
+impl RegisterType {
+	fn from_str(value:&str) -> ResultOpenHems<RegisterType> {
+		match value.to_lowercase().as_str() {
+			"u16" | "uint16" | "" => Ok(RegisterType::U16),
+			"i16" | "int16" => Ok(RegisterType::I16),
+			"u32" | "uint32" => Ok(RegisterType::U32),
+			"i32" | "int32" => Ok(RegisterType::I32),
+			"u64" | "uint64" => Ok(RegisterType::U64),
+			"i64" | "int64" => Ok(RegisterType::I64),
+			"f32" | "float32" => Ok(RegisterType::F32),
+			"f64" | "float64" => Ok(RegisterType::F64),
+			_ => Err(OpenHemsError::new(format!("Invalid Modbus register type '{value}'.")))
+		}
+	}
+	fn get_size(&self) -> u16 {
+		match self {
+			RegisterType::U16 | RegisterType::I16 => 1,
+			RegisterType::U32 | RegisterType::I32 | RegisterType::F32 => 2,
+			RegisterType::U64 | RegisterType::I64 | RegisterType::F64 => 4,
+		}
+	}
+}
+
+/// Byte order of multi-registers values, as in Modbus tools : ABCD (big endian), CDAB (word swap),
+/// BADC (byte swap) or DCBA (little endian).
+fn to_big_endian(words:&[u16], order:&str) -> Vec<u8> {
+	let mut bytes:Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+	match order {
+		"DCBA" => bytes.reverse(),
+		"BADC" => bytes.chunks_mut(2).for_each(|w| w.reverse()),
+		"CDAB" => {
+			bytes = bytes.chunks(2).rev().flatten().copied().collect();
+		}
+		_ => {}
+	}
+	bytes
+}
+fn from_big_endian(bytes:&[u8], order:&str) -> Vec<u16> {
+	// Each order is its own inverse.
+	let words:Vec<u16> = bytes.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect();
+	let bytes = to_big_endian(&words, order);
+	bytes.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect()
+}
+const BYTE_ORDERS: [&str; 4] = ["ABCD", "CDAB", "BADC", "DCBA"];
+
+/// A register (or coil) declared in 'modbus.registers'.
+#[derive(Clone, Debug)]
+struct ModbusRegister {
+	id: String,
+	device: String, // host:port
+	unit: u8,
+	address: u16,
+	kind: RegisterKind,
+	value_type: RegisterType,
+	scale: f64,
+	order: String,
+	on: f64, // Values written to switch a holding register
+	off: f64,
+}
+impl ModbusRegister {
+	fn from_conf(conf:&Yaml, default_host:&str, default_port:i32, default_unit:i32) -> ResultOpenHems<ModbusRegister> {
+		let conf = cast_utility::to_type_dict(conf);
+		let get_str = |key:&str, default:&str| conf.get(key)
+			.map(|v| cast_utility::to_type_str(v))
+			.unwrap_or(default.to_string());
+		let get_float = |key:&str, default:f64| -> ResultOpenHems<f64> {
+			match conf.get(key) {
+				Some(v) => Ok(cast_utility::try_to_type_float(v)? as f64),
+				None => Ok(default)
+			}
+		};
+		let get_int = |key:&str, default:i32| -> ResultOpenHems<i32> {
+			match conf.get(key) {
+				Some(v) => cast_utility::try_to_type_int(v),
+				None => Ok(default)
+			}
+		};
+		let id = get_str("id", "");
+		if id.is_empty() {
+			return Err(OpenHemsError::new("Modbus register need an 'id'.".to_string()));
+		}
+		let Some(address) = conf.get("address") else {
+			return Err(OpenHemsError::new(format!("Modbus register '{id}' need an 'address'.")));
+		};
+		let address = cast_utility::try_to_type_int(address)?;
+		let kind = match get_str("kind", "holding").to_lowercase().as_str() {
+			"holding" => RegisterKind::Holding,
+			"input" => RegisterKind::Input,
+			"coil" => RegisterKind::Coil,
+			"discrete" => RegisterKind::Discrete,
+			kind => {
+				return Err(OpenHemsError::new(format!(
+					"Invalid kind '{kind}' for Modbus register '{id}', use holding, input, coil or discrete."
+				)));
+			}
+		};
+		let order = get_str("order", "ABCD").to_uppercase();
+		if !BYTE_ORDERS.contains(&order.as_str()) {
+			return Err(OpenHemsError::new(format!("Invalid order '{order}' for Modbus register '{id}', use one of {BYTE_ORDERS:?}.")));
+		}
+		let unit = get_int("unit", default_unit)?;
+		let port = get_int("port", default_port)?;
+		if !(0..=0xFFFF).contains(&address) || !(0..=255).contains(&unit) {
+			return Err(OpenHemsError::new(format!("Invalid address or unit for Modbus register '{id}'.")));
+		}
+		Ok(ModbusRegister {
+			device: format!("{}:{port}", get_str("host", default_host)),
+			unit: unit as u8,
+			address: address as u16,
+			kind,
+			value_type: RegisterType::from_str(&get_str("type", "u16"))?,
+			scale: get_float("scale", 1.0)?,
+			order,
+			on: get_float("on", 1.0)?,
+			off: get_float("off", 0.0)?,
+			id,
+		})
+	}
+	fn decode(&self, words:&[u16]) -> f64 {
+		let b = to_big_endian(words, &self.order);
+		let raw = match self.value_type {
+			RegisterType::U16 => u16::from_be_bytes([b[0], b[1]]) as f64,
+			RegisterType::I16 => i16::from_be_bytes([b[0], b[1]]) as f64,
+			RegisterType::U32 => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64,
+			RegisterType::I32 => i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64,
+			RegisterType::F32 => f32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64,
+			RegisterType::U64 => u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f64,
+			RegisterType::I64 => i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f64,
+			RegisterType::F64 => f64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+		};
+		raw*self.scale
+	}
+	fn encode(&self, value:f64) -> Vec<u16> {
+		let raw = if self.scale!=0.0 {value/self.scale} else {value};
+		let bytes = match self.value_type {
+			RegisterType::U16 => (raw.round() as u16).to_be_bytes().to_vec(),
+			RegisterType::I16 => (raw.round() as i16).to_be_bytes().to_vec(),
+			RegisterType::U32 => (raw.round() as u32).to_be_bytes().to_vec(),
+			RegisterType::I32 => (raw.round() as i32).to_be_bytes().to_vec(),
+			RegisterType::F32 => (raw as f32).to_be_bytes().to_vec(),
+			RegisterType::U64 => (raw.round() as u64).to_be_bytes().to_vec(),
+			RegisterType::I64 => (raw.round() as i64).to_be_bytes().to_vec(),
+			RegisterType::F64 => raw.to_be_bytes().to_vec(),
+		};
+		from_big_endian(&bytes, &self.order)
+	}
+}
+
+/// Used by configuration check.
+pub fn check_register(conf:&Yaml) -> Option<String> {
+	ModbusRegister::from_conf(conf, "localhost", 502, 1).err().map(|err| err.message)
+}
+
+/// Network source polling Modbus TCP registers (inverters, energy meters...) and writing coils/registers to switch.
+#[derive(Clone)]
+pub struct ModbusUpdater {
+	registers: Vec<ModbusRegister>,
+	timeout: Duration,
+	clients: Rc<RefCell<HashMap<String, ModbusClient>>>, // By device
+	values: HashMap<String, (f64, DateTime<Local>)>,
+	cycle_id: u32,
+	last_update: Option<DateTime<Local>>,
+	stale: bool,
+}
+impl fmt::Debug for ModbusUpdater {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "ModbusUpdater({} registers)", self.registers.len())
+	}
+}
+impl ModbusUpdater {
+	pub fn new(configurator:&ConfigurationManager) -> ResultOpenHems<ModbusUpdater> {
+		let host = configurator.get_as_str("modbus.host");
+		let port = configurator.get_as_int("modbus.port");
+		let unit = configurator.get_as_int("modbus.unit");
+		let registers = configurator.get_as_list("modbus.registers").into_iter()
+			.map(|conf| ModbusRegister::from_conf(conf, &host, port, unit))
+			.collect::<ResultOpenHems<Vec<ModbusRegister>>>()?;
+		Ok(ModbusUpdater {
+			registers,
+			timeout: Duration::from_secs(configurator.get_as_int("modbus.timeout").max(1) as u64),
+			clients: Rc::new(RefCell::new(HashMap::new())),
+			values: HashMap::new(),
+			cycle_id: 0,
+			last_update: None,
+			stale: false,
+		})
+	}
+	// Run a request on the device connection, reconnect once if it fails.
+	fn with_client<T>(&self, device:&str, mut f:impl FnMut(&mut ModbusClient) -> ResultOpenHems<T>) -> ResultOpenHems<T> {
+		let mut clients = self.clients.borrow_mut();
+		if let Some(client) = clients.get_mut(device) {
+			match f(client) {
+				Ok(value) => {
+					return Ok(value);
+				}
+				Err(err) => {
+					log::debug!("Modbus '{device}' : {}, reconnect.", err.message);
+					clients.remove(device);
+				}
+			}
+		}
+		let mut client = ModbusClient::connect(device, self.timeout)?;
+		let value = f(&mut client);
+		if value.is_ok() {
+			clients.insert(device.to_string(), client);
+		}
+		value
+	}
+	fn read(&self, register:&ModbusRegister) -> ResultOpenHems<f64> {
+		self.with_client(&register.device, |client| {
+			match register.kind {
+				RegisterKind::Coil | RegisterKind::Discrete => {
+					let function = if register.kind==RegisterKind::Coil {READ_COILS} else {READ_DISCRETE_INPUTS};
+					let bits = client.read_bits(register.unit, function, register.address, 1)?;
+					Ok(if bits[0] {1.0} else {0.0})
+				}
+				RegisterKind::Holding | RegisterKind::Input => {
+					let function = if register.kind==RegisterKind::Holding {READ_HOLDING_REGISTERS} else {READ_INPUT_REGISTERS};
+					let words = client.read_registers(register.unit, function, register.address, register.value_type.get_size())?;
+					Ok(register.decode(&words))
+				}
+			}
+		})
+	}
+	pub fn has_entity(&self, nameid:&str) -> bool {
+		self.registers.iter().any(|r| r.id==nameid)
+	}
+	pub fn is_stale(&self) -> bool {
+		self.stale
+	}
+	/// Write the coil, or the 'on'/'off' value in the holding register (the actuator set_value ones if any).
+	/// Return true once written.
+	pub fn call_actuator(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		let entity_id = actuator.get_entity_id();
+		let Some(register) = self.registers.iter().find(|r| r.id==entity_id) else {
+			return Err(OpenHemsError::new(format!("Unknown Modbus register '{entity_id}'.")));
+		};
+		log::info!("Switching '{entity_id}' to {}", if on {"on"} else {"off"});
+		match register.kind {
+			RegisterKind::Coil => {
+				self.with_client(&register.device, |client| client.write_coil(register.unit, register.address, on))?;
+			}
+			RegisterKind::Holding => {
+				let (on_value, off_value) = match actuator.get_service() {
+					ActuatorService::SetValue(on_value, off_value) => (*on_value as f64, *off_value as f64),
+					_ => (register.on, register.off)
+				};
+				let words = register.encode(if on {on_value} else {off_value});
+				self.with_client(&register.device, |client| client.write_registers(register.unit, register.address, &words))?;
+			}
+			_ => {
+				return Err(OpenHemsError::new(format!("Modbus register '{entity_id}' is read only.")));
+			}
+		}
+		Ok(true)
+	}
+	fn get_value(&self, nameid:&str) -> ResultOpenHems<f64> {
+		match self.values.get(nameid) {
+			Some((value, _)) => Ok(*value),
+			None => Err(OpenHemsError::new(format!("No value read yet for '{nameid}'.")))
+		}
+	}
+}
+
+impl HomeStateUpdater for ModbusUpdater {
+	fn default() -> Self {
+		ModbusUpdater {
+			registers: Vec::new(),
+			timeout: Duration::from_secs(5),
+			clients: Rc::new(RefCell::new(HashMap::new())),
+			values: HashMap::new(),
+			cycle_id: 0,
+			last_update: None,
+			stale: false,
+		}
+	}
+	fn init_network(&mut self) -> ResultOpenHems<bool> {
+		Ok(true)
+	}
+	/// Read all registers, failed ones keep their previous value (and date).
+	fn update_network(&mut self) -> ResultOpenHems<bool> {
+		self.cycle_id += 1;
+		let mut errors = Vec::new();
+		let now = Local::now();
+		for register in &self.registers {
+			match self.read(register) {
+				Ok(value) => {
+					log::debug!("Modbus : '{}' := {value}", register.id);
+					self.values.insert(register.id.clone(), (value, now));
+				}
+				Err(err) => {
+					log::error!("Fail read Modbus register '{}' : {}", register.id, err.message);
+					errors.push(err.message);
+				}
+			}
+		}
+		if errors.is_empty() || errors.len()<self.registers.len() {
+			self.stale = false;
+			self.last_update = Some(now);
+			Ok(true)
+		} else if self.last_update.is_some() {
+			self.stale = true;
+			Ok(false)
+		} else {
+			Err(OpenHemsError::new(errors.join(" ")))
+		}
+	}
+	fn register_entity(&mut self, nameid:&str) -> bool {
+		let known = self.has_entity(nameid);
+		if !known {
+			log::error!("Modbus register '{nameid}' is not declared in modbus.registers.");
+		}
+		known
+	}
+	fn get_entity_value_int(&self, nameid:&str) -> ResultOpenHems<i32> {
+		Ok(self.get_value(nameid)?.round() as i32)
+	}
+	fn get_entity_value_float(&self, nameid:&str) -> ResultOpenHems<f32> {
+		Ok(self.get_value(nameid)? as f32)
+	}
+	fn get_entity_value_str(&self, nameid:&str) -> ResultOpenHems<String> {
+		Ok(self.get_value(nameid)?.to_string())
+	}
+	fn get_entity_value_bool(&self, nameid:&str) -> ResultOpenHems<bool> {
+		let register = self.registers.iter().find(|r| r.id==nameid);
+		let value = self.get_value(nameid)?;
+		// A holding register is on when it has its 'on' value.
+		match register {
+			Some(r) if r.kind==RegisterKind::Holding || r.kind==RegisterKind::Input => Ok(value!=r.off),
+			_ => Ok(value!=0.0)
+		}
+	}
+	fn get_cycle_id(&self) -> u32 {
+		self.cycle_id
+	}
+	fn get_entity_last_update(&self, nameid:&str) -> Option<DateTime<Local>> {
+		self.values.get(nameid).map(|(_, date)| *date)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+	use std::sync::{Arc, Mutex};
+	use crate::configuration_manager;
+	use super::*;
+
+	// Modbus TCP simulator with 100 coils and 100 registers (holding and input are the same).
+	fn start_simulator(registers:Arc<Mutex<Vec<u16>>>, coils:Arc<Mutex<Vec<bool>>>) -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		std::thread::spawn(move || {
+			for stream in listener.incoming() {
+				let Ok(mut stream) = stream else {
+					continue;
+				};
+				let mut header = [0u8; 7];
+				while stream.read_exact(&mut header).is_ok() {
+					let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+					let mut pdu = vec![0u8; length-1];
+					stream.read_exact(&mut pdu).unwrap();
+					let address = u16::from_be_bytes([pdu[1], pdu[2]]) as usize;
+					let arg = u16::from_be_bytes([pdu[3], pdu[4]]);
+					let mut response = vec![pdu[0]];
+					match pdu[0] {
+						READ_COILS | READ_DISCRETE_INPUTS => {
+							let coils = coils.lock().unwrap();
+							let mut bytes = vec![0u8; (arg as usize).div_ceil(8)];
+							for i in 0..arg as usize {
+								if coils[address+i] {
+									bytes[i/8] |= 1<<(i%8);
+								}
+							}
+							response.push(bytes.len() as u8);
+							response.extend(bytes);
+						}
+						READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+							let registers = registers.lock().unwrap();
+							response.push((arg*2) as u8);
+							for i in 0..arg as usize {
+								response.extend(registers[address+i].to_be_bytes());
+							}
+						}
+						WRITE_SINGLE_COIL => {
+							coils.lock().unwrap()[address] = arg==0xFF00;
+							response.extend(&pdu[1..5]);
+						}
+						WRITE_SINGLE_REGISTER => {
+							registers.lock().unwrap()[address] = arg;
+							response.extend(&pdu[1..5]);
+						}
+						WRITE_MULTIPLE_REGISTERS => {
+							let mut registers = registers.lock().unwrap();
+							for i in 0..arg as usize {
+								registers[address+i] = u16::from_be_bytes([pdu[6+2*i], pdu[7+2*i]]);
+							}
+							response.extend(&pdu[1..5]);
+						}
+						_ => {
+							response = vec![pdu[0]|0x80, 1];
+						}
+					}
+					let mut frame = header[..4].to_vec();
+					frame.extend(((response.len()+1) as u16).to_be_bytes());
+					frame.push(header[6]);
+					frame.extend(response);
+					stream.write_all(&frame).unwrap();
+				}
+			}
+		});
+		port
+	}
+
+	#[test]
+	fn test_byte_order() {
+		let register = |order:&str| ModbusRegister {
+			id: String::from("r"), device: String::new(), unit: 1, address: 0, kind: RegisterKind::Holding,
+			value_type: RegisterType::I32, scale: 0.1, order: order.to_string(), on: 1.0, off: 0.0,
+		};
+		assert_eq!(register("ABCD").decode(&[0xFFFF, 0xFF38]), -20.0);
+		assert_eq!(register("CDAB").decode(&[0xFF38, 0xFFFF]), -20.0);
+		assert_eq!(register("DCBA").decode(&[0x38FF, 0xFFFF]), -20.0);
+		assert_eq!(register("BADC").decode(&[0xFFFF, 0x38FF]), -20.0);
+		for order in BYTE_ORDERS {
+			assert_eq!(register(order).decode(&register(order).encode(1234.5)), 1234.5);
+		}
+	}
+
+	#[test]
+	fn test_modbus_updater() -> ResultOpenHems<()> {
+		let registers = Arc::new(Mutex::new(vec![0u16; 100]));
+		let coils = Arc::new(Mutex::new(vec![false; 100]));
+		registers.lock().unwrap()[10..12].copy_from_slice(&[0x0001, 0x86A0]); // 100000
+		let port = start_simulator(registers.clone(), coils.clone());
+		let mut configurator = configuration_manager::get(None);
+		configurator.add_yaml_str("test.yaml", &format!("
+modbus:
+  host: 127.0.0.1
+  port: {port}
+  registers:
+    - {{id: sensor.inverter_power, address: 10, kind: input, type: u32, scale: 0.01}}
+    - {{id: switch.heater, address: 3, kind: coil}}
+    - {{id: number.charger_current, address: 20, type: i16, scale: 0.1, off: 6}}
+")).unwrap();
+		let mut updater = ModbusUpdater::new(&configurator)?;
+		assert!(updater.register_entity("sensor.inverter_power"));
+		assert!(updater.update_network()?);
+		assert_eq!(updater.get_entity_value_float("sensor.inverter_power")?, 1000.0);
+		assert!(!updater.get_entity_value_bool("switch.heater")?);
+		assert!(updater.call_actuator(&Actuator::new("switch.heater", None)?, true)?);
+		let conf = &yaml_rust2::YamlLoader::load_from_str("{on: 16, off: 6}").unwrap()[0];
+		assert!(updater.call_actuator(&Actuator::new("number.charger_current", Some(conf))?, true)?);
+		assert!(coils.lock().unwrap()[3]);
+		assert_eq!(registers.lock().unwrap()[20], 160);
+		updater.update_network()?;
+		assert!(updater.get_entity_value_bool("switch.heater")?);
+		assert!(updater.get_entity_value_bool("number.charger_current")?);
+		Ok(())
+	}
+}
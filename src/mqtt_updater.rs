@@ -0,0 +1,545 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use json::JsonValue;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use yaml_rust2::Yaml;
+use crate::cast_utility;
+use crate::configuration_manager::ConfigurationManager;
+use crate::error::{OpenHemsError, ResultOpenHems};
+use crate::home_assistant_api::{Actuator, HomeStateUpdater};
+use crate::network_updater::PublishedState;
+
+const CONNECT_TIMEOUT:Duration = Duration::from_secs(10);
+const RETAINED_DELAY:Duration = Duration::from_secs(2); // Wait retained states after connection
+const RECONNECT_DELAY:Duration = Duration::from_secs(2);
+
+/// An entity read from a MQTT topic, like Zigbee2MQTT 'zigbee2mqtt/plug' with path 'power'.
+#[derive(Clone, Debug)]
+struct MqttEntity {
+	id: String,
+	topic: String,
+	path: String, // JSON path in the payload, when "" the whole payload
+	command: String, // Topic to publish to switch it, when "" it can't be switched
+	on: String,
+	off: String,
+}
+impl MqttEntity {
+	fn from_conf(conf:&Yaml) -> ResultOpenHems<MqttEntity> {
+		let conf = cast_utility::to_type_dict(conf);
+		let get = |key:&str, default:&str| conf.get(key)
+			.map(|v| cast_utility::to_type_str(v))
+			.unwrap_or(default.to_string());
+		let entity = MqttEntity {
+			id: get("id", ""),
+			topic: get("topic", ""),
+			path: get("path", ""),
+			command: get("command", ""),
+			on: get("on", "ON"),
+			off: get("off", "OFF"),
+		};
+		if entity.id.is_empty() || entity.topic.is_empty() {
+			return Err(OpenHemsError::new("MQTT entity need an 'id' and a 'topic'.".to_string()));
+		}
+		if entity.topic.contains(['+', '#']) {
+			return Err(OpenHemsError::new(format!("MQTT entity '{}' : wildcards are not supported in topic.", entity.id)));
+		}
+		Ok(entity)
+	}
+}
+
+/// Used by configuration check.
+pub fn check_entity(conf:&Yaml) -> Option<String> {
+	MqttEntity::from_conf(conf).err().map(|err| err.message)
+}
+
+/// Extract a value with a path like 'ENERGY.Power' or 'sensors[0].value' ('$.' prefix is accepted).
+pub fn extract_json_path<'a>(value:&'a JsonValue, path:&str) -> Option<&'a JsonValue> {
+	let path = path.trim_start_matches('$').trim_start_matches('.');
+	let mut current = value;
+	if path.is_empty() {
+		return Some(current);
+	}
+	for part in path.split('.') {
+		let (name, indexes) = match part.find('[') {
+			Some(i) => (&part[..i], &part[i..]),
+			None => (part, "")
+		};
+		if !name.is_empty() {
+			current = &current[name];
+		}
+		for index in indexes.split('[').filter(|i| !i.is_empty()) {
+			let index:usize = index.trim_end_matches(']').parse().ok()?;
+			current = &current[index];
+		}
+		if current.is_null() {
+			return None;
+		}
+	}
+	Some(current)
+}
+
+// State shared with the connection thread.
+#[derive(Default)]
+struct Shared {
+	states: Mutex<HashMap<String, (JsonValue, DateTime<Local>)>>,
+	connected: AtomicBool,
+	discovered: Mutex<HashSet<String>>, // Discovery configs published on this connection
+	events: Mutex<Option<Sender<String>>>,
+	commands: Mutex<Vec<(String, String)>>, // (object_id, value) received on '<baseTopic>/<object_id>/set'
+}
+
+/// Network source reading states from MQTT topics (Zigbee2MQTT, Tasmota...) and publishing commands.
+#[derive(Clone)]
+pub struct MqttUpdater {
+	client: Client,
+	entities: Vec<MqttEntity>,
+	base_topic: String,
+	discovery_prefix: String, // When "", no Home-Assistant discovery
+	shared: Arc<Shared>,
+	cached: HashMap<String, (JsonValue, DateTime<Local>)>,
+	cycle_id: u32,
+	last_update: Option<DateTime<Local>>,
+	stale: bool,
+}
+impl fmt::Debug for MqttUpdater {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "MqttUpdater({})", self.base_topic)
+	}
+}
+
+impl MqttUpdater {
+	pub fn new(configurator:&ConfigurationManager) -> ResultOpenHems<MqttUpdater> {
+		let entities = configurator.get_as_list("mqtt.entities").into_iter()
+			.map(MqttEntity::from_conf)
+			.collect::<ResultOpenHems<Vec<MqttEntity>>>()?;
+		let base_topic = configurator.get_as_str("mqtt.baseTopic");
+		let mut options = MqttOptions::new(
+			configurator.get_as_str("mqtt.clientId"),
+			configurator.get_as_str("mqtt.host"),
+			configurator.get_as_int("mqtt.port") as u16
+		);
+		options.set_keep_alive(Duration::from_secs(configurator.get_as_int("mqtt.keepAlive").max(5) as u64));
+		let user = configurator.get_as_str("mqtt.user");
+		if !user.is_empty() {
+			options.set_credentials(user, configurator.get_as_str("mqtt.password"));
+		}
+		options.set_last_will(LastWill::new(format!("{base_topic}/status"), "offline", QoS::AtLeastOnce, true));
+		let (client, connection) = Client::new(options, 100);
+		let discovery_prefix = if configurator.get_as_bool("mqtt.discovery") {
+			configurator.get_as_str("mqtt.discoveryPrefix")
+		} else {
+			String::new()
+		};
+		let updater = MqttUpdater {
+			client,
+			entities,
+			base_topic,
+			discovery_prefix,
+			shared: Arc::new(Shared::default()),
+			cached: HashMap::new(),
+			cycle_id: 0,
+			last_update: None,
+			stale: false,
+		};
+		updater.run_connection(connection);
+		Ok(updater)
+	}
+	// Background thread keeping the connection (rumqttc reconnects on next iteration).
+	fn run_connection(&self, mut connection:Connection) {
+		let mut topics:HashMap<String, Vec<(String, String)>> = HashMap::new();
+		for entity in &self.entities {
+			topics.entry(entity.topic.clone()).or_default().push((entity.id.clone(), entity.path.clone()));
+		}
+		let client = self.client.clone();
+		let shared = self.shared.clone();
+		let status_topic = format!("{}/status", self.base_topic);
+		let base_topic = self.base_topic.clone();
+		std::thread::spawn(move || {
+			for event in connection.iter() {
+				match event {
+					Ok(Event::Incoming(Packet::ConnAck(_))) => {
+						log::info!("Connected to MQTT broker.");
+						let command_topic = format!("{base_topic}/+/set");
+						for topic in topics.keys().chain([&command_topic]) {
+							if let Err(err) = client.subscribe(topic.as_str(), QoS::AtLeastOnce) {
+								log::error!("Fail subscribe to '{topic}' : {err}");
+							}
+						}
+						if let Err(err) = client.publish(status_topic.as_str(), QoS::AtLeastOnce, true, "online") {
+							log::error!("Fail publish '{status_topic}' : {err}");
+						}
+						if let Ok(mut discovered) = shared.discovered.lock() {
+							discovered.clear();
+						}
+						shared.connected.store(true, Ordering::SeqCst);
+					}
+					Ok(Event::Incoming(Packet::Publish(publish))) => {
+						Self::on_message(&shared, &topics, &base_topic, &publish.topic, &publish.payload);
+					}
+					Ok(_) => {}
+					Err(err) => {
+						if shared.connected.swap(false, Ordering::SeqCst) {
+							log::warn!("MQTT connection lost : {err}");
+						} else {
+							log::debug!("MQTT connection : {err}");
+						}
+						sleep(RECONNECT_DELAY);
+					}
+				}
+			}
+		});
+	}
+	fn on_message(shared:&Shared, topics:&HashMap<String, Vec<(String, String)>>, base_topic:&str, topic:&str, payload:&[u8]) {
+		let payload = String::from_utf8_lossy(payload);
+		let command = topic.strip_prefix(base_topic)
+			.and_then(|t| t.strip_prefix('/'))
+			.and_then(|t| t.strip_suffix("/set"));
+		if let Some(object_id) = command {
+			log::info!("MQTT : set '{object_id}' to '{payload}'");
+			if let Ok(mut commands) = shared.commands.lock() {
+				commands.push((object_id.to_string(), payload.trim().to_string()));
+			}
+			Self::send_event(shared, object_id);
+			return;
+		}
+		let Some(entities) = topics.get(topic) else {
+			return;
+		};
+		let parsed = json::parse(&payload).ok();
+		let now = Local::now();
+		for (entity_id, path) in entities {
+			let value = if path.is_empty() {
+				JsonValue::from(payload.trim())
+			} else {
+				match parsed.as_ref().and_then(|p| extract_json_path(p, path)) {
+					Some(value) => value.clone(),
+					None => {
+						log::debug!("MQTT : no '{path}' in '{topic}' payload {payload}");
+						continue;
+					}
+				}
+			};
+			log::debug!("MQTT : '{entity_id}' := {value}");
+			if let Ok(mut states) = shared.states.lock() {
+				states.insert(entity_id.clone(), (value, now));
+			}
+			Self::send_event(shared, entity_id);
+		}
+	}
+	fn send_event(shared:&Shared, id:&str) {
+		if let Ok(events) = shared.events.lock() {
+			if let Some(events) = events.as_ref() {
+				let _ = events.send(id.to_string());
+			}
+		}
+	}
+	/// Values set from Home-Assistant on OpenHEMS selects (object_id, value), since last call.
+	pub fn take_commands(&self) -> Vec<(String, String)> {
+		self.shared.commands.lock()
+			.map(|mut commands| std::mem::take(&mut *commands))
+			.unwrap_or_default()
+	}
+	fn is_connected(&self) -> bool {
+		self.shared.connected.load(Ordering::SeqCst)
+	}
+	pub fn has_entity(&self, nameid:&str) -> bool {
+		self.entities.iter().any(|e| e.id==nameid)
+	}
+	pub fn is_stale(&self) -> bool {
+		self.stale
+	}
+	/// Receiver of changed entity ids.
+	pub fn start_events(&self) -> Receiver<String> {
+		let (sender, receiver) = channel();
+		if let Ok(mut events) = self.shared.events.lock() {
+			*events = Some(sender);
+		}
+		receiver
+	}
+	/// Publish the command payload, MQTT gives no confirmation so it returns false.
+	pub fn call_actuator(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		let entity_id = actuator.get_entity_id();
+		let Some(entity) = self.entities.iter().find(|e| e.id==entity_id) else {
+			return Err(OpenHemsError::new(format!("Unknown MQTT entity '{entity_id}'.")));
+		};
+		if entity.command.is_empty() {
+			return Err(OpenHemsError::new(format!("No 'command' topic for MQTT entity '{entity_id}'.")));
+		}
+		let payload = if on {&entity.on} else {&entity.off};
+		log::info!("Switching '{entity_id}' : publish '{payload}' to '{}'", entity.command);
+		self.client.publish(entity.command.as_str(), QoS::AtLeastOnce, false, payload.as_str())
+			.map_err(|err| OpenHemsError::new(format!("Fail publish to '{}' : {err}", entity.command)))?;
+		Ok(false)
+	}
+	fn publish(&self, topic:&str, payload:String) -> ResultOpenHems<()> {
+		self.client.publish(topic, QoS::AtLeastOnce, true, payload)
+			.map_err(|err| OpenHemsError::new(format!("Fail publish to '{topic}' : {err}")))
+	}
+	/// Publish OpenHEMS states on '<baseTopic>/<object_id>/state',
+	/// with Home-Assistant discovery configuration the first time. Selects are set on '<baseTopic>/<object_id>/set'.
+	pub fn publish_states(&mut self, states:&[PublishedState]) -> ResultOpenHems<()> {
+		if !self.is_connected() {
+			return Ok(());
+		}
+		for state in states {
+			let state_topic = format!("{}/{}/state", self.base_topic, state.object_id);
+			if !self.discovery_prefix.is_empty() {
+				let discovered = self.shared.discovered.lock()
+					.map(|d| d.contains(&state.object_id))
+					.unwrap_or(false);
+				if !discovered {
+					let mut config = json::object!{
+						"name": state.name.as_str(),
+						"unique_id": state.object_id.as_str(),
+						"object_id": state.object_id.as_str(),
+						"state_topic": state_topic.as_str(),
+						"availability_topic": format!("{}/status", self.base_topic),
+						"device": {"identifiers": ["openhems"], "name": "OpenHEMS"}
+					};
+					if !state.unit.is_empty() {
+						config["unit_of_measurement"] = state.unit.into();
+					}
+					if !state.options.is_empty() {
+						config["options"] = state.options.clone().into();
+					}
+					let component = match state.domain {
+						"binary_sensor" => {
+							config["payload_on"] = "on".into();
+							config["payload_off"] = "off".into();
+							"binary_sensor"
+						}
+						"select" => {
+							config["command_topic"] = format!("{}/{}/set", self.base_topic, state.object_id).into();
+							"select"
+						}
+						_ => {
+							if !state.options.is_empty() {
+								config["device_class"] = "enum".into();
+							}
+							"sensor"
+						}
+					};
+					if let Err(err) = self.publish(&format!("{}/{component}/{}/config", self.discovery_prefix, state.object_id), config.dump()) {
+						log::error!("{}", err.message);
+						continue;
+					}
+					if let Ok(mut discovered) = self.shared.discovered.lock() {
+						discovered.insert(state.object_id.clone());
+					}
+				}
+			}
+			if let Err(err) = self.publish(&state_topic, state.value.clone()) {
+				log::error!("{}", err.message);
+			}
+		}
+		Ok(())
+	}
+	fn get_entity_value(&self, entity_id:&str) -> ResultOpenHems<&JsonValue> {
+		let Some((value, _)) = self.cached.get(entity_id) else {
+			return Err(OpenHemsError::new(format!("No value received yet for '{entity_id}'.")));
+		};
+		match value.as_str() {
+			Some("unavailable") | Some("unknown") => {
+				Err(OpenHemsError::new(format!("Entity '{entity_id}' is {value}.")))
+			}
+			_ => Ok(value)
+		}
+	}
+	fn get_entity_number(&self, entity_id:&str) -> ResultOpenHems<f64> {
+		let v = self.get_entity_value(entity_id)?;
+		let value = match v.as_str() {
+			Some(s) => s.trim().parse::<f64>().ok(),
+			None => v.as_f64()
+		};
+		value.ok_or(OpenHemsError::new(format!("Value of '{entity_id}' can not be parsed as number : {v}")))
+	}
+}
+
+impl HomeStateUpdater for MqttUpdater {
+	fn default() -> Self {
+		let (client, _) = Client::new(MqttOptions::new("openhems", "localhost", 1883), 10);
+		MqttUpdater {
+			client,
+			entities: Vec::new(),
+			base_topic: String::from("openhems"),
+			discovery_prefix: String::new(),
+			shared: Arc::new(Shared::default()),
+			cached: HashMap::new(),
+			cycle_id: 0,
+			last_update: None,
+			stale: false,
+		}
+	}
+	/// Wait for the connection and retained states.
+	fn init_network(&mut self) -> ResultOpenHems<bool> {
+		let start = Instant::now();
+		while !self.is_connected() {
+			if start.elapsed()>CONNECT_TIMEOUT {
+				return Err(OpenHemsError::new("Fail connect to MQTT broker (check mqtt.host and mqtt.port).".to_string()));
+			}
+			sleep(Duration::from_millis(100));
+		}
+		sleep(RETAINED_DELAY);
+		Ok(true)
+	}
+	fn update_network(&mut self) -> ResultOpenHems<bool> {
+		self.cycle_id += 1;
+		if let Ok(states) = self.shared.states.lock() {
+			self.cached = states.clone();
+		}
+		if self.is_connected() {
+			self.stale = false;
+			self.last_update = Some(Local::now());
+			Ok(true)
+		} else if let Some(last_update) = self.last_update {
+			log::error!("Not connected to MQTT broker : keep running with values from {last_update}.");
+			self.stale = true;
+			Ok(false)
+		} else {
+			Err(OpenHemsError::new("Not connected to MQTT broker.".to_string()))
+		}
+	}
+	fn notify(&self, message:&str) -> ResultOpenHems<bool> {
+		let topic = format!("{}/notification", self.base_topic);
+		self.client.publish(topic.as_str(), QoS::AtLeastOnce, false, message)
+			.map_err(|err| OpenHemsError::new(format!("Fail publish to '{topic}' : {err}")))?;
+		Ok(true)
+	}
+	fn register_entity(&mut self, nameid:&str) -> bool {
+		let known = self.has_entity(nameid);
+		if !known {
+			log::error!("MQTT entity '{nameid}' is not declared in mqtt.entities.");
+		}
+		known
+	}
+	fn get_entity_value_int(&self, nameid:&str) -> ResultOpenHems<i32> {
+		Ok(self.get_entity_number(nameid)?.round() as i32)
+	}
+	fn get_entity_value_float(&self, nameid:&str) -> ResultOpenHems<f32> {
+		Ok(self.get_entity_number(nameid)? as f32)
+	}
+	fn get_entity_value_str(&self, nameid:&str) -> ResultOpenHems<String> {
+		let v = self.get_entity_value(nameid)?;
+		Ok(v.as_str().map(|s| s.to_string()).unwrap_or(v.dump()))
+	}
+	fn get_entity_value_bool(&self, nameid:&str) -> ResultOpenHems<bool> {
+		let v = self.get_entity_value(nameid)?;
+		if let Some(value) = v.as_bool() {
+			Ok(value)
+		} else if let Some(value) = v.as_f64() {
+			Ok(value!=0.0)
+		} else if let Some(value) = v.as_str() {
+			let value = value.to_lowercase();
+			Ok(!["0", "false", "off"].iter().any(|&s| s==value))
+		} else {
+			Err(OpenHemsError::new(format!("Value of '{nameid}' can not be parsed as bool : {v}")))
+		}
+	}
+	fn get_cycle_id(&self) -> u32 {
+		self.cycle_id
+	}
+	/// Last update while connected to the broker : devices publish only on change, a steady value is not stale.
+	fn get_entity_last_update(&self, _nameid:&str) -> Option<DateTime<Local>> {
+		self.last_update
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::configuration_manager;
+	use super::*;
+
+	fn start_broker() -> u16 {
+		let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+		let config:rumqttd::Config = serde_json::from_value(serde_json::json!({
+			"id": 0,
+			"router": {"max_connections": 10, "max_outgoing_packet_count": 200,
+				"max_segment_size": 104857600, "max_segment_count": 10},
+			"v4": {"1": {"name": "v4-1", "listen": format!("127.0.0.1:{port}"), "next_connection_delay_ms": 1,
+				"connections": {"connection_timeout_ms": 60000, "max_payload_size": 20480,
+					"max_inflight_count": 100, "dynamic_filters": true}}}
+		})).unwrap();
+		std::thread::spawn(move || {
+			rumqttd::Broker::new(config).start().unwrap();
+		});
+		port
+	}
+
+	#[test]
+	fn test_json_path() {
+		let value = json::parse(r#"{"ENERGY": {"Power": 120}, "sensors": [{"value": 1}, {"value": 2}]}"#).unwrap();
+		assert_eq!(extract_json_path(&value, "ENERGY.Power"), Some(&JsonValue::from(120)));
+		assert_eq!(extract_json_path(&value, "$.sensors[1].value"), Some(&JsonValue::from(2)));
+		assert_eq!(extract_json_path(&value, "sensors[2].value"), None);
+	}
+
+	#[test]
+	fn test_mqtt_updater() -> ResultOpenHems<()> {
+		let port = start_broker();
+		let mut configurator = configuration_manager::get(None);
+		configurator.add_yaml_str("test.yaml", &format!("
+server:
+  network: mqtt
+mqtt:
+  host: 127.0.0.1
+  port: {port}
+  entities:
+    - {{id: sensor.plug_power, topic: zigbee2mqtt/plug, path: power}}
+    - {{id: switch.plug, topic: zigbee2mqtt/plug, path: state, command: zigbee2mqtt/plug/set}}
+")).unwrap();
+		// Device side
+		let (device, mut connection) = Client::new(MqttOptions::new("device", "127.0.0.1", port), 10);
+		device.subscribe("zigbee2mqtt/plug/set", QoS::AtLeastOnce).unwrap();
+		device.subscribe("homeassistant/#", QoS::AtLeastOnce).unwrap();
+		device.subscribe("openhems/+/state", QoS::AtLeastOnce).unwrap();
+		device.publish("zigbee2mqtt/plug", QoS::AtLeastOnce, true, r#"{"power": 12.5, "state": "ON"}"#).unwrap();
+		let (received_sender, received) = channel();
+		std::thread::spawn(move || {
+			for event in connection.iter() {
+				if let Ok(Event::Incoming(Packet::Publish(publish))) = event {
+					let _ = received_sender.send((publish.topic.clone(), String::from_utf8_lossy(&publish.payload).to_string()));
+				}
+			}
+		});
+		sleep(Duration::from_millis(500));
+
+		let mut updater = MqttUpdater::new(&configurator)?;
+		updater.init_network()?;
+		assert!(updater.register_entity("sensor.plug_power"));
+		assert!(!updater.register_entity("sensor.unknown"));
+		assert!(updater.update_network()?);
+		assert_eq!(updater.get_entity_value_float("sensor.plug_power")?, 12.5);
+		assert!(updater.get_entity_value_bool("switch.plug")?);
+
+		let actuator = Actuator::new("switch.plug", None)?;
+		assert!(!updater.call_actuator(&actuator, false)?);
+		updater.publish_states(&[
+			PublishedState::sensor("openhems_plug_schedule", "plug schedule", String::from("3600"), "s"),
+			PublishedState::binary_sensor("openhems_stale", "stale data", false),
+			PublishedState::select("openhems_plug_mode", "plug mode", "auto", &["auto", "on", "off"]),
+		])?;
+		let mut topics = HashMap::new();
+		while let Ok((topic, payload)) = received.recv_timeout(Duration::from_secs(2)) {
+			topics.insert(topic, payload);
+		}
+		assert_eq!(topics.get("zigbee2mqtt/plug/set").map(|s| s.as_str()), Some("OFF"));
+		let config = json::parse(&topics["homeassistant/sensor/openhems_plug_schedule/config"]).unwrap();
+		assert_eq!(config["state_topic"], "openhems/openhems_plug_schedule/state");
+		assert!(topics.contains_key("homeassistant/binary_sensor/openhems_stale/config"));
+		assert_eq!(topics.get("openhems/openhems_stale/state").map(|s| s.as_str()), Some("off"));
+		// Mode set from Home-Assistant
+		let config = json::parse(&topics["homeassistant/select/openhems_plug_mode/config"]).unwrap();
+		assert_eq!(config["command_topic"], "openhems/openhems_plug_mode/set");
+		device.publish("openhems/openhems_plug_mode/set", QoS::AtLeastOnce, false, "on").unwrap();
+		sleep(Duration::from_millis(500));
+		assert_eq!(updater.take_commands(), vec![(String::from("openhems_plug_mode"), String::from("on"))]);
+		assert!(updater.take_commands().is_empty());
+		Ok(())
+	}
+}
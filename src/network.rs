@@ -3,13 +3,22 @@ use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Local, NaiveDate};
 use yaml_rust2::Yaml;
 use std::fmt::{self, Display};
 use crate::configuration_manager::ConfigurationManager;
 use crate::contract::Contract;
+use crate::cycle::CycleDetector;
 use crate::error::{OpenHemsError, ResultOpenHems};
 use crate::node::{self, Node};
-use crate::home_assistant_api::{HomeStateUpdater,HomeAssistantAPI};
+use crate::feeder::Feeder;
+use crate::home_assistant_api::{Actuator, HomeStateUpdater, HomeAssistantAPI};
+use crate::modbus_updater::ModbusUpdater;
+use crate::mqtt_updater::MqttUpdater;
+use crate::notification::{Notifier, Severity};
+use crate::network_updater::{NetworkSource, NetworkUpdater, PublishedState};
+use crate::schedule::{DeadlineStatus, Program, ScheduleMode};
+use crate::teleinfo::Teleinfo;
 use crate::cast_utility;
 use crate::time::HoursRanges;
 use crate::web::AppState;
@@ -89,23 +98,44 @@ impl<'a, 'b:'a> NodesHeap {
 			heap: self,
 		}
 	}
-	pub fn set_switch(& mut self, nameid:&str, updater:Rc<RefCell<HomeAssistantAPI>>, 
+	pub fn set_switch(& mut self, nameid:&str, updater:Rc<RefCell<NetworkUpdater>>, 
 				node_conf:&HashMap<String, &Yaml>, appstate:&mut AppState
 			) -> ResultOpenHems<()> {
 		// println!("set_switch({nameid})");
 		let priority = HomeAssistantAPI::get_feeder_const_int(node_conf, "priority", 50);
 		let strategy_nameid = HomeAssistantAPI::get_feeder_const_str(node_conf, "strategy", "default");
 		let base = HomeAssistantAPI::get_nodebase(updater, nameid, node_conf)?;
-		let switch = node::get_switch(base, priority as u32, &strategy_nameid, appstate)?;
+		let actuator = match (&base.is_on, node_conf.get("actuator")) {
+			(Feeder::Source(feeder), actuator) => Some(Actuator::new(feeder.get_nameid(), actuator.copied())?),
+			(Feeder::Const(_), Some(_)) => {
+				return Err(OpenHemsError::new("an 'actuator' can not switch a constant 'isOn'".to_string()));
+			}
+			// State guessed from the power (or constant), the actuator entity must be given.
+			(_, Some(actuator)) => Some(Actuator::new("", Some(actuator))?),
+			_ => None
+		};
+		let failsafe = node::Failsafe::from_str(&HomeAssistantAPI::get_feeder_const_str(node_conf, "failsafe", "keep"))?;
+		let running_power = HomeAssistantAPI::get_feeder_const_float(node_conf, "runningPower", 0.0);
+		let cycle = match node_conf.get("cycle") {
+			Some(conf) => CycleDetector::from_conf(conf)?,
+			None => None
+		};
+		let programs = match node_conf.get("programs") {
+			Some(Yaml::Array(programs)) => programs.iter().map(Program::from_conf).collect::<ResultOpenHems<_>>()?,
+			_ => Vec::new()
+		};
+		let mut switch = node::get_switch(base, priority as u32, &strategy_nameid, actuator, failsafe, running_power, appstate)?;
+		switch.get_schedule().set_programs(programs);
+		switch.set_cycle(cycle);
 		self.switch.push(switch);
 		log::debug!("set_switch({nameid}) : Ok");
 		Ok(())
 	}
-	pub fn set_publicpowergrid(& mut self, nameid:&str, updater:Rc<RefCell<HomeAssistantAPI>>, node_conf:&HashMap<String, &Yaml>)  -> ResultOpenHems<()> {
+	pub fn set_publicpowergrid(& mut self, nameid:&str, updater:Rc<RefCell<NetworkUpdater>>, node_conf:&HashMap<String, &Yaml>)  -> ResultOpenHems<()> {
 		// println!("set_publicpowergrid()");
-		let base = HomeAssistantAPI::get_nodebase(updater, nameid, node_conf)?;
+		let base = HomeAssistantAPI::get_nodebase(Rc::clone(&updater), nameid, node_conf)?;
 		if let Some(contract_conf) = node_conf.get("contract") {
-			let contract = Contract::get_from_conf(contract_conf)?;
+			let contract = Contract::get_from_conf(contract_conf, updater)?;
 			let node = node::get_publicpowergrid(base, contract)?;
 			log::debug!("set_publicpowergrid({nameid}) : Ok");
 			self.publicpowergrid = Some(node);
@@ -146,11 +176,14 @@ impl<'a, 'b:'a> NodesHeap {
 
 #[derive(Clone, Debug)]
 pub struct Network {
-    updater: Rc<RefCell<HomeAssistantAPI>>,
+    updater: Rc<RefCell<NetworkUpdater>>,
     nodes: NodesHeap,
     _margin_power_on: f32,
 	_margin_power_on_cache_id: u32,
 	errors: Vec<String>,
+	daily_cost: f32, // Cost of the energy bought on the grid today
+	daily_cost_day: Option<NaiveDate>,
+	notifier: Notifier,
 }
 impl<'a, 'b:'a> Display for Network {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -177,35 +210,30 @@ impl Network
 	pub fn new(configurator:&ConfigurationManager) -> ResultOpenHems<Network> {
 		let margin_power_on = 0.0;
 		let margin_power_on_cache_id = 0;
-		let updater:HomeAssistantAPI;
 		let network_source = configurator.get_as_str("server.network");
-		match network_source.as_str() {
-			"homeassistant" => {
-				// println!("Network: HomeAssistantAPI");
-				updater = HomeAssistantAPI::new(configurator)?;
-			}
-			"fake" => {
-				println!("TODO : Network: FakeNetwork");
-				// let network_updater = FakeNetwork(configurator)
-				todo!()
-			}
+		let source = match network_source.as_str() {
+			"homeassistant" => NetworkSource::HomeAssistant(Box::new(HomeAssistantAPI::new(configurator)?)),
+			"mqtt" => NetworkSource::Mqtt(MqttUpdater::new(configurator)?),
+			"modbus" => NetworkSource::Modbus(ModbusUpdater::new(configurator)?),
 			_ => {
-				return Err(OpenHemsError::new("Invalid server.network configuration '{networkSource}'".to_string()));
+				return Err(OpenHemsError::new(format!("Invalid server.network configuration '{network_source}'.")));
 			}
-		}
+		};
 		let network = Network {
-			updater: Rc::new(RefCell::new(updater)),
+			updater: Rc::new(RefCell::new(NetworkUpdater::new(source, Teleinfo::new(configurator)?))),
 			nodes: NodesHeap::new(),
 			_margin_power_on: margin_power_on,
 			_margin_power_on_cache_id: margin_power_on_cache_id,
-			errors: Vec::new()
+			errors: Vec::new(),
+			daily_cost: 0.0,
+			daily_cost_day: None,
+			notifier: Notifier::new(configurator)?,
 		};
 		Ok(network)
 	}
-	pub fn set_nodes(&mut self, configurator:&ConfigurationManager, appstate:&mut AppState) -> () {
+	pub fn set_nodes(&mut self, configurator:&ConfigurationManager, appstate:&mut AppState) -> ResultOpenHems<()> {
 		let nodes_conf = configurator.get_as_list("network.nodes");
-		let count = 0;
-		for node_c in nodes_conf {
+		for (count, node_c) in nodes_conf.into_iter().enumerate() {
 			let node_conf: HashMap<String, &Yaml> = cast_utility::to_type_dict(node_c);
 			if let Some(class) = node_conf.get("class") {
 				let classname = cast_utility::to_type_str(class);
@@ -232,15 +260,23 @@ impl Network
 						}
 					},
 					_ => {
-						let message = format!("Unknwon class '{classname}'");
+						let message = format!("Unknwon class '{classname}' for node '{nameid}'.");
 						log::error!("ERROR {}",&message);
+						self.errors.push(message);
 					}
 				}
 			} else {
-				log::error!("Missing classname for node.");
+				let message = String::from("Missing classname for node.");
+				log::error!("ERROR {}",&message);
+				self.errors.push(message);
 			}
 		}
 		println!("Nodes:{:?}", self.nodes);
+		if self.errors.is_empty() {
+			Ok(())
+		} else {
+			Err(OpenHemsError::new(self.errors.join(" ")))
+		}
 	}
 	pub fn get_hours_ranges(&self) -> ResultOpenHems<&HoursRanges> {
 		if let Some(power) = self.nodes.get_publicpowergrid() {
@@ -250,11 +286,171 @@ impl Network
 			Err(OpenHemsError::new("Need a public power grid for hours ranges but there is not.".to_string()))
 		}
 	}
+	/// Offpeak status (and Tempo colour) given by the meter through the contract 'period', None to use hours ranges.
+	pub fn get_tariff(&self) -> Option<(bool, Option<String>)> {
+		let contract = self.nodes.get_publicpowergrid().as_ref()?.get_contract();
+		Some((contract.is_offpeak()?, contract.get_color()))
+	}
 	pub fn update(&mut self) -> ResultOpenHems<bool> {
 		let mut updater = self.updater.borrow_mut();
 		updater.update_network()
 	}
-	pub fn notify(&self, message:&str) -> ResultOpenHems<bool> {
-		self.updater.borrow().notify(message)
+	/// True when values come from a previous update (Home-Assistant is unreachable).
+	pub fn is_stale(&self) -> bool {
+		self.updater.borrow().is_stale()
+	}
+	/// Receiver of Home-Assistant changes, if events are enabled.
+	pub fn start_events(&self) -> Option<std::sync::mpsc::Receiver<String>> {
+		self.updater.borrow().start_events()
+	}
+	/// Price of the current hours range of the grid contract.
+	pub fn get_current_price(&self, now:DateTime<Local>) -> Option<f32> {
+		let hoursranges = self.get_hours_ranges().ok()?;
+		hoursranges.check_range(now).ok().map(|range| range.cost)
+	}
+	/// Add the cost of the energy bought on the grid during 'duration' seconds. Reset each day.
+	pub fn update_daily_cost(&mut self, now:DateTime<Local>, duration:u32) {
+		if self.daily_cost_day!=Some(now.date_naive()) {
+			self.daily_cost = 0.0;
+			self.daily_cost_day = Some(now.date_naive());
+		}
+		// Energy really bought, not smoothed.
+		let power = self.nodes.get_publicpowergrid().clone()
+			.ok_or_else(|| OpenHemsError::new("No public power grid.".to_string()))
+			.and_then(|mut grid| grid.get_raw_power());
+		let (Some(price), Ok(power)) = (self.get_current_price(now), power) else {
+			return;
+		};
+		if power>0.0 {
+			self.daily_cost += power*(duration as f32)/3_600_000.0*price;
+		}
+	}
+	/// Count 'duration' seconds on schedules of running switches. Return true if some changed.
+	pub fn decrement_schedules(&mut self, duration:u32) -> bool {
+		let mut changed = false;
+		for switch in self.nodes.get_all_switch_mut("") {
+			match switch.decrement_schedule(duration) {
+				Ok(done) => changed |= done,
+				Err(err) => log::warn!("Fail count schedule of '{}' : {}", switch.get_id(), err.message),
+			}
+		}
+		changed
+	}
+	/// Follow appliances cycles. Return true if some schedules ended with their cycle.
+	pub fn update_cycles(&mut self, now:DateTime<Local>) -> bool {
+		let mut changed = false;
+		for switch in self.nodes.get_all_switch_mut("") {
+			match switch.update_cycle(now) {
+				Ok(done) => changed |= done,
+				Err(err) => log::warn!("Fail follow cycle of '{}' : {}", switch.get_id(), err.message),
+			}
+		}
+		changed
+	}
+	/// Switch on schedules which would miss their timeout otherwise (even in peak hours),
+	/// 'margin' is the delay until the next check. Return the number of forced switches.
+	pub fn enforce_deadlines(&self, now:DateTime<Local>, margin:u32) -> usize {
+		let mut nb = 0;
+		for switch in self.nodes.get_all_switch("") {
+			let (status, forced, timeout) = {
+				let schedule = switch.get_schedule();
+				(schedule.get_deadline_status(now, margin), schedule.is_forced(), *schedule.get_timeout())
+			};
+			match status {
+				DeadlineStatus::AtRisk => {
+					if !forced {
+						let message = format!("Switch on '{}' to end its schedule before {}.", switch.get_id(), timeout.format("%H:%M"));
+						log::warn!("{message}");
+						if let Err(err) = self.notify(Severity::Warning, &message) {
+							log::error!("Fail notify : {}", err.message);
+						}
+					}
+					switch.get_schedule().force(now);
+					if let Err(err) = switch.switch(true) {
+						log::error!("Fail switch on '{}' for its deadline : {}", switch.get_id(), err.message);
+					}
+					nb += 1;
+				}
+				// Its latest start is passed (set too late, or no more offpeak hours) : as soon as possible.
+				DeadlineStatus::Missed if now<timeout => {
+					if !forced {
+						let message = format!("Switch on '{}' now, its schedule can not end before {}.", switch.get_id(), timeout.format("%H:%M"));
+						log::warn!("{message}");
+						if let Err(err) = self.notify(Severity::Warning, &message) {
+							log::error!("Fail notify : {}", err.message);
+						}
+						switch.get_schedule().force(now);
+					}
+					if let Err(err) = switch.switch(true) {
+						log::error!("Fail switch on '{}' for its deadline : {}", switch.get_id(), err.message);
+					}
+					nb += 1;
+				}
+				DeadlineStatus::Missed if forced => {
+					log::warn!("Schedule of '{}' has missed its timeout {}.", switch.get_id(), timeout.format("%H:%M"));
+					switch.get_schedule().set_forced(false);
+				}
+				_ => {
+					switch.get_schedule().set_forced(false);
+				}
+			}
+		}
+		nb
+	}
+	/// Set override modes changed from Home-Assistant (MQTT selects). Return true if some changed.
+	pub fn apply_commands(&self) -> bool {
+		let mut changed = false;
+		for (object_id, value) in self.updater.borrow().take_commands() {
+			let switch = self.nodes.get_all_switch("").iter()
+				.find(|switch| PublishedState::slugify(&format!("openhems_{}_mode", switch.get_id()))==object_id);
+			let Some(switch) = switch else {
+				log::warn!("Ignore '{value}' set on unknown '{object_id}'.");
+				continue;
+			};
+			match ScheduleMode::from_str(&value) {
+				Ok(mode) => {
+					log::info!("Set mode of '{}' to '{value}' from Home-Assistant.", switch.get_id());
+					switch.get_schedule().set_mode(mode);
+					changed = true;
+				}
+				Err(err) => log::warn!("{}", err.message),
+			}
+		}
+		changed
+	}
+	/// Publish OpenHEMS own states (schedules, override modes, price...) through the network source,
+	/// with 'states' given by the server (strategies decisions).
+	pub fn publish_states(&self, now:DateTime<Local>, mut states:Vec<PublishedState>, stale:bool) -> ResultOpenHems<()> {
+		for switch in self.nodes.get_all_switch("") {
+			let (id, name) = (switch.get_id(), switch.get_name());
+			let schedule = switch.get_schedule();
+			states.push(PublishedState::sensor(&format!("openhems_{id}_schedule"), &format!("{name} schedule"),
+				schedule.get_remaining_duration().to_string(), "s"));
+			states.push(PublishedState::select(&format!("openhems_{id}_mode"), &format!("{name} mode"),
+				schedule.get_mode().as_str(), &ScheduleMode::OPTIONS));
+			if let Some(cycle) = switch.get_cycle_state() {
+				states.push(PublishedState::sensor(&format!("openhems_{id}_cycle"), &format!("{name} cycle"),
+					cycle.as_str().to_string(), ""));
+			}
+		}
+		if let Some(price) = self.get_current_price(now) {
+			states.push(PublishedState::sensor("openhems_price", "current price", price.to_string(), "€/kWh"));
+			states.push(PublishedState::sensor("openhems_daily_cost", "daily cost", format!("{:.2}", self.daily_cost), "€"));
+		}
+		if let Ok(hoursranges) = self.get_hours_ranges() {
+			let offpeak = match self.get_tariff() {
+				Some((offpeak, _)) => Some(offpeak),
+				None => hoursranges.check_range(now).ok().map(|range| hoursranges.is_offpeak(range))
+			};
+			if let Some(offpeak) = offpeak {
+				states.push(PublishedState::binary_sensor("openhems_offpeak", "offpeak", offpeak));
+			}
+		}
+		states.push(PublishedState::binary_sensor("openhems_stale", "stale data", stale || self.is_stale()));
+		self.updater.borrow_mut().publish_states(&states)
+	}
+	/// Notify users through 'notifications.channels' (deduplicated and rate limited).
+	pub fn notify(&self, severity:Severity, message:&str) -> ResultOpenHems<bool> {
+		self.notifier.notify(&self.updater.borrow(), severity, message)
 	}
 }
@@ -0,0 +1,211 @@
+use std::sync::mpsc::Receiver;
+use chrono::{DateTime, Local};
+use crate::error::{OpenHemsError, ResultOpenHems};
+use crate::home_assistant_api::{Actuator, HomeAssistantAPI, HomeStateUpdater};
+use crate::modbus_updater::ModbusUpdater;
+use crate::mqtt_updater::MqttUpdater;
+use crate::teleinfo::Teleinfo;
+
+/// A value OpenHEMS publishes about itself (like the schedule of a switch).
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishedState {
+	pub domain: &'static str, // sensor, binary_sensor or select
+	pub object_id: String, // Like 'openhems_car_schedule', only [a-z0-9_]
+	pub name: String,
+	pub value: String, // 'on' or 'off' for binary_sensor
+	pub unit: &'static str,
+	pub options: Vec<String>, // For enum sensors
+}
+impl PublishedState {
+	pub fn sensor(object_id:&str, name:&str, value:String, unit:&'static str) -> PublishedState {
+		PublishedState {
+			domain: "sensor",
+			object_id: Self::slugify(object_id),
+			name: name.to_string(),
+			value,
+			unit,
+			options: Vec::new(),
+		}
+	}
+	pub fn binary_sensor(object_id:&str, name:&str, value:bool) -> PublishedState {
+		PublishedState {
+			domain: "binary_sensor",
+			value: (if value {"on"} else {"off"}).to_string(),
+			..Self::sensor(object_id, name, String::new(), "")
+		}
+	}
+	/// Sensor with a value among 'options'.
+	pub fn enumeration(object_id:&str, name:&str, value:&str, options:&[&str]) -> PublishedState {
+		PublishedState {
+			options: options.iter().map(|o| o.to_string()).collect(),
+			..Self::sensor(object_id, name, value.to_string(), "")
+		}
+	}
+	/// Value among 'options' which can be changed from Home-Assistant with MQTT.
+	/// With the REST API, it's a read-only enum sensor (an entity posted to Home-Assistant can not be controlled there).
+	pub fn select(object_id:&str, name:&str, value:&str, options:&[&str]) -> PublishedState {
+		PublishedState {
+			domain: "select",
+			..Self::enumeration(object_id, name, value, options)
+		}
+	}
+	/// Home-Assistant object id from any text : 'openhems_switch.Water-Heater_mode' to 'openhems_switch_water_heater_mode'.
+	pub fn slugify(text:&str) -> String {
+		let mut slug = String::with_capacity(text.len());
+		for c in text.to_lowercase().chars() {
+			if c.is_ascii_alphanumeric() {
+				slug.push(c);
+			} else if !slug.is_empty() && !slug.ends_with('_') {
+				slug.push('_');
+			}
+		}
+		slug.trim_end_matches('_').to_string()
+	}
+}
+
+/// Source of the network states, selected by 'server.network'.
+#[derive(Clone, Debug)]
+pub enum NetworkSource {
+	HomeAssistant(Box<HomeAssistantAPI>),
+	Mqtt(MqttUpdater),
+	Modbus(ModbusUpdater),
+}
+
+/// The network source, with local sources (the Linky teleinfo) serving their own entities.
+#[derive(Clone, Debug)]
+pub struct NetworkUpdater {
+	source: NetworkSource,
+	teleinfo: Option<Teleinfo>,
+}
+
+macro_rules! dispatch (
+	($self:ident, $u:ident => $e:expr) => (
+		match &$self.source {
+			NetworkSource::HomeAssistant($u) => $e,
+			NetworkSource::Mqtt($u) => $e,
+			NetworkSource::Modbus($u) => $e,
+		}
+	);
+	(mut $self:ident, $u:ident => $e:expr) => (
+		match &mut $self.source {
+			NetworkSource::HomeAssistant($u) => $e,
+			NetworkSource::Mqtt($u) => $e,
+			NetworkSource::Modbus($u) => $e,
+		}
+	);
+);
+
+impl NetworkUpdater {
+	pub fn new(source:NetworkSource, teleinfo:Option<Teleinfo>) -> NetworkUpdater {
+		NetworkUpdater {
+			source,
+			teleinfo,
+		}
+	}
+	// The teleinfo reader, if it serves this entity.
+	fn get_teleinfo(&self, nameid:&str) -> Option<&Teleinfo> {
+		self.teleinfo.as_ref().filter(|teleinfo| teleinfo.has_entity(nameid))
+	}
+	/// True if the source knows this entity ('sensor.x#attribute' is accepted).
+	pub fn has_entity(&self, nameid:&str) -> bool {
+		self.get_teleinfo(nameid).is_some() || dispatch!(self, u => u.has_entity(nameid))
+	}
+	/// Display name of an entity, if the source knows one (Home-Assistant 'friendly_name').
+	pub fn get_friendly_name(&self, nameid:&str) -> Option<String> {
+		match &self.source {
+			NetworkSource::HomeAssistant(u) => u.get_friendly_name(nameid).map(|name| name.to_string()),
+			_ => None
+		}
+	}
+	/// Switch on/off, return false if the source did not confirm the new state.
+	pub fn call_actuator(&self, actuator:&Actuator, on:bool) -> ResultOpenHems<bool> {
+		dispatch!(self, u => u.call_actuator(actuator, on))
+	}
+	/// Receiver of changed entity ids, if the source pushes changes.
+	pub fn start_events(&self) -> Option<Receiver<String>> {
+		match &self.source {
+			NetworkSource::HomeAssistant(u) => u.start_websocket(),
+			NetworkSource::Mqtt(u) => Some(u.start_events()),
+			NetworkSource::Modbus(_) => None,
+		}
+	}
+	/// Send a notification with a Home-Assistant notify service.
+	pub fn call_notify_service(&self, service:&str, title:&str, message:&str) -> ResultOpenHems<bool> {
+		match &self.source {
+			NetworkSource::HomeAssistant(u) => u.notify_service(service, title, message),
+			_ => Err(OpenHemsError::new(format!("Notify service '{service}' needs Home-Assistant network.")))
+		}
+	}
+	/// True when last update failed, so values are from a previous update.
+	pub fn is_stale(&self) -> bool {
+		dispatch!(self, u => u.is_stale())
+	}
+	/// Values set from Home-Assistant on published selects (object_id, value), since last call.
+	pub fn take_commands(&self) -> Vec<(String, String)> {
+		match &self.source {
+			NetworkSource::Mqtt(u) => u.take_commands(),
+			_ => Vec::new()
+		}
+	}
+	pub fn publish_states(&mut self, states:&[PublishedState]) -> ResultOpenHems<()> {
+		match &mut self.source {
+			NetworkSource::HomeAssistant(u) => u.publish_states(states),
+			NetworkSource::Mqtt(u) => u.publish_states(states),
+			NetworkSource::Modbus(_) => Ok(()),
+		}
+	}
+}
+
+impl HomeStateUpdater for NetworkUpdater {
+	fn default() -> Self {
+		NetworkUpdater::new(NetworkSource::HomeAssistant(Box::new(HomeAssistantAPI::default())), None)
+	}
+	fn notify(&self, message:&str) -> ResultOpenHems<bool> {
+		dispatch!(self, u => u.notify(message))
+	}
+	fn init_network(&mut self) -> ResultOpenHems<bool> {
+		dispatch!(mut self, u => u.init_network())
+	}
+	fn update_network(&mut self) -> ResultOpenHems<bool> {
+		if let Some(teleinfo) = self.teleinfo.as_mut() {
+			teleinfo.update();
+		}
+		dispatch!(mut self, u => u.update_network())
+	}
+	fn register_entity(&mut self, nameid:&str) -> bool {
+		self.get_teleinfo(nameid).is_some() || dispatch!(mut self, u => u.register_entity(nameid))
+	}
+	fn get_entity_value_int(&self, nameid:&str) -> ResultOpenHems<i32> {
+		if let Some(teleinfo) = self.get_teleinfo(nameid) {
+			return Ok(teleinfo.get_entity_value_float(nameid)? as i32);
+		}
+		dispatch!(self, u => u.get_entity_value_int(nameid))
+	}
+	fn get_entity_value_float(&self, nameid:&str) -> ResultOpenHems<f32> {
+		if let Some(teleinfo) = self.get_teleinfo(nameid) {
+			return teleinfo.get_entity_value_float(nameid);
+		}
+		dispatch!(self, u => u.get_entity_value_float(nameid))
+	}
+	fn get_entity_value_str(&self, nameid:&str) -> ResultOpenHems<String> {
+		if let Some(teleinfo) = self.get_teleinfo(nameid) {
+			return teleinfo.get_value(nameid);
+		}
+		dispatch!(self, u => u.get_entity_value_str(nameid))
+	}
+	fn get_entity_value_bool(&self, nameid:&str) -> ResultOpenHems<bool> {
+		if let Some(teleinfo) = self.get_teleinfo(nameid) {
+			return Ok(teleinfo.get_value(nameid)?=="true");
+		}
+		dispatch!(self, u => u.get_entity_value_bool(nameid))
+	}
+	fn get_cycle_id(&self) -> u32 {
+		dispatch!(self, u => u.get_cycle_id())
+	}
+	fn get_entity_last_update(&self, nameid:&str) -> Option<DateTime<Local>> {
+		if let Some(teleinfo) = self.get_teleinfo(nameid) {
+			return teleinfo.get_last_update();
+		}
+		dispatch!(self, u => u.get_entity_last_update(nameid))
+	}
+}
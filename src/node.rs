@@ -2,12 +2,13 @@ use core::fmt;
 use std::fmt::Debug;
 use std::ops::Deref;
 use std::sync::{Arc, MutexGuard, Mutex};
-use arrayvec::ArrayString;
 use chrono::{DateTime, Local};
 use crate::error::{OpenHemsError, ResultOpenHems};
-use crate::feeder::{Feeder, SourceFeeder};
+use crate::feeder::Feeder;
+use crate::home_assistant_api::Actuator;
 use crate::contract::Contract;
-use crate::schedule::Schedule;
+use crate::cycle::{CycleDetector, CycleState};
+use crate::schedule::{Schedule, ScheduleMode};
 use crate::time;
 use crate::web::AppState;
 
@@ -65,11 +66,21 @@ impl NodeType {
 pub trait Node {
 	fn get_type(&self) -> NodeType;
 	fn get_id(&self) -> &str;
+	/// Display name (configured 'name', Home-Assistant friendly name or id).
+	fn get_name(&self) -> &str;
 	fn get_min_power(&self) -> f32;
 	fn get_max_power(&self) -> f32;
 	fn get_current_power(&mut self) -> ResultOpenHems<f32>;
 	fn is_on(&self) -> ResultOpenHems<bool>;
 	fn is_activate(&mut self) -> bool;
+	/// Oldest report date of the node sources, None if it has only constants.
+	fn get_last_update(&self) -> Option<DateTime<Local>>;
+	fn is_stale(&self, now:DateTime<Local>, max_age:u32) -> bool {
+		match self.get_last_update() {
+			Some(last_update) => (now-last_update).num_seconds()>max_age as i64,
+			None => false
+		}
+	}
 }
 impl fmt::Display for dyn Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -86,10 +97,11 @@ impl fmt::Debug for dyn Node {
 
 #[derive(Clone)]
 pub struct NodeBase {
-	nameid: ArrayString<16>,
+	nameid: String,
+	name: String,
 	max_power: f32,
 	min_power: f32,
-	current_power: SourceFeeder<f32>,
+	current_power: Feeder<f32>,
 	is_activate: bool,
 	pub is_on: Feeder<bool>
 }
@@ -102,19 +114,30 @@ impl<'a, 'b:'a, 'c:'b> Debug for NodeBase {
     }
 }
 
-pub fn get_nodebase(nameid: &str, max_power: f32, min_power: f32, current_power:SourceFeeder<f32>, is_on:Feeder<bool>)
+pub fn get_nodebase(nameid: &str, max_power: f32, min_power: f32, current_power:Feeder<f32>, is_on:Feeder<bool>)
 		-> ResultOpenHems<NodeBase> {
-	if let Ok(name) = ArrayString::from(nameid) {
-		Ok(NodeBase {
-			nameid: name,
-			max_power: max_power,
-			min_power: min_power,
-			current_power: current_power,
-			is_activate: true,
-			is_on: is_on,
-		})
-	} else {
-		Err(OpenHemsError::new(format!("'id' is to long (Limit is 16) for node {nameid}.")))
+	if nameid.is_empty() {
+		return Err(OpenHemsError::new("'id' is empty.".to_string()));
+	}
+	Ok(NodeBase {
+		nameid: nameid.to_string(),
+		name: nameid.to_string(),
+		max_power,
+		min_power,
+		current_power,
+		is_activate: true,
+		is_on,
+	})
+}
+impl NodeBase {
+	pub fn set_name(&mut self, name:&str) {
+		if !name.is_empty() {
+			self.name = name.to_string();
+		}
+	}
+	/// Current power before filters.
+	pub fn get_raw_power(&mut self) -> ResultOpenHems<f32> {
+		self.current_power.get_raw_value()
 	}
 }
 impl<'a, 'b:'a, 'c:'b> Node for NodeBase {
@@ -122,6 +145,9 @@ impl<'a, 'b:'a, 'c:'b> Node for NodeBase {
 	fn get_id(&self) -> &str {
 		&self.nameid
 	}
+	fn get_name(&self) -> &str {
+		&self.name
+	}
     fn get_min_power(&self) -> f32 {
 		self.min_power
 	}
@@ -137,11 +163,35 @@ impl<'a, 'b:'a, 'c:'b> Node for NodeBase {
     fn is_activate(&mut self) -> bool {
 		self.is_activate
 	}
+	fn get_last_update(&self) -> Option<DateTime<Local>> {
+		match (self.current_power.get_last_update(), self.is_on.get_last_update()) {
+			(Some(current_power), Some(is_on)) => Some(current_power.min(is_on)),
+			(current_power, is_on) => current_power.or(is_on)
+		}
+	}
 	fn get_type(&self) -> NodeType {
 		NodeType::NodeBase
 	}
 }
 
+/// Switch state to apply when network data are stale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failsafe {
+	Keep,
+	Off,
+	On,
+}
+impl Failsafe {
+	pub fn from_str(value:&str) -> ResultOpenHems<Failsafe> {
+		match value.to_lowercase().as_str() {
+			"keep" | "" => Ok(Failsafe::Keep),
+			"off" | "false" => Ok(Failsafe::Off),
+			"on" | "true" => Ok(Failsafe::On),
+			_ => Err(OpenHemsError::new(format!("Invalid failsafe '{value}', use keep, off or on.")))
+		}
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct Switch {
 	// Node
@@ -149,38 +199,122 @@ pub struct Switch {
 	// Outnode
 	// Switch
 	_pritority: u32,
-	_strategy_nameid: ArrayString<16>,
-	schedule: Arc<Mutex<Schedule>>
+	_strategy_nameid: String,
+	schedule: Arc<Mutex<Schedule>>,
+	actuator: Option<Actuator>,
+	failsafe: Failsafe,
+	running_power: f32, // Schedules are counted only while the switch draws more
+	cycle: Option<CycleDetector>,
 }
 pub fn get_switch<'a, 'b:'a, 'c:'b>(node: NodeBase, pritority: u32, strategy_nameid: &str,
-			appstate:&mut AppState
+			actuator: Option<Actuator>, failsafe: Failsafe, running_power: f32, appstate:&mut AppState
 		) -> ResultOpenHems<Switch> {
-	if let Ok(strategy) = ArrayString::from(strategy_nameid) {
-		let sch = Arc::new(Mutex::new(Schedule::new(&node.nameid)));
-		appstate.schedules.insert(node.nameid.to_string(), sch.clone());
-		Ok(Switch {
-			node: node,
-			_pritority: pritority,
-			_strategy_nameid: strategy,
-			schedule: sch
-		})
-	} else {
-		Err(OpenHemsError::new("Strategy is to long (Limit is 16)".to_string()))
-	}
+	let mut schedule = Schedule::new(&node.nameid);
+	schedule.set_name(&node.name);
+	schedule.set_max_power(node.max_power);
+	let sch = Arc::new(Mutex::new(schedule));
+	appstate.schedules.insert(node.nameid.to_string(), sch.clone());
+	Ok(Switch {
+		node,
+		_pritority: pritority,
+		_strategy_nameid: strategy_nameid.to_string(),
+		schedule: sch,
+		actuator,
+		failsafe,
+		running_power,
+		cycle: None,
+	})
 }
 impl Switch {
 	pub fn switch(&self, on:bool) -> ResultOpenHems<bool> {
 		log::debug!("{}.switch(on={on})", self.get_id());
-		if let Feeder::Source(mut feeder) = self.is_on.clone() {
-			let on2 = if self.get_schedule().is_scheduled() {on} // Switch on only if scheduled
-				else {false}; // else don't
-			log::debug!("Switch {}: is_on={} -> is_scheduled={}", self.get_id(), feeder.get_value()?, on2);
-			if feeder.get_value()?!=on2 {
-				return feeder.switch(feeder.get_nameid().as_str(), on2);
+		if let Some(actuator) = &self.actuator {
+			let on2 = match self.get_schedule().get_mode() {
+				ScheduleMode::On => true,
+				ScheduleMode::Off => false,
+				ScheduleMode::Auto if self.get_schedule().is_forced() => true, // Keep on to meet the timeout
+				ScheduleMode::Auto if self.is_cycle_running() => true, // Never cut a running cycle
+				ScheduleMode::Auto if self.get_schedule().is_scheduled() => on, // Switch on only if scheduled
+				ScheduleMode::Auto => false, // else don't
+			};
+			let is_on = self.is_on.get_value()?;
+			log::debug!("Switch {}: is_on={} -> is_scheduled={}", self.get_id(), is_on, on2);
+			if is_on!=on2 {
+				return self.is_on.switch(actuator, on2);
 			}
 		}
 		Ok(true)
 	}
+	/// Switch to the failsafe state, whatever the schedule is. Return false if there is nothing to do.
+	pub fn apply_failsafe(&self) -> ResultOpenHems<bool> {
+		let on = match self.failsafe {
+			Failsafe::Keep => {
+				return Ok(false);
+			}
+			Failsafe::Off => false,
+			Failsafe::On => true,
+		};
+		if let Some(actuator) = &self.actuator {
+			log::warn!("Switch {} to failsafe state (on={on}).", self.get_id());
+			self.is_on.switch(actuator, on)?;
+			return Ok(true);
+		}
+		Ok(false)
+	}
+	pub fn set_cycle(&mut self, cycle:Option<CycleDetector>) {
+		self.cycle = cycle;
+	}
+	pub fn is_cycle_running(&self) -> bool {
+		self.get_cycle_state()==Some(CycleState::Running)
+	}
+	pub fn get_cycle_state(&self) -> Option<CycleState> {
+		self.cycle.as_ref().map(|cycle| cycle.get_state())
+	}
+	/// Follow the appliance cycle from the current power, the schedule is done when the cycle is finished.
+	/// Return true if the schedule changed.
+	pub fn update_cycle(&mut self, now:DateTime<Local>) -> ResultOpenHems<bool> {
+		if self.cycle.is_none() {
+			return Ok(false);
+		}
+		let power = self.get_current_power()?;
+		let Some(state) = self.cycle.as_mut().and_then(|cycle| cycle.update(now, power)) else {
+			return Ok(false);
+		};
+		log::info!("Switch {} : cycle {}", self.get_id(), state.as_str());
+		let mut schedule = self.get_schedule();
+		if state==CycleState::Finished && schedule.is_scheduled() {
+			schedule.set_energy(0.0);
+			schedule.set_duration(0);
+			return Ok(true);
+		}
+		Ok(false)
+	}
+	/// Power drawn while the switch is on and above 'runningPower', None when it's not running.
+	pub fn get_running_power(&mut self) -> ResultOpenHems<Option<f32>> {
+		if !self.is_on()? {
+			return Ok(None);
+		}
+		let power = self.get_current_power()?;
+		Ok(if power>=self.running_power {Some(power)} else {None})
+	}
+	/// Count 'duration' seconds on the schedule if the switch was running. Return true if the schedule changed.
+	pub fn decrement_schedule(&mut self, duration:u32) -> ResultOpenHems<bool> {
+		if !self.get_schedule().is_scheduled() {
+			return Ok(false);
+		}
+		match self.get_running_power()? {
+			Some(_) => {
+				// Energy really drawn : a filter would delay or flatten it.
+				let power = self.node.get_raw_power()?;
+				self.get_schedule().decrement(duration, power)?;
+				Ok(true)
+			}
+			None => {
+				log::debug!("Switch {} is not running, schedule is kept.", self.get_id());
+				Ok(false)
+			}
+		}
+	}
 	pub fn get_schedule<'a>(&'a self) -> MutexGuard<'a, Schedule, > {
 		self.schedule.lock().unwrap()
 	}
@@ -204,6 +338,9 @@ impl<'a, 'b:'a, 'c:'b> Node for Switch {
 	fn get_id(&self) -> &str {
 		self.node.get_id()
 	}
+	fn get_name(&self) -> &str {
+		self.node.get_name()
+	}
     fn get_min_power(&self) -> f32 {
 		self.node.get_min_power()
 	}
@@ -219,6 +356,9 @@ impl<'a, 'b:'a, 'c:'b> Node for Switch {
     fn is_activate(&mut self) -> bool {
 		self.node.is_activate()
 	}
+	fn get_last_update(&self) -> Option<DateTime<Local>> {
+		self.node.get_last_update()
+	}
 	fn get_type(&self) -> NodeType {
 		NodeType::Switch
 	}
@@ -236,6 +376,9 @@ impl<'a, 'b:'a, 'c:'b> PublicPowerGrid {
 	pub fn get_contract(&self) -> &Contract {
 		&self.contract
 	}
+	pub fn get_raw_power(&mut self) -> ResultOpenHems<f32> {
+		self.node.get_raw_power()
+	}
 }
 pub fn get_publicpowergrid<'a, 'b:'a, 'c:'b>(node: NodeBase, contract: Contract) -> ResultOpenHems<PublicPowerGrid> {
 	Ok(PublicPowerGrid {
@@ -254,6 +397,9 @@ impl<'a, 'b:'a, 'c:'b> Node for PublicPowerGrid {
 	fn get_id(&self) -> &str {
 		self.node.get_id()
 	}
+	fn get_name(&self) -> &str {
+		self.node.get_name()
+	}
     fn get_min_power(&self) -> f32 {
 		self.node.get_min_power()
 	}
@@ -269,6 +415,9 @@ impl<'a, 'b:'a, 'c:'b> Node for PublicPowerGrid {
     fn is_activate(&mut self) -> bool {
 		self.node.is_activate()
 	}
+	fn get_last_update(&self) -> Option<DateTime<Local>> {
+		self.node.get_last_update()
+	}
 	fn get_type(&self) -> NodeType {
 		NodeType::PublicPowerGrid
 	}
@@ -311,6 +460,9 @@ impl Node for SolarPanel {
 	fn get_id(&self) -> &str {
 		self.node.get_id()
 	}
+	fn get_name(&self) -> &str {
+		self.node.get_name()
+	}
     fn get_min_power(&self) -> f32 {
 		self.node.get_min_power()
 	}
@@ -326,6 +478,9 @@ impl Node for SolarPanel {
     fn is_activate(&mut self) -> bool {
 		self.node.is_activate()
 	}
+	fn get_last_update(&self) -> Option<DateTime<Local>> {
+		self.node.get_last_update()
+	}
 	fn get_type(&self) -> NodeType {
 		NodeType::PublicPowerGrid
 	}
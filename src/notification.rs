@@ -0,0 +1,372 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use chrono::{DateTime, Local, TimeDelta};
+use reqwest::blocking::Client;
+use serde_json::json;
+use yaml_rust2::Yaml;
+use crate::cast_utility;
+use crate::configuration_manager::ConfigurationManager;
+use crate::error::{OpenHemsError, ResultOpenHems};
+use crate::home_assistant_api::HomeStateUpdater;
+use crate::network_updater::NetworkUpdater;
+
+const TIMEOUT:Duration = Duration::from_secs(10);
+const TITLE:&str = "Notification from OpenHEMS.";
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Severity {
+	Info,
+	Warning,
+	Error,
+}
+impl Severity {
+	pub fn from_str(value:&str) -> ResultOpenHems<Severity> {
+		match value.to_lowercase().as_str() {
+			"info" | "" => Ok(Severity::Info),
+			"warning" | "warn" => Ok(Severity::Warning),
+			"error" => Ok(Severity::Error),
+			_ => Err(OpenHemsError::new(format!("Invalid severity '{value}', use info, warning or error.")))
+		}
+	}
+}
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Severity::Info => write!(f, "info"),
+			Severity::Warning => write!(f, "warning"),
+			Severity::Error => write!(f, "error"),
+		}
+	}
+}
+
+/// Where notifications are sent.
+#[derive(Clone, Debug)]
+enum Channel {
+	Network, // Network source notify : Home-Assistant persistent notification or MQTT topic
+	HomeAssistant(String), // Home-Assistant notify service, like 'notify.mobile_app_phone'
+	Smtp {
+		address: String, // host:port of a local relay, without authentication
+		from: String,
+		to: Vec<String>,
+	},
+	Webhook(String), // POST JSON {title, message, severity}
+	Ntfy {
+		url: String, // Like 'https://ntfy.sh/my_topic'
+		token: String,
+	},
+}
+impl Channel {
+	fn from_conf(conf:&Yaml) -> ResultOpenHems<(Channel, Severity)> {
+		let conf = cast_utility::to_type_dict(conf);
+		let get = |key:&str| conf.get(key).map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+		let class = get("class");
+		let required = |key:&str| -> ResultOpenHems<String> {
+			let value = get(key);
+			if value.is_empty() {
+				Err(OpenHemsError::new(format!("Notification channel '{class}' need a '{key}'.")))
+			} else {
+				Ok(value)
+			}
+		};
+		let channel = match class.to_lowercase().as_str() {
+			"network" => Channel::Network,
+			"homeassistant" => {
+				let service = required("service")?;
+				if !service.contains('.') {
+					return Err(OpenHemsError::new(format!("Invalid notify service '{service}', expect 'notify.<name>'.")));
+				}
+				Channel::HomeAssistant(service)
+			}
+			"smtp" => {
+				let port = match conf.get("port") {
+					Some(port) => cast_utility::try_to_type_int(port)?,
+					None => 25
+				};
+				let host = get("host");
+				Channel::Smtp {
+					address: format!("{}:{port}", if host.is_empty() {"localhost"} else {&host}),
+					from: required("from")?,
+					to: match conf.get("to") {
+						Some(Yaml::Array(list)) => list.iter().map(cast_utility::to_type_str).collect(),
+						_ => vec![required("to")?]
+					},
+				}
+			}
+			"webhook" => Channel::Webhook(required("url")?),
+			"ntfy" => Channel::Ntfy {
+				url: required("url")?,
+				token: get("token"),
+			},
+			_ => {
+				return Err(OpenHemsError::new(format!(
+					"Invalid notification channel class '{class}', use network, homeassistant, smtp, webhook or ntfy."
+				)));
+			}
+		};
+		Ok((channel, Severity::from_str(&get("severity"))?))
+	}
+	fn get_name(&self) -> String {
+		match self {
+			Channel::Network => String::from("network"),
+			Channel::HomeAssistant(service) => service.clone(),
+			Channel::Smtp{address, ..} => format!("smtp://{address}"),
+			Channel::Webhook(url) | Channel::Ntfy{url, ..} => url.clone(),
+		}
+	}
+	fn send(&self, updater:&NetworkUpdater, client:&Client, severity:Severity, message:&str) -> ResultOpenHems<()> {
+		let http_error = |err:reqwest::Error| OpenHemsError::new(format!("Fail notify '{}' : {err}", self.get_name()));
+		match self {
+			Channel::Network => {
+				updater.notify(message)?;
+			}
+			Channel::HomeAssistant(service) => {
+				updater.call_notify_service(service, TITLE, message)?;
+			}
+			Channel::Smtp{address, from, to} => {
+				send_mail(address, from, to, &format!("[OpenHEMS] {severity}"), message)?;
+			}
+			Channel::Webhook(url) => {
+				client.post(url)
+					.header("content-type", "application/json")
+					.body(json!({"title": TITLE, "message": message, "severity": severity.to_string()}).to_string())
+					.send().and_then(|r| r.error_for_status()).map_err(http_error)?;
+			}
+			Channel::Ntfy{url, token} => {
+				let priority = match severity {
+					Severity::Info => "3",
+					Severity::Warning => "4",
+					Severity::Error => "5",
+				};
+				let mut request = client.post(url)
+					.header("Title", "OpenHEMS")
+					.header("Priority", priority)
+					.header("Tags", severity.to_string())
+					.body(message.to_string());
+				if !token.is_empty() {
+					request = request.bearer_auth(token);
+				}
+				request.send().and_then(|r| r.error_for_status()).map_err(http_error)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Send a mail through a SMTP relay (no authentication, no TLS).
+fn send_mail(address:&str, from:&str, to:&[String], subject:&str, message:&str) -> ResultOpenHems<()> {
+	let error = |err:&dyn fmt::Display| OpenHemsError::new(format!("Fail send mail with '{address}' : {err}"));
+	let socket_address = address.to_socket_addrs().map_err(|err| error(&err))?
+		.next().ok_or(error(&"unknown host"))?;
+	let stream = TcpStream::connect_timeout(&socket_address, TIMEOUT).map_err(|err| error(&err))?;
+	let _ = stream.set_read_timeout(Some(TIMEOUT));
+	let mut writer = stream.try_clone().map_err(|err| error(&err))?;
+	let mut reader = BufReader::new(stream);
+	// Read a (multi-line) reply and check its code.
+	let mut expect = |code:&str| -> ResultOpenHems<()> {
+		loop {
+			let mut line = String::new();
+			reader.read_line(&mut line).map_err(|err| error(&err))?;
+			if !line.starts_with(code) {
+				return Err(error(&format!("unexpected reply '{}'", line.trim_end())));
+			}
+			if line.as_bytes().get(3)!=Some(&b'-') {
+				return Ok(());
+			}
+		}
+	};
+	let mut command = |line:&str| writer.write_all(format!("{line}\r\n").as_bytes()).map_err(|err| error(&err));
+	expect("220")?;
+	command("EHLO openhems")?;
+	expect("250")?;
+	command(&format!("MAIL FROM:<{from}>"))?;
+	expect("250")?;
+	for recipient in to {
+		command(&format!("RCPT TO:<{recipient}>"))?;
+		expect("250")?;
+	}
+	command("DATA")?;
+	expect("354")?;
+	let body:Vec<String> = message.lines()
+		.map(|line| if line.starts_with('.') {format!(".{line}")} else {line.to_string()})
+		.collect();
+	command(&format!("From: {from}\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n.",
+		to.join(", "), Local::now().to_rfc2822(), body.join("\r\n")))?;
+	expect("250")?;
+	command("QUIT")?;
+	Ok(())
+}
+
+// Deduplication and rate limiting state of a channel.
+#[derive(Clone, Debug, Default)]
+struct ChannelState {
+	sent: VecDeque<DateTime<Local>>, // Dates of notifications sent during the last hour
+	last_sent: HashMap<String, DateTime<Local>>, // By message
+	suppressed: u32, // Dropped by rate limit since last sent notification
+}
+
+/// Send notifications to the channels configured in 'notifications.channels',
+/// dropping duplicated messages and limiting the number per hour.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+	channels: Vec<(Channel, Severity)>,
+	states: RefCell<Vec<ChannelState>>,
+	dedup_delay: TimeDelta,
+	max_per_hour: usize,
+	client: Client,
+}
+impl Notifier {
+	pub fn new(configurator:&ConfigurationManager) -> ResultOpenHems<Notifier> {
+		let mut channels = configurator.get_as_list("notifications.channels").into_iter()
+			.map(Channel::from_conf)
+			.collect::<ResultOpenHems<Vec<(Channel, Severity)>>>()?;
+		if channels.is_empty() {
+			channels.push((Channel::Network, Severity::Info));
+		}
+		let client = Client::builder().timeout(TIMEOUT).build()
+			.map_err(|err| OpenHemsError::new(format!("Fail build HTTP client : {err}")))?;
+		Ok(Notifier {
+			states: RefCell::new(vec![ChannelState::default(); channels.len()]),
+			channels,
+			dedup_delay: TimeDelta::seconds(configurator.get_as_int("notifications.dedupDelay").max(0) as i64),
+			max_per_hour: configurator.get_as_int("notifications.maxPerHour").max(0) as usize,
+			client,
+		})
+	}
+	/// Send to channels accepting this severity. Return false if nothing was sent (filtered, duplicated or limited).
+	pub fn notify(&self, updater:&NetworkUpdater, severity:Severity, message:&str) -> ResultOpenHems<bool> {
+		self.notify_at(Local::now(), updater, severity, message)
+	}
+	fn notify_at(&self, now:DateTime<Local>, updater:&NetworkUpdater, severity:Severity, message:&str) -> ResultOpenHems<bool> {
+		let mut states = self.states.borrow_mut();
+		let mut sent = false;
+		let mut errors = Vec::new();
+		for ((channel, min_severity), state) in self.channels.iter().zip(states.iter_mut()) {
+			if severity<*min_severity {
+				continue;
+			}
+			if let Some(date) = state.last_sent.get(message) {
+				if now-*date<self.dedup_delay {
+					log::debug!("Notification already sent to '{}' : {message}", channel.get_name());
+					continue;
+				}
+			}
+			while state.sent.front().is_some_and(|date| now-*date>=TimeDelta::hours(1)) {
+				state.sent.pop_front();
+			}
+			if self.max_per_hour>0 && state.sent.len()>=self.max_per_hour {
+				log::warn!("Too many notifications to '{}', drop : {message}", channel.get_name());
+				state.suppressed += 1;
+				continue;
+			}
+			let text = if state.suppressed>0 {
+				format!("{message}\n({} notifications dropped by rate limit)", state.suppressed)
+			} else {
+				message.to_string()
+			};
+			match channel.send(updater, &self.client, severity, &text) {
+				Ok(()) => {
+					sent = true;
+					state.suppressed = 0;
+					state.sent.push_back(now);
+					state.last_sent.retain(|_, date| now-*date<self.dedup_delay);
+					state.last_sent.insert(message.to_string(), now);
+				}
+				Err(err) => {
+					errors.push(err.message);
+				}
+			}
+		}
+		if errors.is_empty() {
+			Ok(sent)
+		} else {
+			Err(OpenHemsError::new(errors.join(" ")))
+		}
+	}
+}
+
+/// Used by configuration check.
+pub fn check_channel(conf:&Yaml) -> Option<String> {
+	Channel::from_conf(conf).err().map(|err| err.message)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+	use std::sync::mpsc::channel;
+	use crate::configuration_manager;
+	use super::*;
+
+	// SMTP relay sending each received mail body.
+	fn start_smtp_server() -> (u16, std::sync::mpsc::Receiver<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let (sender, receiver) = channel();
+		std::thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut stream = stream.unwrap();
+				let mut reader = BufReader::new(stream.try_clone().unwrap());
+				stream.write_all(b"220 localhost\r\n").unwrap();
+				let (mut data, mut in_data) = (String::new(), false);
+				let mut line = String::new();
+				while reader.read_line(&mut line).unwrap_or(0)>0 {
+					let reply = if in_data {
+						if line=="." || line==".\r\n" {
+							in_data = false;
+							sender.send(data.clone()).unwrap();
+							"250 Ok"
+						} else {
+							data.push_str(&line);
+							""
+						}
+					} else if line.starts_with("EHLO") {
+						"250-localhost\r\n250 8BITMIME"
+					} else if line.starts_with("DATA") {
+						in_data = true;
+						"354 End with ."
+					} else if line.starts_with("QUIT") {
+						"221 Bye"
+					} else {
+						"250 Ok"
+					};
+					if !reply.is_empty() {
+						stream.write_all(format!("{reply}\r\n").as_bytes()).unwrap();
+					}
+					line.clear();
+				}
+			}
+		});
+		(port, receiver)
+	}
+
+	#[test]
+	fn test_notifier() -> ResultOpenHems<()> {
+		let (port, mails) = start_smtp_server();
+		let mut configurator = configuration_manager::get(None);
+		configurator.add_yaml_str("test.yaml", &format!("
+notifications:
+  maxPerHour: 2
+  channels:
+    - {{class: smtp, host: 127.0.0.1, port: {port}, from: openhems@home, to: [me@home], severity: warning}}
+")).unwrap();
+		let notifier = Notifier::new(&configurator)?;
+		let updater = NetworkUpdater::default();
+		let now = Local::now();
+		assert!(!notifier.notify_at(now, &updater, Severity::Info, "Started")?); // Filtered
+		assert!(notifier.notify_at(now, &updater, Severity::Error, "Data are stale")?);
+		assert!(!notifier.notify_at(now, &updater, Severity::Error, "Data are stale")?); // Duplicated
+		assert!(notifier.notify_at(now, &updater, Severity::Warning, "Fail switch on 'car'")?);
+		assert!(!notifier.notify_at(now, &updater, Severity::Warning, "Fail switch on 'heater'")?); // Limited
+		assert!(notifier.notify_at(now+TimeDelta::minutes(61), &updater, Severity::Error, "Data are stale")?);
+		let mail = mails.recv_timeout(TIMEOUT).unwrap();
+		assert!(mail.contains("Subject: [OpenHEMS] error") && mail.ends_with("Data are stale\r\n"), "{mail}");
+		mails.recv_timeout(TIMEOUT).unwrap();
+		let mail = mails.recv_timeout(TIMEOUT).unwrap();
+		assert!(mail.contains("(1 notifications dropped by rate limit)"), "{mail}");
+		assert!(check_channel(&yaml_rust2::YamlLoader::load_from_str("{class: ntfy}").unwrap()[0]).is_some());
+		Ok(())
+	}
+}
@@ -12,6 +12,8 @@ pub trait EnergyStrategy {
 	fn get_id(&self) -> &str;
 	fn get_nodes(&self) -> &Vec<Box<dyn Node>>;
 	fn update_network(&mut self, now:DateTime<Local>) -> ResultOpenHems<u64>;
+	/// Last decision, published as 'sensor.openhems_<id>_decision'.
+	fn get_decision(&self) -> &str;
 }
 // #[derive(Clone)]
 pub struct OffPeakStrategy {
@@ -31,7 +33,13 @@ impl<'a, 'b:'a> EnergyStrategy for OffPeakStrategy {
 		todo!();
 	}
 	fn update_network(&mut self, now:DateTime<Local>) -> ResultOpenHems<u64> {
-		if now>self.rangeend {
+		let metered = self.network.borrow().get_tariff();
+		if let Some((offpeak, color)) = metered {
+			if offpeak!=self.inoffpeakrange {
+				log::info!("OffPeakStrategy::update_network() : meter period changed, inoffpeak={offpeak} color={color:?}");
+			}
+			self.inoffpeakrange = offpeak;
+		} else if now>self.rangeend {
 			let network = self.network.borrow_mut();
 			let hoursranges = network.get_hours_ranges()?;
 			let range = hoursranges.check_range(now)?;
@@ -52,6 +60,9 @@ impl<'a, 'b:'a> EnergyStrategy for OffPeakStrategy {
 		}
 		Ok(100000)
 	}
+	fn get_decision(&self) -> &str {
+		if self.inoffpeakrange {"switch_on"} else {"switch_off"}
+	}
 }
 
 impl<'a, 'b:'a, 'c:'b, 'd:'c> OffPeakStrategy {
@@ -1,13 +1,152 @@
-use arrayvec::ArrayString;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, Timelike};
 use json::JsonValue;
-use crate::{error::{OpenHemsError, ResultOpenHems}, server::DecrementTime, time, web};
+use yaml_rust2::Yaml;
+use crate::{cast_utility, error::{OpenHemsError, ResultOpenHems}, server::DecrementTime, time, web};
+
+/// Override of the strategies decision for a switch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScheduleMode {
+	Auto, // Strategies decide, according to the schedule
+	On, // Forced on
+	Off, // Forced off
+}
+impl ScheduleMode {
+	pub const OPTIONS: [&'static str; 3] = ["auto", "on", "off"];
+	pub fn from_str(value:&str) -> ResultOpenHems<ScheduleMode> {
+		match value.to_lowercase().as_str() {
+			"auto" => Ok(ScheduleMode::Auto),
+			"on" => Ok(ScheduleMode::On),
+			"off" => Ok(ScheduleMode::Off),
+			_ => Err(OpenHemsError::new(format!("Invalid schedule mode '{value}', use auto, on or off.")))
+		}
+	}
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ScheduleMode::Auto => "auto",
+			ScheduleMode::On => "on",
+			ScheduleMode::Off => "off",
+		}
+	}
+}
+
+const DAYS:[&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Recurring schedule : run 'duration' seconds before 'timeout' on some week days.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+	duration: u32,
+	timeout: NaiveTime,
+	days: [bool; 7], // From monday
+}
+impl Program {
+	pub fn new(duration:u32, timeout:NaiveTime, days:&[&str]) -> ResultOpenHems<Program> {
+		if duration==0 {
+			return Err(OpenHemsError::new("Program 'duration' must be positive.".to_string()));
+		}
+		let mut program = Program {duration, timeout, days: [days.is_empty(); 7]};
+		for day in days {
+			let day = day.to_lowercase();
+			match DAYS.iter().position(|d| day.starts_with(d)) {
+				Some(index) => program.days[index] = true,
+				None => {
+					return Err(OpenHemsError::new(format!("Invalid program day '{day}', use one of {DAYS:?}.")));
+				}
+			}
+		}
+		Ok(program)
+	}
+	/// Duration is in seconds or "HH:MM", timeout is "HH:MM", days are like "mon" (every day when empty).
+	fn parse(duration:Option<u32>, duration_str:&str, timeout:&str, days:&[&str]) -> ResultOpenHems<Program> {
+		let duration = match duration {
+			Some(duration) => duration,
+			None => NaiveTime::parse_from_str(duration_str, "%H:%M")
+				.map(|d| d.num_seconds_from_midnight())
+				.map_err(|_| OpenHemsError::new(format!("Invalid program duration '{duration_str}', use seconds or HH:MM.")))?
+		};
+		let timeout = NaiveTime::parse_from_str(timeout, "%H:%M")
+			.map_err(|_| OpenHemsError::new(format!("Invalid program timeout '{timeout}', use HH:MM.")))?;
+		Program::new(duration, timeout, days)
+	}
+	pub fn from_conf(conf:&Yaml) -> ResultOpenHems<Program> {
+		let conf = cast_utility::to_type_dict(conf);
+		let duration = match conf.get("duration") {
+			Some(Yaml::Integer(d)) if *d>=0 => Some(*d as u32),
+			_ => None
+		};
+		let duration_str = conf.get("duration").map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+		let timeout = conf.get("timeout").map(|v| cast_utility::to_type_str(v)).unwrap_or_default();
+		let days:Vec<String> = match conf.get("days") {
+			Some(Yaml::Array(days)) => days.iter().map(cast_utility::to_type_str).collect(),
+			Some(days) => vec![cast_utility::to_type_str(days)],
+			None => Vec::new()
+		};
+		let days:Vec<&str> = days.iter().map(|d| d.as_str()).filter(|d| !d.is_empty()).collect();
+		Program::parse(duration, &duration_str, &timeout, &days)
+	}
+	/// Program from the panel, where 'days' are the checked ones : none is an error, not every day.
+	pub fn from_json(json:&JsonValue) -> ResultOpenHems<Program> {
+		let days:Vec<&str> = json["days"].members().filter_map(|d| d.as_str()).collect();
+		if json["days"].is_array() && days.is_empty() {
+			return Err(OpenHemsError::new("Program needs at least one day.".to_string()));
+		}
+		Program::parse(json["duration"].as_u32(), json["duration"].as_str().unwrap_or(""),
+			json["timeout"].as_str().unwrap_or(""), &days)
+	}
+	pub fn to_json(&self) -> JsonValue {
+		let days:Vec<&str> = DAYS.iter().zip(self.days).filter(|(_, on)| *on).map(|(d, _)| *d).collect();
+		json::object!{
+			"duration": self.duration,
+			"timeout": self.timeout.format("%H:%M").to_string(),
+			"days": days
+		}
+	}
+	/// First timeout after 'now' on a program day.
+	pub fn get_next_timeout(&self, now:DateTime<Local>) -> Option<DateTime<Local>> {
+		(0..8).filter_map(|d| {
+				let date = now.date_naive()+Duration::days(d);
+				if !self.days[date.weekday().num_days_from_monday() as usize] {
+					return None;
+				}
+				date.and_time(self.timeout).and_local_timezone(Local).earliest()
+			})
+			.find(|timeout| *timeout>now)
+	}
+}
+
+/// Can the schedule still be done before its timeout ?
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeadlineStatus {
+	None, // No duration or no timeout
+	Ok, // Strategies can still wait for a better time
+	AtRisk, // Must start now to end before the timeout
+	Missed, // The timeout is passed (or too close) to run the whole duration
+}
+impl DeadlineStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			DeadlineStatus::None => "none",
+			DeadlineStatus::Ok => "ok",
+			DeadlineStatus::AtRisk => "atrisk",
+			DeadlineStatus::Missed => "missed",
+		}
+	}
+}
 
 #[derive(Clone, Debug)]
 pub struct Schedule {
-	nameid: ArrayString<16>,
+	nameid: String,
+	name: String, // Display name in the panel
 	duration:u32,
 	timeout:DateTime<Local>,
+	mode: ScheduleMode,
+	forced: bool, // Switched on whatever strategies decide, to meet the timeout
+	late: bool, // Forced after its latest start, it can not end before the timeout
+	energy: f32, // Wh to deliver, instead of (or in addition to) a duration
+	max_power: f32, // Switch power, to estimate the duration of energy schedules
+	programs: Vec<Program>,
+	configured_programs: Vec<Program>,
+	programs_edited: bool, // Programs changed in the panel (saved in the state file) instead of configuration ones
+	armed: Option<DateTime<Local>>, // Timeout of the last program occurrence set, not to set it again
 }
 
 impl DecrementTime for Schedule {
@@ -23,34 +162,54 @@ impl DecrementTime for Schedule {
 				continu = true;
 			}
 		}
+		if !self.is_scheduled() {
+			self.set_forced(false);
+		}
 		Ok(continu)
 	}
 }
 
 impl Schedule {
-	pub fn new(nameid:&ArrayString<16>) -> Schedule {
+	pub fn new(nameid:&str) -> Schedule {
 		Schedule {
-			nameid: nameid.clone(),
+			nameid: nameid.to_string(),
+			name: nameid.to_string(),
 			duration: 0,
 			timeout:Local::now(),
+			mode: ScheduleMode::Auto,
+			forced: false,
+			late: false,
+			energy: 0.0,
+			max_power: 0.0,
+			programs: Vec::new(),
+			configured_programs: Vec::new(),
+			programs_edited: false,
+			armed: None,
 		}
 	}
 	pub fn to_json(&self) -> String {
-		format!("{{\"name\":\"{}\", \"duration\":{}, \"date\":\"{}\", \"timeout\":\"{}\"}}",
-			self.nameid, self.get_duration(),
-			self.get_timeout().format(web::DATE_FORMAT), self.get_timeout().format("%H:%M"))
+		let programs = JsonValue::Array(self.programs.iter().map(|p| p.to_json()).collect());
+		format!("{{\"id\":{}, \"name\":{}, \"duration\":{}, \"date\":\"{}\", \"timeout\":\"{}\", \"mode\":\"{}\", \"deadline\":\"{}\", \"latestStart\":\"{}\", \"energy\":{}, \"programs\":{}}}",
+			json::stringify(self.nameid.as_str()), json::stringify(self.name.as_str()), self.get_duration(),
+			self.get_timeout().format(web::DATE_FORMAT), self.get_timeout().format("%H:%M"), self.mode.as_str(),
+			self.get_deadline_status(Local::now(), 0).as_str(), self.get_latest_start().format("%H:%M"), self.energy, programs.dump())
 	}
 	pub fn update_from_json(&mut self, schedule_json:&JsonValue) -> ResultOpenHems<()> {
 		if let JsonValue::Object(sch) = schedule_json {
 			let mut update = false;
 			let mut timeout = time::MIN_DATETIME.clone();
 			let mut duration = 0;
+			let mut energy = 0.0;
 			if let Some(d) = sch.get("duration") {
 				if let Some(d1) = d.as_i32() {
 					duration = d1 as u32;
 					update = true;
 				}
 			}
+			if let Some(e) = sch.get("energy").and_then(|e| e.as_f32()) {
+				energy = e.max(0.0);
+				update = true;
+			}
 			if let Some(date) = sch.get("timeout") {
 				if let Some(d1) = date.as_str() {
 					if let Ok(timeout_new) = chrono::NaiveTime::parse_from_str(d1, "%H:%M") {
@@ -60,7 +219,21 @@ impl Schedule {
 					}
 				}
 			}
+			let mut other = false;
+			if let Some(mode) = sch.get("mode").and_then(|m| m.as_str()) {
+				self.set_mode(ScheduleMode::from_str(mode)?);
+				other = true;
+			}
+			if let Some(JsonValue::Array(programs)) = sch.get("programs") {
+				self.programs = programs.iter().map(Program::from_json).collect::<ResultOpenHems<_>>()?;
+				self.programs_edited = self.programs!=self.configured_programs;
+				other = true;
+			}
+			if other && !update {
+				return Ok(());
+			}
 			if update {
+				self.set_energy(energy);
 				self.set_duration(duration);
 				self.set_timeout(&timeout);
 			} else {
@@ -69,26 +242,236 @@ impl Schedule {
 		}
 		Ok(())
 	}
+	/// State saved in the state file (with the exact timeout date, unlike to_json()).
+	pub fn to_state(&self) -> JsonValue {
+		let mut state = json::object!{
+			"duration": self.duration,
+			"timeout": self.timeout.timestamp(),
+			"mode": self.mode.as_str(),
+			"energy": self.energy
+		};
+		if let Some(armed) = self.armed {
+			state["armed"] = armed.timestamp().into();
+		}
+		if self.programs_edited {
+			state["programs"] = JsonValue::Array(self.programs.iter().map(|p| p.to_json()).collect());
+		}
+		state
+	}
+	pub fn restore_state(&mut self, state:&JsonValue) -> ResultOpenHems<()> {
+		let (Some(duration), Some(timeout)) = (state["duration"].as_u32(), state["timeout"].as_i64()) else {
+			return Err(OpenHemsError::new(format!("Invalid schedule state : {state}")));
+		};
+		let Some(timeout) = DateTime::from_timestamp(timeout, 0) else {
+			return Err(OpenHemsError::new(format!("Invalid schedule timeout : {timeout}")));
+		};
+		self.duration = duration;
+		self.timeout = timeout.with_timezone(&Local);
+		self.mode = ScheduleMode::from_str(state["mode"].as_str().unwrap_or("auto"))?;
+		self.energy = state["energy"].as_f32().unwrap_or(0.0);
+		self.armed = state["armed"].as_i64()
+			.and_then(|armed| DateTime::from_timestamp(armed, 0))
+			.map(|armed| armed.with_timezone(&Local));
+		if state["programs"].is_array() {
+			self.programs = state["programs"].members().map(Program::from_json).collect::<ResultOpenHems<_>>()?;
+			self.programs_edited = self.programs!=self.configured_programs;
+		}
+		Ok(())
+	}
+	/// Programs from the configuration.
+	pub fn set_programs(&mut self, programs:Vec<Program>) {
+		self.configured_programs = programs.clone();
+		self.programs = programs;
+	}
+	/// Set duration and timeout from the next program occurrence (in less than a day),
+	/// when the schedule is done or its timeout is passed. Return true if set.
+	pub fn arm_programs(&mut self, now:DateTime<Local>) -> bool {
+		if self.is_scheduled() && (now<self.timeout || self.timeout<=*time::MIN_DATETIME) {
+			return false; // Not done yet
+		}
+		let next = self.programs.iter()
+			.filter_map(|program| program.get_next_timeout(now).map(|timeout| (timeout, program.duration)))
+			.min_by_key(|(timeout, _)| *timeout);
+		let Some((timeout, duration)) = next else {
+			return false;
+		};
+		if timeout-now>Duration::days(1) || self.armed==Some(timeout) {
+			return false;
+		}
+		log::info!("Schedule {} : program {} seconds before {}", self.nameid, duration, timeout.format("%a %H:%M"));
+		self.energy = 0.0;
+		self.set_duration(duration);
+		self.set_forced(false);
+		self.timeout = timeout;
+		self.armed = Some(timeout);
+		true
+	}
 	pub fn is_scheduled(&self) -> bool {
-		let ok = self.duration>0;
+		let ok = self.duration>0 || self.energy>0.0;
 		if ok {
-			log::debug!("Schedule::is_scheduled() for {} seconds", self.duration);
+			log::debug!("Schedule::is_scheduled() for {} seconds, {} Wh", self.duration, self.energy);
 		}
 		ok
 	}
+	/// Count 'duration' seconds running at 'power' W. Return true if the schedule continues.
+	pub fn decrement(&mut self, duration:u32, power:f32) -> ResultOpenHems<bool> {
+		if self.energy>0.0 {
+			self.energy = (self.energy - power.max(0.0)*(duration as f32)/3600.0).max(0.0);
+		}
+		self.decrement_time(duration)?;
+		Ok(self.is_scheduled())
+	}
+	/// Last date to switch on to run the whole duration before the timeout.
+	pub fn get_latest_start(&self) -> DateTime<Local> {
+		self.timeout - Duration::seconds(self.get_remaining_duration() as i64)
+	}
+	/// Seconds left to run, estimated at switch max power for energy schedules.
+	pub fn get_remaining_duration(&self) -> u32 {
+		if self.energy>0.0 && self.max_power>0.0 {
+			self.duration.max((self.energy/self.max_power*3600.0).ceil() as u32)
+		} else {
+			self.duration
+		}
+	}
+	/// Deadline status at 'now', at risk if the schedule must start within 'margin' seconds
+	/// (The next loop may be too late).
+	pub fn get_deadline_status(&self, now:DateTime<Local>, margin:u32) -> DeadlineStatus {
+		if !self.is_scheduled() || self.timeout<=*time::MIN_DATETIME {
+			DeadlineStatus::None
+		} else if now>=self.timeout || now>self.get_latest_start() {
+			if self.forced && !self.late && now<self.timeout {
+				DeadlineStatus::AtRisk // Started in time, but the previous loops were late.
+			} else {
+				DeadlineStatus::Missed
+			}
+		} else if now+Duration::seconds(margin as i64)>=self.get_latest_start() {
+			DeadlineStatus::AtRisk
+		} else {
+			DeadlineStatus::Ok
+		}
+	}
+	pub fn set_forced(&mut self, forced:bool) {
+		self.forced = forced;
+		self.late = false;
+	}
+	/// Force on at 'now', late if its latest start is passed (then it ends as soon as possible, after the timeout).
+	pub fn force(&mut self, now:DateTime<Local>) {
+		self.forced = true;
+		self.late = now>self.get_latest_start();
+	}
+	pub fn is_forced(&self) -> bool {
+		self.forced
+	}
 	pub fn set_duration(&mut self, duration:u32) {
 		self.duration = duration;
+		if !self.is_scheduled() {
+			self.set_forced(false);
+		}
+	}
+	pub fn set_energy(&mut self, energy:f32) {
+		self.energy = energy;
+	}
+	pub fn set_name(&mut self, name:&str) {
+		self.name = name.to_string();
+	}
+	pub fn set_max_power(&mut self, max_power:f32) {
+		self.max_power = max_power;
 	}
 	pub fn set_timeout(&mut self, timeout:&DateTime<Local>) {
 		self.timeout = timeout.clone();
 	}
+	pub fn set_mode(&mut self, mode:ScheduleMode) {
+		self.mode = mode;
+	}
+	pub fn get_mode(&self) -> ScheduleMode {
+		self.mode
+	}
 	pub fn get_duration(&self) -> u32 {
 		self.duration
 	}
 	pub fn get_timeout(&self) -> &DateTime<Local> {
 		&self.timeout
 	}
-	pub fn get_name(&self) -> &str {
-		&self.nameid
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_to_json() {
+		let mut schedule = Schedule::new("switch.lixee_zlinky_tic_water_heater_plug");
+		schedule.set_name("Water \"heater\"");
+		let json = json::parse(&schedule.to_json()).unwrap();
+		assert_eq!(json["id"], "switch.lixee_zlinky_tic_water_heater_plug");
+		assert_eq!(json["name"], "Water \"heater\"");
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_deadline() {
+		let now = Local::now();
+		let mut schedule = Schedule::new("car");
+		schedule.set_timeout(&(now+Duration::hours(3)));
+		assert_eq!(schedule.get_deadline_status(now, 60), DeadlineStatus::None);
+		schedule.set_duration(3600);
+		assert_eq!(schedule.get_deadline_status(now, 60), DeadlineStatus::Ok);
+		assert_eq!(schedule.get_latest_start(), now+Duration::hours(2));
+		assert_eq!(schedule.get_deadline_status(now+Duration::minutes(119), 60), DeadlineStatus::AtRisk);
+		assert_eq!(schedule.get_deadline_status(now+Duration::minutes(121), 60), DeadlineStatus::Missed);
+		schedule.force(now+Duration::minutes(119));
+		assert_eq!(schedule.get_deadline_status(now+Duration::minutes(121), 60), DeadlineStatus::AtRisk);
+		schedule.force(now+Duration::minutes(150)); // Set too late : on as soon as possible, but missed
+		assert_eq!(schedule.get_deadline_status(now+Duration::minutes(151), 60), DeadlineStatus::Missed);
+		schedule.decrement_time(3600).unwrap();
+		assert!(!schedule.is_forced());
+		assert_eq!(schedule.get_deadline_status(now, 60), DeadlineStatus::None);
+	}
+
+	#[test]
+	fn test_programs() {
+		let conf = &yaml_rust2::YamlLoader::load_from_str("{duration: \"03:00\", timeout: \"07:00\", days: [mon, Thursday]}").unwrap()[0];
+		let program = Program::from_conf(conf).unwrap();
+		assert_eq!(program.to_json().dump(), "{\"duration\":10800,\"timeout\":\"07:00\",\"days\":[\"mon\",\"thu\"]}");
+		assert_eq!(Program::from_json(&program.to_json()).unwrap(), program);
+		assert!(Program::from_json(&json::parse("{\"duration\":60, \"timeout\":\"25:00\"}").unwrap()).is_err());
+		assert!(Program::from_json(&json::parse("{\"duration\":60, \"timeout\":\"07:00\", \"days\":[]}").unwrap()).is_err());
+		// Sunday 10:00, next occurrence is on Monday 07:00
+		let sunday = chrono::NaiveDate::from_ymd_opt(2024, 6, 2).unwrap()
+			.and_hms_opt(10, 0, 0).unwrap().and_local_timezone(Local).unwrap();
+		let monday = sunday+Duration::hours(21);
+		assert_eq!(program.get_next_timeout(sunday), Some(monday));
+		let mut schedule = Schedule::new("dishwasher");
+		schedule.set_programs(vec![program.clone()]);
+		assert!(!schedule.arm_programs(sunday-Duration::days(2))); // Friday : more than a day before
+		assert!(schedule.arm_programs(sunday));
+		assert_eq!(schedule.get_duration(), 10800);
+		assert_eq!(*schedule.get_timeout(), monday);
+		schedule.set_duration(0); // Cancelled by the user, not set again
+		assert!(!schedule.arm_programs(sunday+Duration::hours(1)));
+		// Next one on thursday
+		assert!(!schedule.arm_programs(monday));
+		assert!(schedule.arm_programs(monday+Duration::days(2)));
+		assert_eq!(*schedule.get_timeout(), monday+Duration::days(3));
+		// Saved from the panel unchanged : configuration programs are still used
+		let programs = json::object!{"programs": [program.to_json()]};
+		schedule.update_from_json(&programs).unwrap();
+		assert!(!schedule.to_state().has_key("programs"));
+		schedule.update_from_json(&json::object!{"programs": []}).unwrap();
+		assert!(schedule.to_state().has_key("programs"));
+	}
+
+	#[test]
+	fn test_energy() {
+		let now = Local::now();
+		let mut schedule = Schedule::new("car");
+		schedule.set_max_power(2000.0);
+		schedule.update_from_json(&json::parse("{\"duration\":0, \"energy\":10000}").unwrap()).unwrap();
+		assert!(schedule.is_scheduled());
+		assert_eq!(schedule.get_remaining_duration(), 18000);
+		schedule.set_timeout(&(now+Duration::hours(6)));
+		assert_eq!(schedule.get_latest_start(), now+Duration::hours(1));
+		assert!(schedule.decrement(3600, 4000.0).unwrap());
+		assert_eq!(schedule.energy, 6000.0);
+		assert!(!schedule.decrement(3600*4, 2000.0).unwrap());
+		assert_eq!(schedule.energy, 0.0);
+	}
+}
@@ -1,10 +1,13 @@
-use std::{cell::RefCell, cmp::min, fmt::Debug, rc::Rc, sync::Arc, thread::sleep, time::Duration};
+use std::{cell::RefCell, cmp::min, collections::HashSet, fmt::Debug, rc::Rc, sync::{mpsc::RecvTimeoutError, Arc}, thread::sleep, time::{Duration, Instant}};
 use chrono::{DateTime, Local, MappedLocalTime, NaiveDate, NaiveDateTime};
 use yaml_rust2::Yaml;
 use crate::{
-	configuration_manager::ConfigurationManager, error::{OpenHemsError, ResultOpenHems}, network::Network, offpeak_strategy::{EnergyStrategy, OffPeakStrategy}, solarnosell_strategy::SolarNoSellStrategy, time, utils::get_yaml_key, web::AppState
+	configuration_manager::ConfigurationManager, error::{OpenHemsError, ResultOpenHems}, network::Network, network_updater::PublishedState, node::Node, notification::Severity, offpeak_strategy::{EnergyStrategy, OffPeakStrategy}, solarnosell_strategy::SolarNoSellStrategy, time, utils::get_yaml_key, web::AppState
 };
 
+// Minimum delay between loops when woken up by Home-Assistant events.
+const EVENT_DEBOUNCE:Duration = Duration::from_secs(1);
+
 pub trait DecrementTime {
 	fn decrement_time(&mut self, duration:u32) -> ResultOpenHems<bool>;
 }
@@ -23,6 +26,9 @@ pub struct Server {
 	_inoverloadmode: bool,
 	_errors: Vec<String>,
 	app_state: Arc<AppState>,
+	stale_delay: u32,
+	stale_nodes: HashSet<String>,
+	failsafe_done: HashSet<String>, // Switches set to failsafe state since data are stale
 }
 impl<'a> Debug for Server {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
@@ -45,12 +51,15 @@ impl<'a> Server {
 			_inoverloadmode: false,
 			_errors: Vec::new(),
 			app_state: Arc::new(AppState::new()),
+			stale_delay: configurator.get_as_int("server.staleDelay").max(0) as u32,
+			stale_nodes: HashSet::new(),
+			failsafe_done: HashSet::new(),
 		};
 		Ok(hems_server)
 	}
 	pub fn init(&mut self, configurator: &ConfigurationManager, appstate:&mut AppState) -> ResultOpenHems<()> {
 		let mut network = self.network.borrow_mut();
-		network.set_nodes(configurator, appstate);
+		network.set_nodes(configurator, appstate)?;
 		if let Some(configuration) = configurator.get("server.strategies") {
 			if let Some(list) = configuration.clone().into_vec() {
 				let default = String::from("");
@@ -61,7 +70,7 @@ impl<'a> Server {
 							classname = v;
 						} else {
 							return Err(OpenHemsError::new(format!(
-								"Missing key 'id' for strategy."
+								"Missing key 'class' for strategy."
 							)));
 						}
 						let mut id: &String = &default;
@@ -97,32 +106,111 @@ impl<'a> Server {
 	pub fn loop1(&mut self, now:DateTime<Local>, duration:u32) {
 		log::info!("Server::loop1({:?}, {})", now, duration);
 		self.now = now;
-		if duration> 0 {
-			self.app_state.decrement_time(duration).unwrap();
-			/* for d in self.decrement_time.iter_mut() {
-				if let Err(err) = d.decrement_time(duration) {
-					log::error!("Fail decrement time : {}", err.message);
-				}
-			} */
+		/* for d in self.decrement_time.iter_mut() {
+			if let Err(err) = d.decrement_time(duration) {
+				log::error!("Fail decrement time : {}", err.message);
+			}
+		} */
+		if let Err(err) = self.app_state.arm_programs(now) {
+			log::error!("Fail set programs : {}", err.message);
 		}
 		let mut sleep_duration = self.loopdelay;
-		{
+		let updated = {
 			let mut network = self.network.borrow_mut();
-			if let Err(err) = network.update() {
-				log::error!("Fail update network : {}", err.message);
-				return;
+			match network.update() {
+				Ok(true) => true,
+				Ok(false) => {
+					log::warn!("Network values are from a previous update.");
+					true
+				}
+				Err(err) => {
+					log::error!("Fail update network : {}", err.message);
+					false
+				}
 			}
+		};
+		let stale = self.check_stale(now);
+		if !updated {
+			return;
 		}
-		for strategy in self.strategies.iter_mut() {
-			match strategy.update_network(now) {
-				Ok(time2sleep) => {
-					sleep_duration = min(sleep_duration, time2sleep);
+		// Schedules are counted only while switches run (with up to date values), and end with appliance cycles.
+		let mut changed = self.network.borrow().apply_commands();
+		changed |= duration>0 && self.network.borrow_mut().decrement_schedules(duration);
+		changed |= self.network.borrow_mut().update_cycles(now);
+		if changed {
+			if let Err(err) = self.app_state.save_changes(now) {
+				log::error!("Fail save schedules : {}", err.message);
+			}
+		}
+		self.network.borrow_mut().update_daily_cost(now, duration);
+		if !stale {
+			for strategy in self.strategies.iter_mut() {
+				match strategy.update_network(now) {
+					Ok(time2sleep) => {
+						sleep_duration = min(sleep_duration, time2sleep);
+					}
+					Err(err) => {
+						log::error!("Fail update strategy {} : {err}", strategy.get_id());
+					}
+				}
+			}
+			self.network.borrow().enforce_deadlines(now, self.loopdelay as u32);
+		}
+		let decisions = self.strategies.iter()
+			.map(|strategy| PublishedState::sensor(&format!("openhems_{}_decision", strategy.get_id()),
+				&format!("{} decision", strategy.get_id()), strategy.get_decision().to_string(), ""))
+			.collect();
+		if let Err(err) = self.network.borrow().publish_states(now, decisions, stale) {
+			log::error!("Fail publish states : {}", err.message);
+		}
+	}
+	/// When some node data are older than 'server.staleDelay', strategies are suspended
+	/// and switches are set to their failsafe state. Return true if data are stale.
+	fn check_stale(&mut self, now:DateTime<Local>) -> bool {
+		if self.stale_delay==0 {
+			return false;
+		}
+		let network = self.network.borrow();
+		let stale:HashSet<String> = network.get_all()
+			.filter(|node| node.is_stale(now, self.stale_delay))
+			.map(|node| node.get_id().to_string())
+			.collect();
+		if stale.is_empty() {
+			if !self.stale_nodes.is_empty() {
+				let message = String::from("Network data are up to date again, strategies are resumed.");
+				log::info!("{message}");
+				if let Err(err) = network.notify(Severity::Info, &message) {
+					log::error!("Fail notify : {}", err.message);
+				}
+				self.stale_nodes.clear();
+				self.failsafe_done.clear();
+			}
+			return false;
+		}
+		let mut new_stale:Vec<&String> = stale.difference(&self.stale_nodes).collect();
+		if !new_stale.is_empty() {
+			new_stale.sort();
+			let message = format!("No data update for more than {} seconds on {new_stale:?} : strategies are suspended and switches set to failsafe state.", self.stale_delay);
+			log::error!("{message}");
+			if let Err(err) = network.notify(Severity::Error, &message) {
+				log::error!("Fail notify : {}", err.message);
+			}
+		}
+		for switch in network.get_all_switch("") {
+			if self.failsafe_done.contains(switch.get_id()) {
+				continue;
+			}
+			match switch.apply_failsafe() {
+				Ok(_) => {
+					self.failsafe_done.insert(switch.get_id().to_string());
 				}
 				Err(err) => {
-					log::error!("Fail update strategy {} : {err}", strategy.get_id());
+					log::error!("Fail apply failsafe on {} : {}", switch.get_id(), err.message);
 				}
 			}
 		}
+		self.stale_nodes = stale;
+		true
 	}
 	pub fn run(&mut self, data: Arc<AppState>) {
 		self.app_state = data;
@@ -132,23 +220,52 @@ impl<'a> Server {
 			r.store(false, std::sync::atomic::Ordering::SeqCst);
 		}).expect("Failed to set Ctrl+C handler");
 		log::info!("Run OpenHEMS core server with loop-delay={}", self.loopdelay);
-		let mut duration = 0;
+		let events = self.network.borrow().start_events();
 		let loopdelay = Duration::from_secs(self.loopdelay);
+		let mut duration = 0;
+		let mut last_loop = Instant::now();
+		let mut remainder = 0.0;
 		while running.load(std::sync::atomic::Ordering::SeqCst) {
-			let now = Local::now();
-			let nextloop = now + loopdelay;
-			self.loop1(now, duration);
-			let t = Local::now();
-			if t<nextloop {
-				let secs = nextloop - t;
-				duration = self.loopdelay as u32;
-				log::info!("Sleep for {} seconds.", secs.num_seconds());
-				sleep(secs.to_std().unwrap());
-			} else if t>nextloop {
-				let secs = (t - nextloop).as_seconds_f32();
-				duration = self.loopdelay as u32 + secs as u32;
-				log::warn!("Missing {secs} seconds for the loop.");
+			self.loop1(Local::now(), duration);
+			let nextloop = last_loop + loopdelay;
+			if let Some(events) = &events {
+				// Wake up on Home-Assistant changes, but not more than once per EVENT_DEBOUNCE.
+				let min_next = Instant::now() + EVENT_DEBOUNCE;
+				while running.load(std::sync::atomic::Ordering::SeqCst) {
+					let now = Instant::now();
+					if now>=nextloop {
+						break;
+					}
+					match events.recv_timeout(min(nextloop-now, Duration::from_secs(1))) {
+						Ok(entity_id) if !entity_id.is_empty() => {
+							log::debug!("Wake up on change of '{entity_id}'.");
+							if min_next>now {
+								sleep(min_next-now);
+							}
+							while events.try_recv().is_ok() {} // Drain
+							break;
+						}
+						Err(RecvTimeoutError::Disconnected) => {
+							sleep(nextloop.saturating_duration_since(Instant::now()));
+						}
+						_ => {}
+					}
+				}
+			} else {
+				let t = Instant::now();
+				if t<nextloop {
+					log::info!("Sleep for {} seconds.", (nextloop-t).as_secs());
+					sleep(nextloop-t);
+				} else {
+					log::warn!("Missing {} seconds for the loop.", (t-nextloop).as_secs_f32());
+				}
 			}
+			// Count real elapsed time, keeping fractions for next loops.
+			let now = Instant::now();
+			remainder += (now-last_loop).as_secs_f64();
+			duration = remainder as u32;
+			remainder -= duration as f64;
+			last_loop = now;
 		}
 	}
 
@@ -7,6 +7,7 @@ use yaml_rust2::Yaml;
 use crate::error::ResultOpenHems;
 use crate::network::Network;
 use crate::node::Node;
+use crate::notification::Severity;
 use crate::offpeak_strategy::EnergyStrategy;
 use crate::time;
 
@@ -23,6 +24,7 @@ pub struct SolarNoSellStrategy {
 	next_eval_date: DateTime<Local>,
 	eval_frequency: chrono::Duration,
 	deferables: HashMap<String, u32>, // List of devices to switch on/off
+	decision: &'static str,
 }
 
 impl<'a, 'b:'a> EnergyStrategy for SolarNoSellStrategy {
@@ -37,6 +39,9 @@ impl<'a, 'b:'a> EnergyStrategy for SolarNoSellStrategy {
 		let cycle_duration = 30;
 		self.apply(cycle_duration, now)
 	}
+	fn get_decision(&self) -> &str {
+		self.decision
+	}
 }
 
 impl SolarNoSellStrategy {
@@ -53,6 +58,7 @@ impl SolarNoSellStrategy {
 			ratio: 1.0,
 			ref_coefficient: 0.0,
 			deferables: HashMap::new(),
+			decision: "wait",
 		})
 	}
 	fn apply(&mut self, cycle_duration:u32, now:DateTime<Local>) -> ResultOpenHems<u64> {
@@ -72,13 +78,16 @@ impl SolarNoSellStrategy {
 			let production_solarpanel = network.get_current_power("solarpanel")?;
 			power_margin = production_solarpanel - consumption + consumption_battery;
 		}
+		self.decision = "wait";
 		if power_margin>self.margin {
 			if self.switch_on_devices(&mut power_margin)? {
+				self.decision = "switch_on";
 				let dt = ((cycle_duration as f32)/5.0).max(3.0);
 				return Ok(dt as u64);
 			}
 		} else if power_margin<self.margin {
 			if self.switch_off_devices(&mut power_margin)? {
+				self.decision = "switch_off";
 				let dt = ((cycle_duration as f32)/5.0).max(3.0);
 				return Ok(dt as u64);
 			}
@@ -141,7 +150,9 @@ impl SolarNoSellStrategy {
 				if let Err(err) = node.switch(true) {
 					let message = format!("SolarNoSellStrategy : Fail to switch on device '{}' : {}", node.get_id(), err.message);
 					log::error!("{}", message);
-					network.notify(&message)?;
+					if let Err(err) = network.notify(Severity::Warning, &message) {
+						log::error!("Fail notify : {}", err.message);
+					}
 				} else {
 					*power_margin -= node_power;
 					if *power_margin<=0.0 {
@@ -161,7 +172,7 @@ impl SolarNoSellStrategy {
 		assert!(*power_margin<self.margin);
 		let mut network = self.network.borrow_mut();
 		for node in network.get_all_switch_mut("all") {
-			if !node.is_on()? {
+			if !node.is_on()? || node.is_cycle_running() {
 				continue;
 			}
 			// production < consommation - (1-X) * consommationDevice
@@ -218,15 +229,15 @@ impl SolarNoSellStrategy {
 					update = true;
 					// to_drop.push(node_id);
 				} else {
-					deferables.insert(node_id, schedule.get_duration());
-					if *duration != schedule.get_duration() {
+					deferables.insert(node_id, schedule.get_remaining_duration());
+					if *duration != schedule.get_remaining_duration() {
 						update = true;
 					}
 				}
 			} else {
 				if is_scheduled {
 					// Add a new deferrable
-					deferables.insert(node_id, schedule.get_duration());
+					deferables.insert(node_id, schedule.get_remaining_duration());
 					update = true;
 				}
 			}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use crate::configuration_manager::ConfigurationManager;
+use crate::error::{OpenHemsError, ResultOpenHems};
+
+const STX:u8 = 0x02; // Start of frame
+const ETX:u8 = 0x03; // End of frame
+const EOT:u8 = 0x04; // Frame interrupted
+const LF:u8 = 0x0A; // Start of group
+const CR:u8 = 0x0D; // End of group
+const RECONNECT_DELAY:Duration = Duration::from_secs(5);
+// In auto mode, time to get a valid frame before trying the other baud rate.
+const PROBE_DELAY:Duration = Duration::from_secs(20);
+pub const ENTITY_PREFIX:&str = "teleinfo.";
+
+/// Linky TIC mode : historic (1200 bauds, space separator) or standard (9600 bauds, tab separator).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeleinfoMode {
+	Auto,
+	Historic,
+	Standard,
+}
+impl TeleinfoMode {
+	pub fn from_str(value:&str) -> ResultOpenHems<TeleinfoMode> {
+		match value.to_lowercase().as_str() {
+			"" | "auto" => Ok(TeleinfoMode::Auto),
+			"historic" | "historique" => Ok(TeleinfoMode::Historic),
+			"standard" => Ok(TeleinfoMode::Standard),
+			_ => Err(OpenHemsError::new(format!("Invalid teleinfo mode '{value}', use auto, historic or standard.")))
+		}
+	}
+	/// Serial baud rates to try, auto alternate between historic and standard.
+	fn get_baud_rates(&self) -> &'static [u32] {
+		match self {
+			TeleinfoMode::Auto => &[1200, 9600],
+			TeleinfoMode::Historic => &[1200],
+			TeleinfoMode::Standard => &[9600],
+		}
+	}
+}
+
+/// Split a byte stream into frames (label -> value), checking groups checksum.
+#[derive(Debug, Default)]
+pub struct TeleinfoParser {
+	frame: Option<HashMap<String, String>>,
+	group: Option<Vec<u8>>,
+}
+impl TeleinfoParser {
+	/// Parse one group (between LF and CR) : 'LABEL SP VALUE SP CHECKSUM' (historic)
+	/// or 'LABEL HT [DATE HT] VALUE HT CHECKSUM' (standard).
+	fn parse_group(group:&[u8]) -> Option<(String, String)> {
+		if group.len()<4 {
+			return None;
+		}
+		let checksum = group[group.len()-1];
+		let separator = group[group.len()-2];
+		let (sum_end, separator) = match separator {
+			b'\t' => (group.len()-1, '\t'), // Standard : last separator is in checksum
+			b' ' => (group.len()-2, ' '),
+			_ => {
+				return None;
+			}
+		};
+		let sum:u32 = group[..sum_end].iter().map(|b| *b as u32).sum();
+		if ((sum & 0x3F) as u8)+0x20!=checksum {
+			log::debug!("Teleinfo : bad checksum for '{}'", String::from_utf8_lossy(group));
+			return None;
+		}
+		let payload = String::from_utf8_lossy(&group[..group.len()-2]).to_string();
+		let mut parts = payload.split(separator);
+		let label = parts.next()?.to_string();
+		let parts:Vec<&str> = parts.collect();
+		let value = match parts.as_slice() {
+			[value] => value.to_string(),
+			[date, ""] => date.to_string(),
+			[_, value] => value.to_string(),
+			_ => payload[label.len()+1..].to_string()
+		};
+		Some((label, value.trim().to_string()))
+	}
+	/// Feed bytes, return completed frames.
+	pub fn push(&mut self, bytes:&[u8]) -> Vec<HashMap<String, String>> {
+		let mut frames = Vec::new();
+		for byte in bytes {
+			let byte = byte & 0x7F; // 7E1 read as 8N1 keep the parity bit
+			match byte {
+				STX => {
+					self.frame = Some(HashMap::new());
+					self.group = None;
+				}
+				ETX => {
+					if let Some(frame) = self.frame.take() {
+						if !frame.is_empty() {
+							frames.push(frame);
+						}
+					}
+					self.group = None;
+				}
+				EOT => {
+					self.frame = None;
+					self.group = None;
+				}
+				LF => {
+					self.group = Some(Vec::new());
+				}
+				CR => {
+					if let (Some(group), Some(frame)) = (self.group.take(), self.frame.as_mut()) {
+						if let Some((label, value)) = Self::parse_group(&group) {
+							frame.insert(label, value);
+						}
+					}
+				}
+				_ => {
+					if let Some(group) = self.group.as_mut() {
+						group.push(byte);
+					}
+				}
+			}
+		}
+		frames
+	}
+}
+
+// Tempo colour from 'STGE' register bits (0:none, 1:bleu, 2:blanc, 3:rouge).
+fn get_stge_color(stge:&str, shift:u32) -> Option<&'static str> {
+	let stge = u32::from_str_radix(stge, 16).ok()?;
+	match (stge >> shift) & 0x3 {
+		1 => Some("bleu"),
+		2 => Some("blanc"),
+		3 => Some("rouge"),
+		_ => None
+	}
+}
+
+/// Add normalized values to a frame : power (VA), index (Wh), injection (Wh),
+/// period (HC/HP/TH), offpeak, color and nextcolor (Tempo : bleu, blanc, rouge).
+pub fn get_values(frame:&HashMap<String, String>) -> HashMap<String, String> {
+	let mut values = frame.clone();
+	let get_float = |label:&str| frame.get(label).and_then(|v| v.parse::<f64>().ok());
+	// Power
+	let power = get_float("SINSTS").or(get_float("PAPP"))
+		.or(get_float("IINST").map(|i| i*230.0));
+	if let Some(power) = power {
+		values.insert("power".to_string(), power.to_string());
+	}
+	// Counters
+	let index = get_float("EAST").or_else(|| {
+		let indexes:Vec<f64> = ["BASE", "HCHC", "HCHP", "EJPHN", "EJPHPM",
+				"BBRHCJB", "BBRHPJB", "BBRHCJW", "BBRHPJW", "BBRHCJR", "BBRHPJR"]
+			.iter().filter_map(|label| get_float(label)).collect();
+		if indexes.is_empty() {None} else {Some(indexes.iter().sum())}
+	});
+	if let Some(index) = index {
+		values.insert("index".to_string(), index.to_string());
+	}
+	if let Some(injection) = get_float("EAIT") {
+		values.insert("injection".to_string(), injection.to_string());
+	}
+	// Tariff period
+	let (period, color) = if let Some(ptec) = frame.get("PTEC") {
+		// Historic : TH.., HC.., HP.., HN.., PM.., HCJB, HPJW...
+		let period = match ptec.get(..2) {
+			Some("HC") => "HC",
+			Some("TH") => "TH",
+			_ => "HP",
+		};
+		let color = match ptec.get(2..4) {
+			Some("JB") => Some("bleu"),
+			Some("JW") => Some("blanc"),
+			Some("JR") => Some("rouge"),
+			_ => None
+		};
+		(Some(period), color)
+	} else if let Some(ltarf) = frame.get("LTARF") {
+		// Standard : 'HEURE CREUSE', 'HC BLEU', 'HP  ROUGE', 'BASE'...
+		let ltarf = ltarf.to_uppercase();
+		let period = if ltarf.contains("CREUSE") || ltarf.starts_with("HC") {
+			"HC"
+		} else if ltarf.contains("PLEINE") || ltarf.starts_with("HP") {
+			"HP"
+		} else {
+			"TH"
+		};
+		let color = if ltarf.contains("BLEU") {
+			Some("bleu")
+		} else if ltarf.contains("BLANC") {
+			Some("blanc")
+		} else if ltarf.contains("ROUGE") {
+			Some("rouge")
+		} else {
+			frame.get("STGE").and_then(|stge| get_stge_color(stge, 24))
+		};
+		(Some(period), color)
+	} else {
+		(None, None)
+	};
+	if let Some(period) = period {
+		values.insert("period".to_string(), period.to_string());
+		values.insert("offpeak".to_string(), (period=="HC").to_string());
+	}
+	if let Some(color) = color {
+		values.insert("color".to_string(), color.to_string());
+	}
+	let nextcolor = match frame.get("DEMAIN").map(|v| v.as_str()) {
+		Some("BLEU") => Some("bleu"),
+		Some("BLAN") => Some("blanc"),
+		Some("ROUG") => Some("rouge"),
+		_ => frame.get("STGE").and_then(|stge| get_stge_color(stge, 26))
+	};
+	if let Some(nextcolor) = nextcolor {
+		values.insert("nextcolor".to_string(), nextcolor.to_string());
+	}
+	values
+}
+
+// Last frame read by the background thread.
+type SharedFrame = Arc<Mutex<Option<(HashMap<String, String>, DateTime<Local>)>>>;
+
+/// Linky TIC reader, from a serial device ('/dev/ttyUSB0'), a TCP stream ('tcp://host:port', like ser2net)
+/// or a file. Values are entities 'teleinfo.<LABEL>' (like 'teleinfo.PAPP') and 'teleinfo.power', 'teleinfo.index',
+/// 'teleinfo.injection', 'teleinfo.period', 'teleinfo.offpeak', 'teleinfo.color', 'teleinfo.nextcolor'.
+#[derive(Clone)]
+pub struct Teleinfo {
+	port: String,
+	mode: TeleinfoMode,
+	shared: SharedFrame,
+	values: HashMap<String, String>,
+	last_update: Option<DateTime<Local>>,
+}
+impl fmt::Debug for Teleinfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Teleinfo({})", self.port)
+	}
+}
+impl Teleinfo {
+	/// None when 'teleinfo.port' is not set.
+	pub fn new(configurator:&ConfigurationManager) -> ResultOpenHems<Option<Teleinfo>> {
+		let port = configurator.get_as_str("teleinfo.port");
+		if port.is_empty() {
+			return Ok(None);
+		}
+		let teleinfo = Teleinfo {
+			port,
+			mode: TeleinfoMode::from_str(&configurator.get_as_str("teleinfo.mode"))?,
+			shared: Arc::new(Mutex::new(None)),
+			values: HashMap::new(),
+			last_update: None,
+		};
+		teleinfo.start();
+		Ok(Some(teleinfo))
+	}
+	fn is_serial(port:&str) -> bool {
+		port.starts_with("/dev/tty")
+	}
+	fn open(port:&str, baud_rate:u32) -> ResultOpenHems<Box<dyn Read + Send>> {
+		let error = |err:&dyn fmt::Display| OpenHemsError::new(format!("Fail open teleinfo '{port}' : {err}"));
+		if let Some(address) = port.strip_prefix("tcp://") {
+			let stream = TcpStream::connect(address).map_err(|err| error(&err))?;
+			Ok(Box::new(stream))
+		} else if Self::is_serial(port) {
+			let serial = serialport::new(port, baud_rate)
+				.data_bits(serialport::DataBits::Seven)
+				.parity(serialport::Parity::Even)
+				.stop_bits(serialport::StopBits::One)
+				.timeout(Duration::from_secs(10))
+				.open()
+				.map_err(|err| error(&err))?;
+			Ok(Box::new(serial))
+		} else {
+			let file = std::fs::File::open(port).map_err(|err| error(&err))?;
+			Ok(Box::new(file))
+		}
+	}
+	/// Parse the stream until its end or an error, storing the last frame.
+	/// With a probe delay, fail if no valid frame is read in time (wrong baud rate).
+	fn read_stream(mut stream:impl Read, shared:&SharedFrame, probe:Option<Duration>) -> ResultOpenHems<()> {
+		let mut parser = TeleinfoParser::default();
+		let mut buffer = [0u8; 512];
+		let start = Instant::now();
+		let mut probe = probe;
+		loop {
+			let size = match stream.read(&mut buffer) {
+				Ok(0) => {
+					return Ok(());
+				}
+				Ok(size) => size,
+				Err(err) if err.kind()==std::io::ErrorKind::Interrupted => {
+					continue;
+				}
+				Err(err) => {
+					return Err(OpenHemsError::new(format!("Teleinfo read error : {err}")));
+				}
+			};
+			if let Some(frame) = parser.push(&buffer[..size]).pop() {
+				log::trace!("Teleinfo frame : {frame:?}");
+				*shared.lock().unwrap() = Some((frame, Local::now()));
+				probe = None;
+			} else if probe.is_some_and(|delay| start.elapsed()>=delay) {
+				return Err(OpenHemsError::new("Teleinfo : no valid frame read".to_string()));
+			}
+		}
+	}
+	// Background thread, reopen the port on error or end of stream.
+	// On a serial port in auto mode, switch baud rate until a valid frame is read.
+	fn start(&self) {
+		let (port, shared) = (self.port.clone(), self.shared.clone());
+		let baud_rates = self.mode.get_baud_rates();
+		let probe = if Self::is_serial(&port) && baud_rates.len()>1 {Some(PROBE_DELAY)} else {None};
+		std::thread::spawn(move || {
+			let mut rate = 0;
+			loop {
+				let baud_rate = baud_rates[rate];
+				match Self::open(&port, baud_rate) {
+					Ok(stream) => {
+						log::info!("Teleinfo : reading '{port}' ({baud_rate} bauds)");
+						let opened = Local::now();
+						if let Err(err) = Self::read_stream(stream, &shared, probe) {
+							log::warn!("{}", err.message);
+						}
+						let read = shared.lock().unwrap().as_ref().is_some_and(|(_, date)| *date>=opened);
+						if !read {
+							rate = (rate+1) % baud_rates.len();
+						}
+					}
+					Err(err) => {
+						log::error!("{}", err.message);
+					}
+				}
+				sleep(RECONNECT_DELAY);
+			}
+		});
+	}
+	/// Take the last frame read. Return false if there is no new one.
+	pub fn update(&mut self) -> bool {
+		let shared = self.shared.lock().unwrap();
+		match shared.as_ref() {
+			Some((frame, date)) if Some(*date)!=self.last_update => {
+				self.values = get_values(frame);
+				self.last_update = Some(*date);
+				true
+			}
+			_ => false
+		}
+	}
+	pub fn has_entity(&self, nameid:&str) -> bool {
+		nameid.starts_with(ENTITY_PREFIX)
+	}
+	pub fn get_value(&self, nameid:&str) -> ResultOpenHems<String> {
+		let label = nameid.trim_start_matches(ENTITY_PREFIX);
+		match self.values.get(label) {
+			Some(value) => Ok(value.clone()),
+			None => Err(OpenHemsError::new(format!("No value '{label}' in teleinfo frame.")))
+		}
+	}
+	pub fn get_entity_value_float(&self, nameid:&str) -> ResultOpenHems<f32> {
+		let value = self.get_value(nameid)?;
+		value.parse::<f32>()
+			.map_err(|_| OpenHemsError::new(format!("Teleinfo value '{nameid}' is not a number : '{value}'.")))
+	}
+	pub fn get_last_update(&self) -> Option<DateTime<Local>> {
+		self.last_update
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Build a group with its checksum.
+	fn group(fields:&[&str], separator:char) -> String {
+		let mut payload = fields.join(&separator.to_string());
+		if separator=='\t' {
+			payload.push('\t');
+		}
+		let sum:u32 = payload.bytes().map(|b| b as u32).sum();
+		let checksum = ((sum & 0x3F) as u8 + 0x20) as char;
+		if separator==' ' {
+			payload.push(' ');
+		}
+		format!("\n{payload}{checksum}\r")
+	}
+
+	#[test]
+	fn test_teleinfo() -> ResultOpenHems<()> {
+		let mut parser = TeleinfoParser::default();
+		// Historic Tempo, with a bad checksum group
+		let historic = format!("\x02{}{}{}{}{}{}\n PAPP 02000 X\r\x03",
+			group(&["ADCO", "021861348497"], ' '), group(&["BBRHCJB", "001234567"], ' '),
+			group(&["BBRHPJB", "000765433"], ' '), group(&["PTEC", "HPJB"], ' '),
+			group(&["DEMAIN", "ROUG"], ' '), group(&["PAPP", "01250"], ' '));
+		// Begin with an incomplete frame
+		let frames = parser.push(format!("{}\x03{historic}", group(&["PAPP", "00010"], ' ')).as_bytes());
+		assert_eq!(frames.len(), 1);
+		let values = get_values(&frames[0]);
+		assert_eq!(values["PAPP"], "01250");
+		assert_eq!(values["power"], "1250");
+		assert_eq!(values["index"], "2000000");
+		assert_eq!(values["period"], "HP");
+		assert_eq!(values["offpeak"], "false");
+		assert_eq!(values["color"], "bleu");
+		assert_eq!(values["nextcolor"], "rouge");
+		// Standard, with horodate groups
+		let standard = format!("\x02{}{}{}{}{}\x03",
+			group(&["DATE", "H240315013000", ""], '\t'), group(&["EAST", "012345678"], '\t'),
+			group(&["SINSTS", "00820"], '\t'), group(&["LTARF", "HC BLEU"], '\t'),
+			group(&["SMAXSN", "H240315010000", "03120"], '\t'));
+		let frames = parser.push(standard.as_bytes());
+		let values = get_values(&frames[0]);
+		assert_eq!(values["DATE"], "H240315013000");
+		assert_eq!(values["SMAXSN"], "03120");
+		assert_eq!(values["power"], "820");
+		assert_eq!(values["index"], "12345678");
+		assert_eq!(values["period"], "HC");
+		assert_eq!(values["color"], "bleu");
+		// From a stream
+		let shared = Arc::new(Mutex::new(None));
+		// Garbage, as read with a wrong baud rate
+		assert!(Teleinfo::read_stream(&[0x7Fu8; 64][..], &shared, Some(Duration::ZERO)).is_err());
+		Teleinfo::read_stream(historic.as_bytes(), &shared, Some(Duration::ZERO))?;
+		let mut teleinfo = Teleinfo {
+			port: String::new(),
+			mode: TeleinfoMode::Auto,
+			shared,
+			values: HashMap::new(),
+			last_update: None,
+		};
+		assert!(teleinfo.update());
+		assert!(!teleinfo.update());
+		assert_eq!(teleinfo.get_value("teleinfo.power")?, "1250");
+		assert!(teleinfo.get_value("teleinfo.EAST").is_err());
+		Ok(())
+	}
+}
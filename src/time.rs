@@ -336,6 +336,9 @@ impl HoursRanges {
 		//TODO : return self._timeoutCallBack.getHoursRanges(nowDatetime, attime).checkRange(nowDatetime, attime)
 		// print("OffPeakStrategy.checkRange(",now,")")
 		// # This has no real signification but it's usefull and the most simple way
+		if self.ranges.is_empty() {
+			return Err(OpenHemsError::new("HoursRanges : no range.".to_string()));
+		}
 		let mut time2nextrange = 3600*24; // = 24h = a full day
 		let mut currange = &self.ranges[0];
 		let timenow = now.time();
@@ -1,29 +1,185 @@
-use chrono::Local;
+use chrono::{DateTime, Local};
 use futures::StreamExt;
 use json::JsonValue;
 use actix_web::{error, Error, HttpResponse};
 use std::{collections::HashMap, ops::DerefMut, sync::{Arc, Mutex}};
-use crate::{error::ResultOpenHems, schedule::Schedule, server::DecrementTime, time};
+use crate::{
+	auth::{AuthConfig, Authenticated, Role},
+	configuration_manager::ConfigurationManager,
+	error::{OpenHemsError, ResultOpenHems}, logger, schedule::Schedule
+};
 
 pub const DATE_FORMAT:&str = "%d/%m/%Y";
+const SAVE_INTERVAL:i64 = 300; // Seconds between saves of schedules which only count down
+
+/// HTTP server settings, from 'server.*' configuration keys.
+#[derive(Clone, Debug)]
+pub struct WebServerConfig {
+	pub bind_address: String,
+	pub port: u16,
+	pub html_root: String, // URL prefix, always ending with '/'
+	pub static_root: String, // Directory with js/, css/, img/ and templates/
+	pub workers: usize,
+	pub ssl_certificate: String,
+	pub ssl_key: String,
+}
+impl WebServerConfig {
+	pub fn from_conf(configurator:&ConfigurationManager) -> WebServerConfig {
+		let mut bind_address = configurator.get_as_str("server.bindAddress");
+		if bind_address.is_empty() {
+			// In a container, localhost is not reachable from outside.
+			bind_address = if configurator.get_as_bool("server.inDocker") {
+				String::from("0.0.0.0")
+			} else {
+				String::from("127.0.0.1")
+			};
+		}
+		let mut static_root = configurator.get_as_str("server.staticRoot");
+		if static_root.is_empty() {
+			static_root = String::from(".");
+		}
+		WebServerConfig {
+			bind_address,
+			port: configurator.get_as_int("server.port") as u16,
+			html_root: Self::normalize_root(&configurator.get_as_str("server.htmlRoot")),
+			static_root: static_root.trim_end_matches('/').to_string(),
+			workers: configurator.get_as_int("server.workers").max(1) as usize,
+			ssl_certificate: configurator.get_as_str("server.ssl_certificate"),
+			ssl_key: configurator.get_as_str("server.ssl_key"),
+		}
+	}
+	/// Return the URL prefix as "/" or "/prefix/".
+	pub fn normalize_root(root:&str) -> String {
+		let root = root.trim_matches('/');
+		if root.is_empty() {
+			String::from("/")
+		} else {
+			format!("/{root}/")
+		}
+	}
+	/// True for an URL path like "/api/hassio_ingress/xyz" (matching ^/[A-Za-z0-9/_-]*$).
+	pub fn is_valid_root(root:&str) -> bool {
+		root.starts_with('/') && root.chars().all(|c| c.is_ascii_alphanumeric() || "/_-".contains(c))
+	}
+	/// Prefix for actix scope : "" or "/prefix".
+	pub fn get_scope(&self) -> &str {
+		self.html_root.trim_end_matches('/')
+	}
+	pub fn get_static_path(&self, dir:&str) -> String {
+		format!("{}/{}", self.static_root, dir)
+	}
+	pub fn is_tls(&self) -> bool {
+		!self.ssl_certificate.is_empty()
+	}
+	pub fn get_tls_config(&self) -> ResultOpenHems<rustls::ServerConfig> {
+		let cert_file = std::fs::File::open(&self.ssl_certificate)
+			.map_err(|err| OpenHemsError::new(format!("Fail open certificate '{}' : {err}", self.ssl_certificate)))?;
+		let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(cert_file))
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|err| OpenHemsError::new(format!("Fail read certificate '{}' : {err}", self.ssl_certificate)))?;
+		// The key may be in the certificate file.
+		let key_path = if self.ssl_key.is_empty() {&self.ssl_certificate} else {&self.ssl_key};
+		let key_file = std::fs::File::open(key_path)
+			.map_err(|err| OpenHemsError::new(format!("Fail open private key '{key_path}' : {err}")))?;
+		let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(key_file))
+			.map_err(|err| OpenHemsError::new(format!("Fail read private key '{key_path}' : {err}")))?
+			.ok_or_else(|| OpenHemsError::new(format!("No private key found in '{key_path}'.")))?;
+		rustls::ServerConfig::builder_with_provider(std::sync::Arc::new(rustls::crypto::ring::default_provider()))
+			.with_safe_default_protocol_versions()
+			.map_err(|err| OpenHemsError::new(format!("Fail configure TLS : {err}")))?
+			.with_no_client_auth()
+			.with_single_cert(certs, key)
+			.map_err(|err| OpenHemsError::new(format!("Invalid TLS certificate : {err}")))
+	}
+}
 
 pub struct AppState {
     pub schedules: HashMap<String, Arc<Mutex<Schedule>>>,
+	state_file: String, // Where schedules are saved, when "" they are not
+	saved: Mutex<(i64, JsonValue)>, // Date and summary of the last save
 }
 impl AppState {
 	pub fn new() -> Self {
 		AppState {
 			schedules: HashMap::new(),
+			state_file: String::new(),
+			saved: Mutex::new((0, JsonValue::Null)),
 		}
 	}
-	pub fn decrement_time(&self, duration:u32) -> ResultOpenHems<bool> {
-		log::debug!("AppState::decrement_time() for {} seconds", duration);
+	/// Set schedules from their recurring programs, and save them if some are set.
+	pub fn arm_programs(&self, now:DateTime<Local>) -> ResultOpenHems<bool> {
+		let mut armed = false;
 		for schedule in self.schedules.values() {
-			let mut sch = schedule.lock().unwrap();
-			sch.decrement_time(duration)?;
+			armed |= schedule.lock().unwrap().arm_programs(now);
 		}
+		if armed {
+			self.save()?;
+		}
+		Ok(armed)
+	}
+	/// Save schedules in 'server.stateFile' (written in a temporary file then renamed).
+	pub fn save(&self) -> ResultOpenHems<()> {
+		self.save_at(Local::now(), true).map(|_| ())
+	}
+	/// Save schedules if they changed (scheduled or not, mode, programs...),
+	/// else at most every SAVE_INTERVAL while they only count down. Return true if saved.
+	pub fn save_changes(&self, now:DateTime<Local>) -> ResultOpenHems<bool> {
+		self.save_at(now, false)
+	}
+	fn save_at(&self, now:DateTime<Local>, force:bool) -> ResultOpenHems<bool> {
+		if self.state_file.is_empty() {
+			return Ok(false);
+		}
+		let mut schedules = JsonValue::new_object();
+		for (key, schedule) in &self.schedules {
+			schedules[key.as_str()] = schedule.lock().unwrap().to_state();
+		}
+		// Counters are ignored to detect changes
+		let mut summary = schedules.clone();
+		for (_, state) in summary.entries_mut() {
+			state["duration"] = (state["duration"].as_u32().unwrap_or(0)>0).into();
+			state.remove("energy");
+		}
+		let mut saved = self.saved.lock().unwrap();
+		if !force && saved.1==summary && now.timestamp()-saved.0<SAVE_INTERVAL {
+			return Ok(false);
+		}
+		let state = json::object!{"date": now.timestamp(), "schedules": schedules};
+		let tmp_file = format!("{}.tmp", self.state_file);
+		std::fs::write(&tmp_file, state.pretty(1))
+			.and_then(|_| std::fs::rename(&tmp_file, &self.state_file))
+			.map_err(|err| OpenHemsError::new(format!("Fail save state in '{}' : {err}", self.state_file)))?;
+		*saved = (now.timestamp(), summary);
 		Ok(true)
 	}
+	/// Restore schedules saved in 'state_file' (the time elapsed since is not counted : switches did not run for OpenHEMS).
+	/// Then schedules are saved there on each change (at a limited rate while they only count down).
+	pub fn restore(&mut self, state_file:&str) -> ResultOpenHems<()> {
+		self.state_file = state_file.to_string();
+		if state_file.is_empty() || !std::path::Path::new(state_file).exists() {
+			return Ok(());
+		}
+		let content = std::fs::read_to_string(state_file)
+			.map_err(|err| OpenHemsError::new(format!("Fail read state file '{state_file}' : {err}")))?;
+		let state = json::parse(&content)
+			.map_err(|err| OpenHemsError::new(format!("Invalid state file '{state_file}' : {err}")))?;
+		for (key, schedule_state) in state["schedules"].entries() {
+			match self.schedules.get(key) {
+				Some(schedule) => {
+					// A bad saved schedule must not lose the others.
+					if let Err(err) = schedule.lock().unwrap().restore_state(schedule_state) {
+						log::error!("Ignore saved schedule of '{key}' : {}", err.message);
+					}
+				}
+				None => {
+					log::warn!("Ignore saved schedule of unknown node '{key}'.");
+				}
+			}
+		}
+		let elapsed = Local::now().timestamp() - state["date"].as_i64().unwrap_or(i64::MAX);
+		log::info!("Schedules restored from '{state_file}', {elapsed} seconds after they were saved.");
+		Ok(())
+	}
 }
 
 fn nodes_json(data: &AppState) -> String {
@@ -31,19 +187,17 @@ fn nodes_json(data: &AppState) -> String {
 	let mut sep = "";
 	for (key, schedule) in &data.schedules {
 		let schedule = schedule.lock().unwrap();
-		nodes.push_str(&format!("{}\"{}\":{}", sep, key, schedule.to_json()));
+		nodes.push_str(&format!("{}{}:{}", sep, json::stringify(key.as_str()), schedule.to_json()));
 		sep = ",";
 	}
 	nodes.push_str("}");
-	nodes
+	// Spliced in a <script> : no "</script>" from ids or names ('<' is only in strings).
+	nodes.replace('<', "\\u003c")
 }
 
 const MAX_SIZE: usize = 262_144; // max payload size is 256k
-pub async fn states(
-			_tmpl: actix_web::web::Data<tera::Tera>,
-			data: actix_web::web::Data<Arc<AppState>>,
-			mut payload: actix_web::web::Payload
-		) -> Result<HttpResponse, Error> {
+
+async fn read_json(mut payload: actix_web::web::Payload) -> Result<JsonValue, Error> {
 	// payload is a stream of Bytes objects
     let mut body = actix_web::web::BytesMut::new();
     while let Some(chunk) = payload.next().await {
@@ -57,9 +211,30 @@ pub async fn states(
 	let json_str = String::from_utf8(body.to_vec())
 		.map_err(|_| error::ErrorBadRequest("Invalid UTF-8."))?;
 	log::debug!("Received body: {}", json_str);
-	let json_values = json::parse(&json_str)
-		.map_err(|_| error::ErrorBadRequest("Invalid JSON."))?;
+	json::parse(&json_str)
+		.map_err(|_| error::ErrorBadRequest("Invalid JSON."))
+}
+
+fn json_response(body: String) -> HttpResponse {
+	let mut response = HttpResponse::Ok().body(body);
+	response.headers_mut().insert(
+		actix_web::http::header::HeaderName::from_static("content-type"),
+		actix_web::http::header::HeaderValue::from_static("text/plain")
+	);
+	response
+}
+
+pub async fn states(
+			req: actix_web::HttpRequest,
+			user: Authenticated,
+			auth: actix_web::web::Data<AuthConfig>,
+			data: actix_web::web::Data<Arc<AppState>>,
+			payload: actix_web::web::Payload
+		) -> Result<HttpResponse, Error> {
+	user.require_mutation(&req, &auth)?;
+	let json_values = read_json(payload).await?;
 	if let JsonValue::Object(object) =  json_values {
+		log::info!("User '{}' update schedules.", user.name);
 		for (key, schedule_json) in object.iter() {
 			if let Some(schedule) = data.schedules.get(key) {
 				let mut schedule_mutex = schedule.lock().unwrap();
@@ -67,32 +242,144 @@ pub async fn states(
 					.map_err(|_| error::ErrorBadRequest("Invalid schedule object."))?;
 			}
 		}
+		if let Err(err) = data.save() {
+			log::error!("{}", err.message);
+		}
 	}
 
 	let nodes = nodes_json(&data);
-	let mut response = HttpResponse::Ok().body(nodes);
-	response.headers_mut().insert(
-		actix_web::http::header::HeaderName::from_static("content-type"),
-		actix_web::http::header::HeaderValue::from_static("text/plain")
-	);
-    Ok(response)
+    Ok(json_response(nodes))
+}
+
+/// Current log levels.
+pub async fn get_log(user: Authenticated) -> Result<HttpResponse, Error> {
+	user.require(Role::ReadOnly)?;
+	Ok(json_response(logger::get_levels_json()))
+}
+
+/// Change log levels for troubleshooting : {"level":"debug", "modules":["home_assistant_api=trace"]}
+pub async fn set_log(
+			req: actix_web::HttpRequest,
+			user: Authenticated,
+			auth: actix_web::web::Data<AuthConfig>,
+			payload: actix_web::web::Payload
+		) -> Result<HttpResponse, Error> {
+	user.require_mutation(&req, &auth)?;
+	let json_values = read_json(payload).await?;
+	let level = json_values["level"].as_str().unwrap_or("info");
+	let modules:Vec<String> = json_values["modules"].members()
+		.filter_map(|m| m.as_str().map(|m| m.to_string()))
+		.collect();
+	let levels = logger::set_levels_str(level, &modules)
+		.map_err(|err| error::ErrorBadRequest(err.message))?;
+	log::warn!("User '{}' changed log levels.", user.name);
+	Ok(json_response(levels))
 }
 
 pub async fn index(
+			req: actix_web::HttpRequest,
+			user: Authenticated,
+			auth: actix_web::web::Data<AuthConfig>,
 			tmpl: actix_web::web::Data<tera::Tera>,
+			conf: actix_web::web::Data<WebServerConfig>,
 			data: actix_web::web::Data<Arc<AppState>>
-		) -> HttpResponse {
+		) -> Result<HttpResponse, Error> {
+	user.require(Role::ReadOnly)?;
 	let mut ctx = tera::Context::new();
+	ctx.insert("csrf_token", auth.get_csrf_token());
+	ctx.insert("readonly", &(user.role<Role::Operator));
+	// Home-Assistant ingress give the public prefix in a header (any client can set it, so it's only a path).
+	let root = match req.headers().get("X-Ingress-Path").and_then(|v| v.to_str().ok()) {
+		Some(path) if WebServerConfig::is_valid_root(path) => WebServerConfig::normalize_root(path),
+		Some(path) => {
+			log::warn!("Ignore invalid X-Ingress-Path header '{}'.", path.escape_debug());
+			conf.html_root.clone()
+		}
+		None => conf.html_root.clone()
+	};
+	ctx.insert("root", &root);
     ctx.insert("translate_tooltip_duration", "Duration");
     ctx.insert("tooltip_duration", "Duration");
     ctx.insert("translate_tooltip_timeout", "Timeout");
     ctx.insert("tooltip_timeout", "Timeout");
     ctx.insert("text_for", "for");
     ctx.insert("text_before", "before");
+    ctx.insert("text_every", "every");
+    ctx.insert("text_or", "or");
+    ctx.insert("tooltip_energy", "Energy to deliver");
+    ctx.insert("translate_add_program", "Add a program");
+    ctx.insert("translate_deadline_atrisk", "Forced on to end in time, latest start");
+    ctx.insert("translate_deadline_missed", "Can not end in time, latest start was");
     ctx.insert("DATE_FORMAT", &DATE_FORMAT);
 	let nodes = nodes_json(&data);
     ctx.insert("nodes", &nodes);
 	let rendered = tmpl.render("panel.jinja2", &ctx)
         .unwrap_or_else(|_| "Template error".into());
-    HttpResponse::Ok().body(rendered)
-}
\ No newline at end of file
+    Ok(HttpResponse::Ok().body(rendered))
+}
+#[cfg(test)]
+mod tests {
+	use crate::schedule::ScheduleMode;
+	use super::*;
+
+	#[test]
+	fn test_ingress_root() {
+		assert!(WebServerConfig::is_valid_root("/api/hassio_ingress/Ab-9_x/"));
+		assert!(!WebServerConfig::is_valid_root("/x\"><script>alert(1)</script>"));
+		assert!(!WebServerConfig::is_valid_root("api"));
+		let mut ctx = tera::Context::new();
+		ctx.insert("root", "/a/");
+		assert_eq!(tera::Tera::one_off("{{ root | json_encode() | safe }}", &ctx, false).unwrap(), "\"/a/\"");
+	}
+
+	#[test]
+	fn test_nodes_json() {
+		let mut appstate = AppState::new();
+		let id = "switch.\"a\"</script>";
+		appstate.schedules.insert(id.to_string(), Arc::new(Mutex::new(Schedule::new(id))));
+		let nodes = nodes_json(&appstate);
+		assert!(!nodes.contains("</script>"));
+		assert_eq!(json::parse(&nodes).unwrap()[id]["id"], id);
+	}
+
+	#[test]
+	fn test_state_file() -> ResultOpenHems<()> {
+		let state_file = std::env::temp_dir().join(format!("openhems_state_{}.json", std::process::id()));
+		let state_file = state_file.to_str().unwrap();
+		let new_appstate = || {
+			let mut appstate = AppState::new();
+			appstate.schedules.insert("car".to_string(), Arc::new(Mutex::new(Schedule::new("car"))));
+			appstate.schedules.insert("pump".to_string(), Arc::new(Mutex::new(Schedule::new("pump"))));
+			appstate
+		};
+		let mut appstate = new_appstate();
+		appstate.restore(state_file)?; // No file yet
+		{
+			let mut schedule = appstate.schedules["car"].lock().unwrap();
+			schedule.set_duration(3600);
+			schedule.set_mode(ScheduleMode::On);
+		}
+		appstate.save()?;
+		// Counting down is saved only at a limited rate, unlike the end of the schedule
+		let now = Local::now();
+		appstate.schedules["car"].lock().unwrap().set_duration(3000);
+		assert!(!appstate.save_changes(now)?);
+		assert!(appstate.save_changes(now+chrono::Duration::seconds(SAVE_INTERVAL))?);
+		appstate.schedules["car"].lock().unwrap().set_duration(0);
+		assert!(appstate.save_changes(now+chrono::Duration::seconds(SAVE_INTERVAL))?);
+		appstate.schedules["car"].lock().unwrap().set_duration(3600);
+		appstate.save()?;
+		// Saved 100 seconds ago, with an invalid schedule
+		let mut state = json::parse(&std::fs::read_to_string(state_file).unwrap()).unwrap();
+		state["date"] = (state["date"].as_i64().unwrap()-100).into();
+		state["schedules"]["pump"] = json::object!{"duration": "never"};
+		std::fs::write(state_file, state.dump()).unwrap();
+		let mut appstate = new_appstate();
+		appstate.restore(state_file)?;
+		let schedule = appstate.schedules["car"].lock().unwrap();
+		assert_eq!(schedule.get_duration(), 3600); // Only counted while the switch runs
+		assert_eq!(schedule.get_mode(), ScheduleMode::On);
+		let _ = std::fs::remove_file(state_file);
+		Ok(())
+	}
+}